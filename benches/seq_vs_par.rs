@@ -1,7 +1,9 @@
 #[macro_use] extern crate criterion;
 
+use byteorder::{WriteBytesExt, LE};
 use criterion::{Criterion, Fun};
-use rread_lst::reading::{analyze_lst_par, analyze_lst_seq};
+use libps::binary_parsing::*;
+use libps::helper_funcs::*;
 
 fn get_config() -> Criterion {
     Criterion::default().nresamples(10)
@@ -9,17 +11,40 @@ fn get_config() -> Criterion {
                         .sample_size(10)
 }
 
+/// The data section of a "43" file with `records` events on stop1 and start.
+fn synthetic_data(records: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(records * 8);
+    for idx in 0..records as u64 {
+        let channel = if idx % 2 == 0 { 1 } else { 6 };
+        data.write_u64::<LE>((idx * 10) << 4 | channel).unwrap();
+    }
+    data
+}
+
+fn parse(data: &[u8]) -> LstReturnU16 {
+    let options = ParseOptions::default();
+    let channels = create_channels_u16("43", vec![1, 0, 0, 0, 0, 1], &options.channel_names(), data.len()).unwrap();
+    parse_43(data, 0, &TimepatchBits::new("43", &options.device).unwrap(), channels, &options)
+}
+
 fn pysight_benchmark(c: &mut Criterion) {
-    let pysight_par = Fun::new("Parallel", 
-        |b, t| b.iter(|| analyze_lst_par(*t)));
+    let pysight_par = Fun::new("Parallel",
+        |b, records: &usize| {
+            let data = synthetic_data(*records);
+            b.iter(|| parse(&data))
+        });
 
-    let pysight_seq = Fun::new("Sequential", 
-        |b, t| b.iter(|| analyze_lst_seq(*t)));
+    let pysight_seq = Fun::new("Sequential",
+        |b, records: &usize| {
+            let data = synthetic_data(*records);
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+            b.iter(|| pool.install(|| parse(&data)))
+        });
     let functions = vec!(pysight_par, pysight_seq);
-    c.bench_functions("PySight", functions, 20i32);
+    c.bench_functions("PySight", functions, 1usize << 22);
 }
 
-criterion_group!(name = benches; 
-                 config = get_config(); 
+criterion_group!(name = benches;
+                 config = get_config();
                  targets = pysight_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -1,54 +1,94 @@
 use rayon::prelude::*;
-use std::str;
-use std::sync::Mutex;
-use rayon::prelude::*;
+use std::collections::HashMap;
 use byteorder::{ReadBytesExt, LE};
+use failure::{Error, format_err};
 use pyo3::prelude::*;
 use pyo3::{ToPyObject, IntoPyObject, PyObject, PyDict};
 
 
+use crate::channels::*;
+use crate::device::Device;
 use crate::helper_funcs::*;
+use crate::resync::*;
+use crate::units::*;
+use crate::window::Window;
+use crate::writer::decode_record;
+
+/// The parsed data of a single channel, for timepatches with a tag field of up
+/// to 8 bits. Vectors of fields that don't exist in the timepatch stay empty.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedDataU8 {
+    pub lost: Vec<bool>,
+    pub tag: Vec<u8>,
+    pub edge: Vec<bool>,
+    pub time: Vec<u64>,
+    /// Sweep number of each event, only populated when it was requested
+    pub sweep: Vec<u64>,
+}
 
-pub const NUM_OF_INPUT_CHANNELS: usize = 6;
-
-impl ParsedData {
-    pub fn new(tp: &str, lost: Vec<bool>, tag: TagType, edge: Vec<bool>, time: TimeType) -> ParsedData {
-        match tp {
-            "0" =>  ParsedData { lost: lost, tag: , edge: , time: },
-            "5" => ParsedData { lost: lost, tag: , edge: , time: },
-            "1" => ParsedData { lost: lost, tag: , edge: , time: },
-            "1a" => ParsedData { lost: lost, tag: , edge: , time: },
-            "2a" => ParsedData { lost: lost, tag: , edge: , time: },
-            "22" => ParsedData { lost: lost, tag: , edge: , time: },
-            "32" => ParsedData { lost: lost, tag: , edge: , time: },
-            "2" => ParsedData { lost: lost, tag: , edge: , time: },
-            "5b" => ParsedData { lost: lost, tag: , edge: , time: },
-            "Db" => ParsedData { lost: lost, tag: , edge: , time: },
-            "f3" => ParsedData { lost: lost, tag: , edge: , time: },
-            "43" => ParsedData { lost: lost, tag: , edge: , time: },
-            "c3" => ParsedData { lost: lost, tag: , edge: , time: },
-            "3" => ParsedData { lost: lost, tag: , edge: , time: },
-        }
-        ParsedData { lost, tag, edge, time }
+/// The parsed data of a single channel, for all other timepatches.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedDataU16 {
+    pub lost: Vec<bool>,
+    pub tag: Vec<u16>,
+    pub edge: Vec<bool>,
+    pub time: Vec<u64>,
+    /// Sweep number of each event, only populated when it was requested
+    pub sweep: Vec<u64>,
+}
+
+impl ParsedDataU8 {
+    pub fn new(lost: Vec<bool>, tag: Vec<u8>, edge: Vec<bool>, time: Vec<u64>) -> ParsedDataU8 {
+        ParsedDataU8 {lost, tag, edge, time, sweep: vec![]}
     }
 
     pub fn push_lost(&mut self, val: bool) {
-        &mut self.lost.push(val);
+        self.lost.push(val);
     }
 
     pub fn push_edge(&mut self, val: bool) {
-        &mut self.edge.push(val);
+        self.edge.push(val);
     }
 
     pub fn push_tag(&mut self, val: u8) {
-        &mut self.tag.push(val);
+        self.tag.push(val);
     }
 
     pub fn push_time(&mut self, val: u64) {
-        &mut self.time.push(val);
+        self.time.push(val);
+    }
+
+    pub fn push_sweep(&mut self, val: u64) {
+        self.sweep.push(val);
+    }
+
+    /// Appends the events of `other`, which all follow the events of this
+    /// channel, with their times moved by `time_offset` and their sweeps by
+    /// `sweep_offset`.
+    pub fn append(&mut self, mut other: ParsedDataU8, time_offset: u64, sweep_offset: u64) {
+        self.lost.append(&mut other.lost);
+        self.tag.append(&mut other.tag);
+        self.edge.append(&mut other.edge);
+        self.time.extend(other.time.iter().map(|time| time + time_offset));
+        self.sweep.extend(other.sweep.iter().map(|sweep| sweep + sweep_offset));
+    }
+
+    /// Returns a copy holding only the events marked in `mask`. Vectors
+    /// that aren't populated for this timepatch are left empty.
+    pub fn select(&self, mask: &[bool]) -> ParsedDataU8 {
+        let mut selected = ParsedDataU8::new(pick(&self.lost, mask), pick(&self.tag, mask),
+                                             pick(&self.edge, mask), pick(&self.time, mask));
+        selected.sweep = pick(&self.sweep, mask);
+        selected
     }
 }
 
+/// The values marked in `mask`, or none if `vals` isn't populated.
+fn pick<T: Clone>(vals: &[T], mask: &[bool]) -> Vec<T> {
+    if vals.len() != mask.len() { return vec![] };
+    vals.iter().zip(mask).filter(|(_, keep)| **keep).map(|(val, _)| val.clone()).collect()
+}
+
 impl IntoPyObject for ParsedDataU8 {
     fn into_object(self, py: Python) -> PyObject {
         let dict = PyDict::new(py);
@@ -56,6 +96,7 @@ impl IntoPyObject for ParsedDataU8 {
         dict.set_item("tag", self.tag).expect("TAG insertion error.");
         dict.set_item("edge", self.edge).expect("Edge insertion error.");
         dict.set_item("time", self.time).expect("Time insertion error.");
+        dict.set_item("sweep", self.sweep).expect("Sweep insertion error.");
 
         dict.into()
     }
@@ -68,6 +109,7 @@ impl ToPyObject for ParsedDataU8 {
         dict.set_item("tag", &self.tag).expect("TAG insertion error.");
         dict.set_item("edge", &self.edge).expect("Edge insertion error.");
         dict.set_item("time", &self.time).expect("Time insertion error.");
+        dict.set_item("sweep", &self.sweep).expect("Sweep insertion error.");
 
         dict.into()
     }
@@ -75,23 +117,47 @@ impl ToPyObject for ParsedDataU8 {
 
 impl ParsedDataU16 {
     pub fn new(lost: Vec<bool>, tag: Vec<u16>, edge: Vec<bool>, time: Vec<u64>) -> ParsedDataU16 {
-        ParsedDataU16 {lost, tag, edge, time}
+        ParsedDataU16 {lost, tag, edge, time, sweep: vec![]}
     }
 
     pub fn push_lost(&mut self, val: bool) {
-        &mut self.lost.push(val);
+        self.lost.push(val);
     }
 
     pub fn push_edge(&mut self, val: bool) {
-        &mut self.edge.push(val);
+        self.edge.push(val);
     }
 
     pub fn push_tag(&mut self, val: u16) {
-        &mut self.tag.push(val);
+        self.tag.push(val);
     }
 
     pub fn push_time(&mut self, val: u64) {
-        &mut self.time.push(val);
+        self.time.push(val);
+    }
+
+    pub fn push_sweep(&mut self, val: u64) {
+        self.sweep.push(val);
+    }
+
+    /// Appends the events of `other`, which all follow the events of this
+    /// channel, with their times moved by `time_offset` and their sweeps by
+    /// `sweep_offset`.
+    pub fn append(&mut self, mut other: ParsedDataU16, time_offset: u64, sweep_offset: u64) {
+        self.lost.append(&mut other.lost);
+        self.tag.append(&mut other.tag);
+        self.edge.append(&mut other.edge);
+        self.time.extend(other.time.iter().map(|time| time + time_offset));
+        self.sweep.extend(other.sweep.iter().map(|sweep| sweep + sweep_offset));
+    }
+
+    /// Returns a copy holding only the events marked in `mask`. Vectors
+    /// that aren't populated for this timepatch are left empty.
+    pub fn select(&self, mask: &[bool]) -> ParsedDataU16 {
+        let mut selected = ParsedDataU16::new(pick(&self.lost, mask), pick(&self.tag, mask),
+                                              pick(&self.edge, mask), pick(&self.time, mask));
+        selected.sweep = pick(&self.sweep, mask);
+        selected
     }
 }
 
@@ -102,6 +168,7 @@ impl IntoPyObject for ParsedDataU16 {
         dict.set_item("tag", self.tag).expect("TAG insertion error.");
         dict.set_item("edge", self.edge).expect("Edge insertion error.");
         dict.set_item("time", self.time).expect("Time insertion error.");
+        dict.set_item("sweep", self.sweep).expect("Sweep insertion error.");
 
         dict.into()
     }
@@ -114,647 +181,1403 @@ impl ToPyObject for ParsedDataU16 {
         dict.set_item("tag", &self.tag).expect("TAG insertion error.");
         dict.set_item("edge", &self.edge).expect("Edge insertion error.");
         dict.set_item("time", &self.time).expect("Time insertion error.");
+        dict.set_item("sweep", &self.sweep).expect("Sweep insertion error.");
 
         dict.into()
     }
 }
 
+/// A single record of the list file after it was decoded. `time` is relative
+/// to the start of the sweep in `sweep` until `distribute` makes it absolute.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Event {
+    pub channel: usize,
+    pub edge: bool,
+    pub time: u64,
+    pub sweep: u64,
+    pub tag: u16,
+    pub lost: bool,
+}
+
+/// Decodes the fields every timepatch has in the same place: the channel, the
+/// edge, the time and, if the timepatch has one, the sweep counter. Tags and the
+/// lost bit are left out, and records of an unknown channel give None.
+pub(crate) fn decode_common(mut record: &[u8], bit_order: &[u8; 4], device: &Device) -> Option<Event> {
+    let line = record.read_uint::<LE>(record.len()).ok()?;
+    let channel = device.decode_channel(line)?;
+    let edge = (line >> device.channel_bits) & 0b1 == 1;
+    let time_shift = device.channel_bits + 1;
+    let time = (line >> time_shift) % field_modulus(bit_order[3]);
+    let sweep = if bit_order[2] > 0 {
+        (line >> (time_shift + bit_order[3])) % field_modulus(bit_order[2])
+    } else { 0 };
+    Some(Event { channel, edge, time, sweep, ..Event::default() })
+}
+
+/// Containers of the parsed data of a single channel.
+pub trait ChannelData {
+    /// Appends a decoded event. `bit_order` decides which of the optional
+    /// fields exist in the timepatch and are therefore kept.
+    fn push_event(&mut self, event: &Event, bit_order: &[u8; 4], options: &ParseOptions);
+
+    fn times(&self) -> &[u64];
+}
+
+impl ChannelData for ParsedDataU8 {
+    fn push_event(&mut self, event: &Event, bit_order: &[u8; 4], options: &ParseOptions) {
+        self.push_edge(event.edge);
+        self.push_time(event.time);
+        if bit_order[1] > 0 { self.push_tag(event.tag as u8) };
+        if bit_order[0] > 0 { self.push_lost(event.lost) };
+        if bit_order[2] > 0 && options.sweep_output { self.push_sweep(event.sweep) };
+    }
+
+    fn times(&self) -> &[u64] {
+        &self.time
+    }
+}
+
+impl ChannelData for ParsedDataU16 {
+    fn push_event(&mut self, event: &Event, bit_order: &[u8; 4], options: &ParseOptions) {
+        self.push_edge(event.edge);
+        self.push_time(event.time);
+        if bit_order[1] > 0 { self.push_tag(event.tag) };
+        if bit_order[0] > 0 { self.push_lost(event.lost) };
+        if bit_order[2] > 0 && options.sweep_output { self.push_sweep(event.sweep) };
+    }
+
+    fn times(&self) -> &[u64] {
+        &self.time
+    }
+}
+
+/// Reference of the times of the parsed events.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeMode {
+    /// Time since the start of the acquisition, `range * (sweep - 1)` plus the
+    /// time within the sweep
+    #[default]
+    Absolute,
+    /// Time since the start of the event's sweep
+    SweepRelative,
+}
+
+/// How overflows of the time counter are detected in timepatches without a sweep
+/// counter, where the time restarts from zero in endless mode.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeOverflow {
+    /// Times are returned as they were recorded
+    #[default]
+    Disabled,
+    /// The counter overflows at the full width of the time field, detected by
+    /// the time jumping backwards
+    BackwardJumps,
+    /// The counter restarts every `range` bins, as set in the header
+    Range,
+}
+
+impl TimeOverflow {
+    /// The names "disabled", "jumps" and "range".
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimeOverflow::Disabled => "disabled",
+            TimeOverflow::BackwardJumps => "jumps",
+            TimeOverflow::Range => "range",
+        }
+    }
+}
+
+/// Period of the start events in start/stop mode, where every time is relative to
+/// the most recent start. Counting the starts gives an absolute timeline.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StartClock {
+    /// Times are relative to the most recent start
+    #[default]
+    Disabled,
+    /// Starts arrive every given number of bins
+    Period(u64),
+    /// Starts arrive every `range` bins, as set in the header
+    Range,
+}
+
+/// Edges of a channel that are kept while parsing. The edge bit of a record is 0
+/// for rising edges and 1 for falling edges.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChannelFilter {
+    Rising,
+    Falling,
+    #[default]
+    Both,
+    /// The channel is skipped altogether
+    Off,
+}
+
+impl ChannelFilter {
+    /// Parses the names "rising", "falling", "both" and "off".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rising" => Some(ChannelFilter::Rising),
+            "falling" => Some(ChannelFilter::Falling),
+            "both" => Some(ChannelFilter::Both),
+            "off" => Some(ChannelFilter::Off),
+            _ => None,
+        }
+    }
+
+    pub fn accepts(&self, edge: bool) -> bool {
+        match self {
+            ChannelFilter::Rising => !edge,
+            ChannelFilter::Falling => edge,
+            ChannelFilter::Both => true,
+            ChannelFilter::Off => false,
+        }
+    }
+}
+
+/// What happens to events of inputs that the channel map marks as inactive.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InactivePolicy {
+    /// Parsing fails, naming the inputs and their number of events
+    Strict,
+    /// The inputs are added to the output as if they were active
+    Lenient,
+    /// The events are discarded and counted in the stats
+    #[default]
+    Drop,
+}
+
+impl InactivePolicy {
+    /// Parses the names "strict", "lenient" and "drop".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "strict" => Some(InactivePolicy::Strict),
+            "lenient" => Some(InactivePolicy::Lenient),
+            "drop" => Some(InactivePolicy::Drop),
+            _ => None,
+        }
+    }
+
+    /// Fails in strict mode if any inactive input had events. `names` holds the
+    /// name of every input.
+    pub fn check(&self, stats: &ParseStats, names: &[String]) -> Result<(), Error> {
+        if *self != InactivePolicy::Strict { return Ok(()) };
+        let offending: Vec<String> = stats.inactive.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(id, count)| {
+                let name = names.get(id).cloned().unwrap_or_else(|| format!("input{}", id + 1));
+                format!("{} ({} events)", name, count)
+            })
+            .collect();
+        if offending.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!("Events on inactive channels: {}", offending.join(", ")))
+        }
+    }
+}
+
+/// State of the counters at the start of the parsed data, for parsing a file from
+/// the middle. The default is the start of the file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CounterState {
+    /// Overflows of the sweep counter before the data
+    pub sweep_epoch: u64,
+    /// Overflows of the time counter before the data, in timepatches without sweeps
+    pub time_epoch: u64,
+    /// Start events before the data
+    pub starts: u64,
+    /// Raw sweep counter of the last record before the data
+    pub last_sweep: Option<u64>,
+    /// Raw time counter of the last record before the data, in timepatches without sweeps
+    pub last_time: Option<u64>,
+}
+
+/// Optional processing of the events while they're distributed to their channels.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Dead time of each channel, in bins. An event that follows the previous
+    /// event of its channel by less than that is dropped. Missing or zero
+    /// entries disable the filter for that channel.
+    pub dead_time: Vec<u64>,
+    /// Keep the sweep number of each event, for timepatches with a sweep counter.
+    /// The numbers keep counting up when the counter overflows.
+    pub sweep_output: bool,
+    pub time_mode: TimeMode,
+    pub time_overflow: TimeOverflow,
+    /// Replaces the sweep and overflow handling when it's enabled
+    pub start_clock: StartClock,
+    /// Unit of the times in the Python output. The parsed data is always in bins.
+    pub time_unit: TimeUnit,
+    /// Edges kept on each channel. Missing entries keep both edges.
+    pub channel_filter: Vec<ChannelFilter>,
+    /// Only events inside the window are kept
+    pub window: Option<Window>,
+    pub resume: CounterState,
+    /// Layout of the channel field and names of the inputs
+    pub device: Device,
+    /// Names of the roles some inputs play, e.g. "pmt1" for "stop1", which
+    /// replace the input names in the output
+    pub roles: HashMap<String, String>,
+    pub inactive_events: InactivePolicy,
+    /// Byte offset of the parsed data in the data section, so that skipped
+    /// ranges are reported relative to the whole data section
+    pub data_offset: usize,
+}
+
+impl ParseOptions {
+    fn accepts(&self, event: &Event) -> bool {
+        self.channel_filter.get(event.channel).is_none_or(|filter| filter.accepts(event.edge))
+    }
+
+    /// Names of the channels in the output, in the order of their ids. Invalid
+    /// roles are ignored, see `role_names` for checking them.
+    pub fn channel_names(&self) -> Vec<String> {
+        role_names(&self.device, &self.roles).unwrap_or_else(|_| self.device.channel_names.clone())
+    }
+
+    /// The channel map of the file without the channels that are filtered out,
+    /// so that no memory is reserved for them.
+    pub fn filter_channel_map(&self, channel_map: Vec<u8>) -> Vec<u8> {
+        channel_map.iter().enumerate()
+            .map(|(idx, active)| match self.channel_filter.get(idx) {
+                Some(ChannelFilter::Off) => 0,
+                _ => *active,
+            })
+            .collect()
+    }
+}
+
+/// Counters collected while parsing a file.
+#[derive(Debug, Clone, Default)]
+pub struct ParseStats {
+    /// Events dropped by the dead-time filter, per channel
+    pub dead_time_dropped: Vec<u64>,
+    /// Number of times the sweep counter overflowed
+    pub sweep_wraps: u64,
+    /// Number of times the time counter overflowed, in timepatches without sweeps
+    pub time_wraps: u64,
+    /// Events removed by the edge and channel filter, per channel
+    pub filtered: Vec<u64>,
+    /// Events outside the requested window
+    pub outside_window: u64,
+    /// Number of start events, when they're counted
+    pub starts: u64,
+    /// Events of each stop channel that arrived before the first start when
+    /// starts are counted. These are always the first events of their channel,
+    /// and their times are left relative.
+    pub stops_before_start: Vec<u64>,
+    /// Events of inputs that the channel map marks as inactive, per channel.
+    /// They're only kept in lenient mode.
+    pub inactive: Vec<u64>,
+    /// Events of inputs the channel container has no id for
+    pub unknown_channel: u64,
+    /// Byte ranges of the data section that were skipped because their records
+    /// were damaged or misaligned
+    pub skipped: Vec<SkippedRange>,
+}
+
+impl ParseStats {
+    pub fn new(num_of_channels: usize) -> Self {
+        ParseStats { dead_time_dropped: vec![0; num_of_channels], sweep_wraps: 0, time_wraps: 0,
+                     filtered: vec![0; num_of_channels], outside_window: 0, starts: 0,
+                     stops_before_start: vec![0; num_of_channels], inactive: vec![0; num_of_channels],
+                     unknown_channel: 0, skipped: Vec::new() }
+    }
+
+    /// Adds the counters of the next file of a merged acquisition. Its skipped
+    /// ranges are moved by `data_offset`, the size of the data sections before it.
+    pub fn append(&mut self, other: ParseStats, data_offset: usize) {
+        fn add_each(totals: &mut Vec<u64>, counts: &[u64]) {
+            if totals.len() < counts.len() { totals.resize(counts.len(), 0) };
+            for (total, count) in totals.iter_mut().zip(counts) { *total += count };
+        }
+        add_each(&mut self.dead_time_dropped, &other.dead_time_dropped);
+        add_each(&mut self.filtered, &other.filtered);
+        add_each(&mut self.stops_before_start, &other.stops_before_start);
+        add_each(&mut self.inactive, &other.inactive);
+        self.sweep_wraps += other.sweep_wraps;
+        self.time_wraps += other.time_wraps;
+        self.outside_window += other.outside_window;
+        self.starts += other.starts;
+        self.unknown_channel += other.unknown_channel;
+        self.skipped.extend(other.skipped.iter().map(|range| SkippedRange {
+            start: range.start + data_offset,
+            stop: range.stop + data_offset,
+            reason: range.reason,
+        }));
+    }
+}
+
+impl IntoPyObject for ParseStats {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        dict.set_item("dead_time_dropped", self.dead_time_dropped).expect("Dead time insertion error.");
+        dict.set_item("sweep_wraps", self.sweep_wraps).expect("Sweep wraps insertion error.");
+        dict.set_item("time_wraps", self.time_wraps).expect("Time wraps insertion error.");
+        dict.set_item("filtered", self.filtered).expect("Filtered insertion error.");
+        dict.set_item("outside_window", self.outside_window).expect("Window insertion error.");
+        dict.set_item("starts", self.starts).expect("Starts insertion error.");
+        dict.set_item("stops_before_start", self.stops_before_start).expect("Early stops insertion error.");
+        dict.set_item("inactive", self.inactive).expect("Inactive insertion error.");
+        dict.set_item("unknown_channel", self.unknown_channel).expect("Unknown channel insertion error.");
+        let skipped: Vec<(usize, usize, &str)> = self.skipped.iter()
+            .map(|range| (range.start, range.stop, range.reason.name()))
+            .collect();
+        dict.set_item("skipped", skipped).expect("Skipped insertion error.");
+        dict.into()
+    }
+}
+
+/// Python object of a channel, with its times converted to `unit` if the bin
+/// width is known.
+fn channel_object<T>(py: Python, data: T, bin_width: Option<BinWidth>, unit: TimeUnit) -> PyObject
+    where T: ChannelData + IntoPyObject {
+    let converted = match bin_width {
+        Some(width) if unit != TimeUnit::Bins => Some(width.convert_all(data.times(), unit)),
+        _ => None,
+    };
+    let object = data.into_object(py);
+    if let Some(converted) = converted {
+        let dict: &PyDict = object.cast_as(py).expect("Channel conversion error.");
+        dict.set_item("time", converted).expect("Time insertion error.");
+    }
+    object
+}
+
+/// Name of the unit the times of the Python output end up in.
+fn unit_name(bin_width: Option<BinWidth>, unit: TimeUnit) -> &'static str {
+    match (bin_width, unit) {
+        (Some(_), TimeUnit::Picoseconds) => "ps",
+        (Some(_), TimeUnit::Seconds) => "s",
+        _ => "bins",
+    }
+}
+
+/// The parsed channels of a list file. Only active inputs are part of it, and
+/// they're named after their roles if they were given.
 #[derive(Debug)]
 pub struct LstReturnU16 {
-    stop1: ParsedDataU16,
-    stop2: ParsedDataU16,
-    stop3: ParsedDataU16,
-    stop4: ParsedDataU16,
-    stop5: ParsedDataU16,
-    start: ParsedDataU16,
+    channels: Channels<ParsedDataU16>,
+    stats: ParseStats,
+    bin_width: Option<BinWidth>,
+    time_unit: TimeUnit,
 }
 
 impl LstReturnU16 {
-    pub fn new(stop1: ParsedDataU16, stop2: ParsedDataU16, stop3: ParsedDataU16,
-               stop4: ParsedDataU16, stop5: ParsedDataU16, start: ParsedDataU16) -> Self {
-                   LstReturnU16 { stop1, stop2, stop3, stop4, stop5, start }
-               }
+    pub fn from_channels(channels: Channels<ParsedDataU16>, stats: ParseStats) -> Self {
+        LstReturnU16 { channels, stats, bin_width: None, time_unit: TimeUnit::Bins }
+    }
+
+    pub fn stats(&self) -> &ParseStats {
+        &self.stats
+    }
+
+    /// Sets the bin width of the acquisition and the unit of the times in the Python output.
+    pub fn with_units(mut self, bin_width: Option<BinWidth>, time_unit: TimeUnit) -> Self {
+        self.bin_width = bin_width;
+        self.time_unit = time_unit;
+        self
+    }
+
+    pub fn bin_width(&self) -> Option<BinWidth> {
+        self.bin_width
+    }
+
+    pub fn channels(&self) -> &Channels<ParsedDataU16> {
+        &self.channels
+    }
+
+    /// Times of the input with id `id` in `unit`, if it's active and the bin
+    /// width is known.
+    pub fn times_in(&self, id: usize, unit: TimeUnit) -> Option<Vec<f64>> {
+        let width = self.bin_width?;
+        self.channel(id).map(|channel| width.convert_all(&channel.time, unit))
+    }
+
+    /// Data of the input with id `id`, in the order of the channel codes, or
+    /// None if it isn't active.
+    pub fn channel(&self, id: usize) -> Option<&ParsedDataU16> {
+        self.channels.get(id)
+    }
+
+    /// Data of the channel called `name`, e.g. "stop1" or a role like "laser".
+    pub fn channel_by_name(&self, name: &str) -> Option<&ParsedDataU16> {
+        self.channels.by_name(name)
+    }
+
+    /// Fails if inactive inputs had events and `policy` is strict.
+    pub fn check_inactive(&self, policy: InactivePolicy) -> Result<(), Error> {
+        policy.check(&self.stats, self.channels.id_names())
+    }
+
+    /// Time of the latest event of any channel.
+    pub fn last_time(&self) -> Option<u64> {
+        self.channels.iter().filter_map(|channel| channel.data.time.last()).max().cloned()
+    }
+
+    /// Highest sweep number of any channel, if sweeps were kept.
+    pub fn last_sweep(&self) -> Option<u64> {
+        self.channels.iter().filter_map(|channel| channel.data.sweep.last()).max().cloned()
+    }
+
+    /// The events of all channels merged and ordered by time, e.g. to write
+    /// them to a new list file. Fields that weren't kept are left at 0.
+    pub fn events(&self) -> Vec<Event> {
+        let mut events: Vec<Event> = self.channels.iter()
+            .flat_map(|channel| {
+                let data = &channel.data;
+                (0..data.time.len()).map(move |idx| Event {
+                    channel: channel.id,
+                    edge: data.edge.get(idx).cloned().unwrap_or(false),
+                    time: data.time[idx],
+                    sweep: data.sweep.get(idx).cloned().unwrap_or(0),
+                    tag: data.tag.get(idx).map_or(0, |tag| *tag),
+                    lost: data.lost.get(idx).cloned().unwrap_or(false),
+                })
+            })
+            .collect();
+        events.sort_by_key(|event| event.time);
+        events
+    }
+
+    /// Appends the result of the next file of a merged acquisition, with its
+    /// times moved by `time_offset`, its sweeps by `sweep_offset` and its
+    /// skipped ranges by `data_offset`. Channels that are only active in
+    /// `other` are added.
+    pub fn append(&mut self, other: LstReturnU16, time_offset: u64, sweep_offset: u64, data_offset: usize) {
+        for channel in other.channels {
+            self.channels.activate(channel.id, ParsedDataU16::default());
+            if let Some(data) = self.channels.get_mut(channel.id) {
+                data.append(channel.data, time_offset, sweep_offset);
+            }
+        }
+        self.stats.append(other.stats, data_offset);
+    }
 }
 
 impl IntoPyObject for LstReturnU16 {
     fn into_object(self, py: Python) -> PyObject {
         let dict = PyDict::new(py);
-        dict.set_item("stop1", self.stop1).expect("STOP1 error");
-        dict.set_item("stop2", self.stop2).expect("STOP2 error");
-        dict.set_item("stop3", self.stop3).expect("STOP3 error");
-        dict.set_item("stop4", self.stop4).expect("STOP4 error");
-        dict.set_item("stop5", self.stop5).expect("STOP5 error");
-        dict.set_item("start", self.start).expect("START error");
+        let (width, unit) = (self.bin_width, self.time_unit);
+        for channel in self.channels {
+            dict.set_item(channel.name, channel_object(py, channel.data, width, unit)).expect("Channel error");
+        }
+        dict.set_item("stats", self.stats).expect("Stats error");
+        dict.set_item("bin_width_ps", width.map(|width| width.picoseconds)).expect("Bin width error");
+        dict.set_item("time_unit", unit_name(width, unit)).expect("Time unit error");
         dict.into()
     }
 }
 
+/// The parsed channels of a list file. Only active inputs are part of it, and
+/// they're named after their roles if they were given.
 #[derive(Debug)]
 pub struct LstReturnU8 {
-    stop1: ParsedDataU8,
-    stop2: ParsedDataU8,
-    stop3: ParsedDataU8,
-    stop4: ParsedDataU8,
-    stop5: ParsedDataU8,
-    start: ParsedDataU8,
+    channels: Channels<ParsedDataU8>,
+    stats: ParseStats,
+    bin_width: Option<BinWidth>,
+    time_unit: TimeUnit,
 }
 
 impl LstReturnU8 {
-    pub fn new(stop1: ParsedDataU8, stop2: ParsedDataU8, stop3: ParsedDataU8,
-               stop4: ParsedDataU8, stop5: ParsedDataU8, start: ParsedDataU8) -> Self {
-                   LstReturnU8 { stop1, stop2, stop3, stop4, stop5, start }
-               }
+    pub fn from_channels(channels: Channels<ParsedDataU8>, stats: ParseStats) -> Self {
+        LstReturnU8 { channels, stats, bin_width: None, time_unit: TimeUnit::Bins }
+    }
+
+    pub fn stats(&self) -> &ParseStats {
+        &self.stats
+    }
+
+    /// Sets the bin width of the acquisition and the unit of the times in the Python output.
+    pub fn with_units(mut self, bin_width: Option<BinWidth>, time_unit: TimeUnit) -> Self {
+        self.bin_width = bin_width;
+        self.time_unit = time_unit;
+        self
+    }
+
+    pub fn bin_width(&self) -> Option<BinWidth> {
+        self.bin_width
+    }
+
+    pub fn channels(&self) -> &Channels<ParsedDataU8> {
+        &self.channels
+    }
+
+    /// Times of the input with id `id` in `unit`, if it's active and the bin
+    /// width is known.
+    pub fn times_in(&self, id: usize, unit: TimeUnit) -> Option<Vec<f64>> {
+        let width = self.bin_width?;
+        self.channel(id).map(|channel| width.convert_all(&channel.time, unit))
+    }
+
+    /// Data of the input with id `id`, in the order of the channel codes, or
+    /// None if it isn't active.
+    pub fn channel(&self, id: usize) -> Option<&ParsedDataU8> {
+        self.channels.get(id)
+    }
+
+    /// Data of the channel called `name`, e.g. "stop1" or a role like "laser".
+    pub fn channel_by_name(&self, name: &str) -> Option<&ParsedDataU8> {
+        self.channels.by_name(name)
+    }
+
+    /// Fails if inactive inputs had events and `policy` is strict.
+    pub fn check_inactive(&self, policy: InactivePolicy) -> Result<(), Error> {
+        policy.check(&self.stats, self.channels.id_names())
+    }
+
+    /// Time of the latest event of any channel.
+    pub fn last_time(&self) -> Option<u64> {
+        self.channels.iter().filter_map(|channel| channel.data.time.last()).max().cloned()
+    }
+
+    /// Highest sweep number of any channel, if sweeps were kept.
+    pub fn last_sweep(&self) -> Option<u64> {
+        self.channels.iter().filter_map(|channel| channel.data.sweep.last()).max().cloned()
+    }
+
+    /// The events of all channels merged and ordered by time, e.g. to write
+    /// them to a new list file. Fields that weren't kept are left at 0.
+    pub fn events(&self) -> Vec<Event> {
+        let mut events: Vec<Event> = self.channels.iter()
+            .flat_map(|channel| {
+                let data = &channel.data;
+                (0..data.time.len()).map(move |idx| Event {
+                    channel: channel.id,
+                    edge: data.edge.get(idx).cloned().unwrap_or(false),
+                    time: data.time[idx],
+                    sweep: data.sweep.get(idx).cloned().unwrap_or(0),
+                    tag: data.tag.get(idx).map_or(0, |tag| u16::from(*tag)),
+                    lost: data.lost.get(idx).cloned().unwrap_or(false),
+                })
+            })
+            .collect();
+        events.sort_by_key(|event| event.time);
+        events
+    }
+
+    /// Appends the result of the next file of a merged acquisition, with its
+    /// times moved by `time_offset`, its sweeps by `sweep_offset` and its
+    /// skipped ranges by `data_offset`. Channels that are only active in
+    /// `other` are added.
+    pub fn append(&mut self, other: LstReturnU8, time_offset: u64, sweep_offset: u64, data_offset: usize) {
+        for channel in other.channels {
+            self.channels.activate(channel.id, ParsedDataU8::default());
+            if let Some(data) = self.channels.get_mut(channel.id) {
+                data.append(channel.data, time_offset, sweep_offset);
+            }
+        }
+        self.stats.append(other.stats, data_offset);
+    }
 }
 
 impl IntoPyObject for LstReturnU8 {
     fn into_object(self, py: Python) -> PyObject {
         let dict = PyDict::new(py);
-        dict.set_item("stop1", self.stop1).expect("STOP1 error");
-        dict.set_item("stop2", self.stop2).expect("STOP2 error");
-        dict.set_item("stop3", self.stop3).expect("STOP3 error");
-        dict.set_item("stop4", self.stop4).expect("STOP4 error");
-        dict.set_item("stop5", self.stop5).expect("STOP5 error");
-        dict.set_item("start", self.start).expect("START error");
+        let (width, unit) = (self.bin_width, self.time_unit);
+        for channel in self.channels {
+            dict.set_item(channel.name, channel_object(py, channel.data, width, unit)).expect("Channel error");
+        }
+        dict.set_item("stats", self.stats).expect("Stats error");
+        dict.set_item("bin_width_ps", width.map(|width| width.picoseconds)).expect("Bin width error");
+        dict.set_item("time_unit", unit_name(width, unit)).expect("Time unit error");
         dict.into()
     }
 }
 
+/// Number of records decoded in parallel before they're followed in file order.
+const RECORDS_PER_BLOCK: usize = 1 << 20;
+
+/// A record after the parallel decoding step.
+#[derive(Debug, Clone, Copy)]
+enum Decoded {
+    /// All-zero padding
+    Empty,
+    Invalid,
+    Valid(Event),
+}
+
+/// Follows a counter that restarts every `modulus` counts, e.g. the sweep
+/// counter, in file order and counts its overflows, so that the values keep
+/// increasing after the counter wraps around. Only a drop of more than half the
+/// modulus is taken as a wrap, so records that arrive slightly out of order
+/// around the overflow don't start a new epoch. For the same reason a wrap is
+/// missed if the counter moves on by more than half the modulus between two
+/// values, e.g. if more than `modulus / 2` sweeps pass without any event, and
+/// everything after it is then a whole modulus too early.
+pub(crate) struct CounterUnwrapper {
+    modulus: u64,
+    pub(crate) epoch: u64,
+    pub(crate) last: Option<u64>,
+}
+
+impl CounterUnwrapper {
+    pub(crate) fn new(modulus: u64) -> Self {
+        CounterUnwrapper { modulus, epoch: 0, last: None }
+    }
+
+    /// The unwrapped value, and whether the counter wrapped at this event.
+    pub(crate) fn unwrap(&mut self, value: u64) -> (u64, bool) {
+        let wrapped = match self.last {
+            Some(last) => value < last && last - value > self.modulus / 2,
+            None => false,
+        };
+        if wrapped { self.epoch += 1 };
+        self.last = Some(value);
+        (self.epoch * self.modulus + value, wrapped)
+    }
+}
+
+/// Number of counts after which a field of `bits` bits overflows.
+pub(crate) fn field_modulus(bits: u8) -> u64 {
+    if bits >= 64 { u64::MAX } else { 1u64 << bits }
+}
+
+/// Turns the decoded times into absolute times, following the sweep, time and
+/// start counters in file order.
+pub(crate) struct Timeline {
+    range: u64,
+    has_sweeps: bool,
+    sweeps: CounterUnwrapper,
+    times: Option<CounterUnwrapper>,
+    start_period: Option<u64>,
+    start_channel: usize,
+    pub(crate) starts: u64,
+}
+
+impl Timeline {
+    pub(crate) fn new(range: u64, bit_order: &[u8; 4], options: &ParseOptions) -> Self {
+        let mut sweeps = CounterUnwrapper::new(field_modulus(bit_order[2]));
+        sweeps.epoch = options.resume.sweep_epoch;
+        sweeps.last = options.resume.last_sweep;
+        let time_modulus = match options.time_overflow {
+            TimeOverflow::Range if range > 0 => Some(range),
+            TimeOverflow::Range | TimeOverflow::BackwardJumps => Some(field_modulus(bit_order[3])),
+            TimeOverflow::Disabled => None,
+        };
+        let times = time_modulus.map(|modulus| {
+            let mut unwrapper = CounterUnwrapper::new(modulus);
+            unwrapper.epoch = options.resume.time_epoch;
+            unwrapper.last = options.resume.last_time;
+            unwrapper
+        });
+        let start_period = match options.start_clock {
+            StartClock::Period(period) => Some(period),
+            StartClock::Range => Some(range),
+            StartClock::Disabled => None,
+        };
+        Timeline { range, has_sweeps: bit_order[2] > 0, sweeps, times, start_period,
+                   start_channel: options.device.start_channel(), starts: options.resume.starts }
+    }
+
+    /// The absolute time of the event. The sweep of the event is replaced by
+    /// its unwrapped value.
+    pub(crate) fn absolute(&mut self, event: &mut Event, stats: &mut ParseStats) -> u64 {
+        if let Some(period) = self.start_period {
+            if event.channel == self.start_channel { self.starts += 1 };
+            match self.starts {
+                0 => {
+                    if let Some(count) = stats.stops_before_start.get_mut(event.channel) { *count += 1 };
+                    event.time
+                },
+                starts => event.time + period * (starts - 1),
+            }
+        } else if self.has_sweeps {
+            let (sweep, wrapped) = self.sweeps.unwrap(event.sweep);
+            if wrapped { stats.sweep_wraps += 1 };
+            event.sweep = sweep;
+            event.time + self.range * sweep.saturating_sub(1)
+        } else if let Some(times) = self.times.as_mut() {
+            let (time, wrapped) = times.unwrap(event.time);
+            if wrapped { stats.time_wraps += 1 };
+            time
+        } else { event.time }
+    }
+
+    /// The counters after the last event, to resume parsing from the next one.
+    pub(crate) fn state(&self) -> CounterState {
+        CounterState {
+            sweep_epoch: self.sweeps.epoch,
+            time_epoch: self.times.as_ref().map_or(0, |times| times.epoch),
+            starts: self.starts,
+            last_sweep: self.sweeps.last,
+            last_time: self.times.as_ref().and_then(|times| times.last),
+        }
+    }
+}
+
+/// An event as `for_each_record` follows it along the file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FollowedEvent {
+    /// All fields of the record, with the sweep unwrapped
+    pub(crate) event: Event,
+    /// Absolute time of the event
+    pub(crate) time: u64,
+    /// The counters right before the event
+    pub(crate) state: CounterState,
+}
+
+/// Follows the records of a data section with the layout of `timepatch` in file
+/// order, decoding each block of them in parallel. `visit` gets the offset and
+/// raw value of every record that isn't all zeros, and the followed event if
+/// its channel code belongs to an input of `options.device`. The counters are
+/// followed like `options` asks for, but nothing is filtered. Bytes after the
+/// last complete record are ignored.
+pub(crate) fn for_each_record<F>(data: &[u8], timepatch: &str, range: u64, options: &ParseOptions,
+                                 mut visit: F) -> Result<(), Error>
+    where F: FnMut(usize, u64, Option<FollowedEvent>) -> Result<(), Error> {
+    let bit_order = TimepatchBits::new(timepatch, &options.device)?;
+    let record_size = bytes_per_record(timepatch)?;
+    let below = lost_below_tag(timepatch);
+    let mut timeline = Timeline::new(range, &bit_order, options);
+    let mut stats = ParseStats::new(options.device.num_of_channels());
+    let complete = data.len() - data.len() % record_size;
+    let mut records: Vec<(u64, Option<Event>)> = Vec::with_capacity(RECORDS_PER_BLOCK);
+    for (block_idx, block) in data[..complete].chunks(record_size * RECORDS_PER_BLOCK).enumerate() {
+        block
+            .par_chunks(record_size)
+            .map(|record| {
+                let line = { record }.read_uint::<LE>(record_size).unwrap_or(0);
+                (line, decode_record(line, &bit_order, below, &options.device))
+            })
+            .collect_into_vec(&mut records);
+        for (idx, (line, event)) in records.iter().enumerate() {
+            if *line == 0 { continue };
+            let offset = (block_idx * RECORDS_PER_BLOCK + idx) * record_size;
+            let followed = event.map(|mut event| {
+                let state = timeline.state();
+                let time = timeline.absolute(&mut event, &mut stats);
+                FollowedEvent { event, time, state }
+            });
+            visit(offset, *line, followed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the records of the file and distributes the events to their channels.
+/// The records of each block are decoded in parallel, but they're pushed in file
+/// order, so the events of every channel keep the order in which they were
+/// recorded. All-zero records are skipped. In timepatches with a sweep counter the
+/// decoded time is within the sweep, and the sweep offset is added here after the
+/// counter was unwrapped, so absolute times stay monotonic across overflows. In
+/// the other timepatches the time counter itself is unwrapped if
+/// `options.time_overflow` asks for it. In start/stop mode the start events can be
+/// counted instead, and each time is offset by the start it follows. The counters
+/// start from `options.resume`, and events outside `options.window` are dropped.
+/// The edge and channel filter is applied after the counters saw the event, and
+/// the dead-time filter always works on the absolute times of the events that
+/// passed it. Events of inactive inputs are counted, and only kept if
+/// `options.inactive_events` is lenient, in which case their channel is added to
+/// `channels`. Events of inputs without an id in `channels` are only counted.
+/// Decoding starts from the first offset with trusted alignment. A damaged
+/// record is skipped, and if the records that follow it aren't valid either,
+/// decoding resumes from the next offset with trusted alignment. The skipped
+/// byte ranges are listed in the stats.
+pub fn distribute<T, F>(data: &[u8], record_size: usize, range: u64, bit_order: &[u8; 4],
+                        mut channels: Channels<T>, options: &ParseOptions, decode: F)
+    -> (Channels<T>, ParseStats)
+    where T: ChannelData + Default, F: Fn(&[u8]) -> Option<Event> + Sync {
+    let mut stats = ParseStats::new(channels.num_of_ids());
+    let mut last_time: Vec<Option<u64>> = vec![None; channels.num_of_ids()];
+    // lenient mode activates inactive channels, whose events are still counted
+    let inactive: Vec<bool> = (0..channels.num_of_ids()).map(|ch| !channels.is_active(ch)).collect();
+    let mut timeline = Timeline::new(range, bit_order, options);
+    let resync = Resync::new(record_size, bit_order, options);
+    let mut records: Vec<Decoded> = Vec::with_capacity(RECORDS_PER_BLOCK);
+    let mut last_key = None;
+    let mut pos = 0;
+    if let Some((start, reason)) = resync.start(data) {
+        stats.skipped.push(SkippedRange { start: options.data_offset, stop: options.data_offset + start, reason });
+        pos = start;
+    }
+    'blocks: while pos < data.len() {
+        let block = &data[pos..(pos + record_size * RECORDS_PER_BLOCK).min(data.len())];
+        block
+            .par_chunks(record_size)
+            .map(|line| if !line.iter().any(|byte| *byte != 0) {
+                Decoded::Empty
+            } else if !resync.is_valid(line) {
+                Decoded::Invalid
+            } else {
+                decode(line).map_or(Decoded::Invalid, Decoded::Valid)
+            })
+            .collect_into_vec(&mut records);
+        // records before this offset were skipped after an invalid record
+        let mut skip_until = pos;
+        for (idx, record) in records.iter().enumerate() {
+            let offset = pos + idx * record_size;
+            if offset < skip_until { continue };
+            let mut event = match *record {
+                Decoded::Empty => continue,
+                Decoded::Valid(event) => event,
+                Decoded::Invalid => {
+                    let (resume, reason) = resync.resume_after(data, offset, last_key);
+                    stats.skipped.push(SkippedRange { start: options.data_offset + offset,
+                                                      stop: options.data_offset + resume, reason });
+                    if (resume - pos) % record_size != 0 {
+                        pos = resume;
+                        continue 'blocks;
+                    }
+                    skip_until = resume;
+                    continue;
+                },
+            };
+            last_key = Some(resync.key(&event));
+            let ch = event.channel;
+            if ch >= channels.num_of_ids() {
+                stats.unknown_channel += 1;
+                continue;
+            }
+            let time = timeline.absolute(&mut event, &mut stats);
+            if let Some(window) = options.window {
+                if !window.contains(time, event.sweep) {
+                    stats.outside_window += 1;
+                    continue;
+                }
+            }
+            if !options.accepts(&event) {
+                stats.filtered[ch] += 1;
+                continue;
+            }
+            if inactive[ch] {
+                stats.inactive[ch] += 1;
+                if options.inactive_events != InactivePolicy::Lenient { continue };
+                if !channels.is_active(ch) { channels.activate(ch, T::default()) };
+            }
+            let dead_time = options.dead_time.get(ch).cloned().unwrap_or(0);
+            if let Some(last) = last_time[ch] {
+                if time >= last && time - last < dead_time {
+                    stats.dead_time_dropped[ch] += 1;
+                    continue;
+                }
+            }
+            last_time[ch] = Some(time);
+            if options.time_mode == TimeMode::Absolute {
+                event.time = time;
+            }
+            if let Some(channel) = channels.get_mut(ch) {
+                channel.push_event(&event, bit_order, options);
+            }
+        }
+        pos = skip_until.max(pos + block.len());
+    }
+    stats.starts = timeline.starts;
+    (channels, stats)
+}
+
 
 /// Parse a list file for time patch "1"
-pub fn parse_1(data: &[u8], _range: u64, bit_order: &[u8; 4],
-               parsed_data: Vec<Mutex<ParsedDataU16>>)
+pub fn parse_1(data: &[u8], range: u64, bit_order: &[u8; 4],
+               parsed_data: Channels<ParsedDataU16>, options: &ParseOptions)
     -> LstReturnU16 {
     let bitmap = to_bits_u32(bit_order);
-    data
-        .par_chunks(4)
-        .filter_map(|mut line| if line != [0u8; 4] {
-            line.read_u32::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            println!("{:?}", line);
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]).into();
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 4, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u32::<LE>().ok()?;
+        let ch = device.decode_channel(u64::from(line))?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = (line & bitmap[3]).into();
+        Some(Event { channel: ch, edge, time, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 /// Parse a list file for time patch "0"
-pub fn parse_0(data: &[u8], _range: u64, bit_order: &[u8; 4],
-               parsed_data: Vec<Mutex<ParsedDataU16>>) -> LstReturnU16 {
+pub fn parse_0(data: &[u8], range: u64, bit_order: &[u8; 4],
+               parsed_data: Channels<ParsedDataU16>, options: &ParseOptions) -> LstReturnU16 {
     let bitmap = to_bits_u16(bit_order);
-    data
-        .par_chunks(2)
-        .filter_map(|mut line| if line != [0u8; 2] {
-            line.read_u16::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            println!("{:?}", line);
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]).into();
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 2, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u16::<LE>().ok()?;
+        let ch = device.decode_channel(u64::from(line))?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = (line & bitmap[3]).into();
+        Some(Event { channel: ch, edge, time, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 /// Parse a list file for time patch "5"
 pub fn parse_5(data: &[u8], range: u64, bit_order: &[u8; 4],
-               parsed_data: Vec<Mutex<ParsedDataU16>>)
+               parsed_data: Channels<ParsedDataU16>, options: &ParseOptions)
     -> LstReturnU16 {
     let bitmap = to_bits_u32(bit_order);
-    data
-        .par_chunks(4)
-        .filter_map(|mut line| if line != [0u8; 4] {
-            line.read_u32::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            println!("line: {:b}", line);
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u8 = (line & bitmap[2]) as u8;
-            time += range * (u64::from(sweep - 1));
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 4, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u32::<LE>().ok()?;
+        let ch = device.decode_channel(u64::from(line))?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = (line & bitmap[3]) as u64;
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = (line & bitmap[2]) as u64;
+        Some(Event { channel: ch, edge, time, sweep, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 
 /// Parse a list file for time patch "1a"
 pub fn parse_1a(data: &[u8], range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>)
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions)
     -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(6)
-        .filter_map(|mut line| if line != [0u8; 6] {
-            line.read_u48::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u16 = (line & bitmap[2]) as u16;
-            time += range * (u64::from(sweep - 1));
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))}
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 6, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u48::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = line & bitmap[2];
+        Some(Event { channel: ch, edge, time, sweep, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
+}
 
 
 /// Parse a list file for time patch "2a"
 pub fn parse_2a(data: &[u8], range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU8>>) -> LstReturnU8 {
+                parsed_data: Channels<ParsedDataU8>, options: &ParseOptions) -> LstReturnU8 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(6)
-        .filter_map(|mut line| if line != [0u8; 6] {
-            line.read_u48::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u16 = (line & bitmap[2]) as u16;
-            time += range * (u64::from(sweep - 1));
-            line = line >> bit_order[2]; // throw away "sweep" bits
-            let tag: u8 = (line & bitmap[1]) as u8;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU8> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU8::new(parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0))}
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 6, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u48::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = line & bitmap[2];
+        line >>= bit_order[2]; // throw away "sweep" bits
+        let tag: u16 = (line & bitmap[1]) as u16;
+        Some(Event { channel: ch, edge, time, sweep, tag, ..Event::default() })
+    });
+    LstReturnU8::from_channels(channels, stats)
+}
 
 
 /// Parse a list file for time patch "22"
-pub fn parse_22(data: &[u8], _range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU8>>) -> LstReturnU8 {
+pub fn parse_22(data: &[u8], range: u64, bit_order: &[u8; 4],
+                parsed_data: Channels<ParsedDataU8>, options: &ParseOptions) -> LstReturnU8 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(6)
-        .filter_map(|mut line| if line != [0u8; 6] {
-            line.read_u48::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let tag: u8 = (line & bitmap[1]) as u8;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU8> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU8::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 6, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u48::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let tag: u16 = (line & bitmap[1]) as u16;
+        Some(Event { channel: ch, edge, time, tag, ..Event::default() })
+    });
+    LstReturnU8::from_channels(channels, stats)
 }
 
 
 /// Parse a list file for time patch "32"
 pub fn parse_32(data: &[u8], range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>) -> LstReturnU16 {
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions) -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(6)
-        .filter_map(|mut line| if line != [0u8; 6] {
-            line.read_u48::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u16 = (line & bitmap[2]) as u16;
-            time += range * (u64::from(sweep - 1));
-            line = line >> bit_order[2]; // throw away "sweep" bits
-            let lost: bool = (line & bitmap[0]) == 1;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_lost(lost);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 6, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u48::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = line & bitmap[2];
+        line >>= bit_order[2]; // throw away "sweep" bits
+        let lost: bool = (line & bitmap[0]) == 1;
+        Some(Event { channel: ch, edge, time, sweep, lost, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 
 /// Parse a list file for time patch "2"
-pub fn parse_2(data: &[u8], _range: u64, bit_order: &[u8; 4],
-               parsed_data: Vec<Mutex<ParsedDataU16>>) -> LstReturnU16 {
+pub fn parse_2(data: &[u8], range: u64, bit_order: &[u8; 4],
+               parsed_data: Channels<ParsedDataU16>, options: &ParseOptions) -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(6)
-        .filter_map(|mut line| if line != [0u8; 6] {
-            line.read_u48::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]) as u64;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 6, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u48::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        Some(Event { channel: ch, edge, time, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 
 /// Parse a list file for time patch "5b"
 pub fn parse_5b(data: &[u8], range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>)
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions)
     -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(8)
-        .filter_map(|mut line| if line != [0u8; 8] {
-            line.read_u64::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u16 = (line & bitmap[2]) as u16;
-            time += range * (u64::from(sweep - 1));
-            line = line >> bit_order[2]; // throw away "sweep" bits
-            let tag: u16 = (line & bitmap[1]) as u16;
-            line = line >> bit_order[1]; // throw away "tag" bits
-            let lost: bool = (line & bitmap[0]) == 1;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-            ParsedData.push_lost(lost);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 8, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u64::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = line & bitmap[2];
+        line >>= bit_order[2]; // throw away "sweep" bits
+        let tag: u16 = (line & bitmap[1]) as u16;
+        line >>= bit_order[1]; // throw away "tag" bits
+        let lost: bool = (line & bitmap[0]) == 1;
+        Some(Event { channel: ch, edge, time, sweep, tag, lost })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 /// Parse a list file for time patch "Db"
+#[allow(non_snake_case)]
 pub fn parse_Db(data: &[u8], range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>) -> LstReturnU16 {
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions) -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(8)
-        .filter_map(|mut line| if line != [0u8; 8] {
-            line.read_u64::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u16 = (line & bitmap[2]) as u16;
-            time += range * (u64::from(sweep - 1));
-            line = line >> bit_order[2];  // throw away "sweep bits"
-            let tag: u16 = (line & bitmap[1]) as u16;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 8, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u64::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = line & bitmap[2];
+        line >>= bit_order[2];  // throw away "sweep bits"
+        let tag: u16 = (line & bitmap[1]) as u16;
+        Some(Event { channel: ch, edge, time, sweep, tag, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 /// Parse a list file for time patch "f3"
 pub fn parse_f3(data: &[u8], range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>) -> LstReturnU16 {
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions) -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(8)
-        .filter_map(|mut line| if line != [0u8; 8] {
-            line.read_u64::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let mut time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let sweep: u16 = (line & bitmap[2]) as u16;
-            time += range * (u64::from(sweep - 1));
-            line = line >> bit_order[2]; // throw away "sweep" bits
-            let lost: bool = (line & bitmap[0]) == 1;
-            line = line >> bit_order[0];  // throw away lost bit
-            let tag: u16 = (line & bitmap[1]) as u16;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-            ParsedData.push_lost(lost);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 8, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u64::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let sweep: u64 = line & bitmap[2];
+        line >>= bit_order[2]; // throw away "sweep" bits
+        let lost: bool = (line & bitmap[0]) == 1;
+        line >>= bit_order[0];  // throw away lost bit
+        let tag: u16 = (line & bitmap[1]) as u16;
+        Some(Event { channel: ch, edge, time, sweep, tag, lost })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 
 /// Parse a list file for time patch "43"
-pub fn parse_43(data: &[u8], _range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>)
+pub fn parse_43(data: &[u8], range: u64, bit_order: &[u8; 4],
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions)
     -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(8)
-        .filter_map(|mut line| if line != [0u8; 8] {
-            line.read_u64::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let tag: u16 = (line & bitmap[1]) as u16;
-            line = line >> bit_order[1]; // throw away "tag" bits
-            let lost: bool = (line & bitmap[0]) == 1;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error.");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-            ParsedData.push_lost(lost);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 8, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u64::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let tag: u16 = (line & bitmap[1]) as u16;
+        line >>= bit_order[1]; // throw away "tag" bits
+        let lost: bool = (line & bitmap[0]) == 1;
+        Some(Event { channel: ch, edge, time, tag, lost, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 
 /// Parse a list file for time patch "c3"
-pub fn parse_c3(data: &[u8], _range: u64, bit_order: &[u8; 4],
-                parsed_data: Vec<Mutex<ParsedDataU16>>) -> LstReturnU16 {
+pub fn parse_c3(data: &[u8], range: u64, bit_order: &[u8; 4],
+                parsed_data: Channels<ParsedDataU16>, options: &ParseOptions) -> LstReturnU16 {
     let bitmap = to_bits_u64(bit_order);
-    data
-        .par_chunks(8)
-        .filter_map(|mut line| if line != [0u8; 8] {
-            line.read_u64::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let tag: u16 = (line & bitmap[1]) as u16;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error.");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-        }).collect::<Vec<_>>();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU16> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU16::new(parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0),
-                      parsed_data_no_mutex.remove(0))
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 8, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u64::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let tag: u16 = (line & bitmap[1]) as u16;
+        Some(Event { channel: ch, edge, time, tag, ..Event::default() })
+    });
+    LstReturnU16::from_channels(channels, stats)
 }
 
 /// Parse a list file for time patch "3"
-pub fn parse_3(data: &[u8], _range: u64, bit_order: &[u8; 4],
-               parsed_data: Vec<Mutex<ParsedDataU8>>)
+pub fn parse_3(data: &[u8], range: u64, bit_order: &[u8; 4],
+               parsed_data: Channels<ParsedDataU8>, options: &ParseOptions)
     -> LstReturnU8 {
     let bitmap = to_bits_u64(bit_order);
-    let _res: Vec<_> = data
-        .par_chunks(8)
-        .filter_map(|mut line| if line != [0u8; 8] {
-            line.read_u64::<LE>().ok()
-            } else { None })
-        .map(|mut line| {
-            let ch = ((line & 0b111) - 1) as usize;
-            line = line >> 3;  // throw away "channel" bits
-            let edge = (line & 0b1) == 1;
-            line = line >> 1;  // throw away "edge" bit
-            let time: u64 = (line & bitmap[3]) as u64;
-            line = line >> bit_order[3]; // throw away "time" bits
-            let tag: u8 = (line & bitmap[1]) as u8;
-            line = line >> bit_order[1]; // throw away "tag" bits
-            let lost: bool = (line & bitmap[0]) == 1;
-            let mut ParsedData = parsed_data[ch].lock().expect("Mutex lock error");
-            ParsedData.push_edge(edge);
-            ParsedData.push_time(time);
-            ParsedData.push_tag(tag);
-            ParsedData.push_lost(lost);
-        }).collect();
-    let mut parsed_data_no_mutex: Vec<ParsedDataU8> = parsed_data.into_iter().map(|x| x.into_inner().unwrap()).collect();
-    LstReturnU8::new(parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0),
-                     parsed_data_no_mutex.remove(0))
-}
-
-
-// /// Parse a list file for time patch "5"
-// fn seq_parse_5(data: &[u8], range: u64, bit_order: &[u8; 4],
-//                            mut parsed_data: Vec<Vec<ParsedData>>)
-//     -> Result<Vec<Vec<ParsedData>>, Error> {
-//     let num_of_bytes_per_line = ((bit_order.iter().sum::<u8>() + 4) / 8) as usize;
-//     let bitmap = to_bits_u32(bit_order);
-//     let res: Vec<_> = data
-//         .chunks(4)
-//         .filter_map(|mut line| if line != [0u8; 4] {
-//             line.read_u32::<LE>().ok()
-//             } else { None })
-//         .map(|mut line| {
-//             let ch = ((line & 0b111) - 1) as usize;
-//             line = line >> 3;  // throw away "channel" bits
-//             let edge = (line & 0b1) == 1;
-//             line = line >> 1;  // throw away "edge" bit
-//             let mut time: u64 = (line & bitmap[3]) as u64;
-//             line = line >> bit_order[3]; // throw away "time" bits
-//             let sweep: u16 = (line & bitmap[2]) as u16;
-//             time += range * (u64::from(sweep - 1));
-//             line = line >> bit_order[2]; // throw away "sweep" bits
-//             let tag: u16 = (line & bitmap[1]) as u16;
-//             line = line >> bit_order[1]; // throw away "tag" bits
-//             let lost: bool = (line & bitmap[0]) == 1;
-//             let dl = ParsedData::new(lost, tag, edge, time);
-//             parsed_data[ch].push(dl);
-//         }).collect();
-//     Ok(parsed_data)
-// }
+    let device = &options.device;
+    let (channels, stats) = distribute(data, 8, range, bit_order, parsed_data, options, |mut line| {
+        let mut line = line.read_u64::<LE>().ok()?;
+        let ch = device.decode_channel(line)?;
+        line >>= device.channel_bits;  // throw away "channel" bits
+        let edge = (line & 0b1) == 1;
+        line >>= 1;  // throw away "edge" bit
+        let time: u64 = line & bitmap[3];
+        line >>= bit_order[3]; // throw away "time" bits
+        let tag: u16 = (line & bitmap[1]) as u16;
+        line >>= bit_order[1]; // throw away "tag" bits
+        let lost: bool = (line & bitmap[0]) == 1;
+        Some(Event { channel: ch, edge, time, tag, lost, ..Event::default() })
+    });
+    LstReturnU8::from_channels(channels, stats)
+}
 
 
-/// Parse a list file for time patch "43"
-// fn seq_parse_43(data: &[u8], range: u64, bit_order: &[u8; 4],
-//                            mut parsed_data: Vec<Vec<ParsedData>>)
-//     -> Result<Vec<Vec<ParsedData>>, Error> {
-//     let num_of_bytes_per_line = ((bit_order.iter().sum::<u8>() + 4) / 8) as usize;
-//     let bitmap = to_bits_u64(bit_order);
-//     let res: Vec<_> = data
-//         .chunks(4)
-//         .filter_map(|mut line| if line != [0u8; 4] {
-//             line.read_u64::<LE>().ok()
-//             } else { None })
-//         .map(|mut line| {
-//             let ch = ((line & 0b111) - 1) as usize;
-//             line = line >> 3;  // throw away "channel" bits
-//             let edge = (line & 0b1) == 1;
-//             line = line >> 1;  // throw away "edge" bit
-//             let time: u64 = (line & bitmap[3]) as u64;
-//             line = line >> bit_order[3]; // throw away "time" bits
-//             line = line >> bit_order[2]; // throw away "sweep" bits
-//             let tag: u16 = (line & bitmap[1]) as u16;
-//             line = line >> bit_order[1]; // throw away "tag" bits
-//             let lost: bool = (line & bitmap[0]) == 1;
-//             let dl = ParsedData::new(lost, tag, edge, time);
-//             parsed_data[ch].push(dl);
-//         }).collect();
-//     Ok(parsed_data)
-// }
-
-
-
-
-/// Mock implementation of the parsing function that uses parallel
-/// execution. Used for benchmarking.
 #[cfg(test)]
-pub fn analyze_lst_par(no: i32) -> Result<Vec<Mutex<Vec<ParsedData>>>, Error> {
-    let fname = "1000nm_Pulsatile_Modulation_-9000mV_to_9500mV_1_sweep_each_32s_long009.lst";  //002
-    let start_of_data = 1568usize;  // 1565
-    let range = 80000u64;
-    let timepatch = "43";
-    let channel_map = vec![1, 0, 0, 0, 0, 1];
-
-    let data_with_headers = FileBuffer::open(fname).expect("bad file name");
-    let data_size: usize = (fs::metadata(fname)?.len() - start_of_data as u64) as usize;
-    let data = &data_with_headers[start_of_data..];
-    // Open the file and convert it to a usable format
-
-    let chan_map = create_channel_vec(data_size, channel_map);
-
-    let tp_enum = Timepatch::new(timepatch);
-    let processed_data = match tp_enum {
-        Timepatch::Tp43(func) => func(data, range, &TimepatchBits::new(timepatch), chan_map),
-        _ => panic!()
-    };
-    processed_data
-}
+mod tests {
+    use std::{env, fs};
 
-/// Mock implementation of the parsing function that uses sequential,
-/// instead of parallel, parsing. Used for benchmarking.
-#[cfg(test)]
-pub fn analyze_lst_seq(no: i32) -> Result<Vec<Vec<ParsedData>>, Error> {
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+    use crate::analyze_lst_u16;
+    use crate::header::LstHeader;
+    use crate::writer::write_lst;
+
+    /// A "5" record of stop1 at `time` in `sweep`, with the channel code `code`.
+    fn record_5(data: &mut Vec<u8>, code: u32, time: u32, sweep: u32) {
+        edge_record_5(data, code, false, time, sweep);
+    }
+
+    /// Like `record_5`, with the falling edge if `edge`.
+    fn edge_record_5(data: &mut Vec<u8>, code: u32, edge: bool, time: u32, sweep: u32) {
+        data.write_u32::<LE>((sweep << 24) | (time << 4) | (u32::from(edge) << 3) | code).unwrap();
+    }
+
+    fn parse_5_with(data: &[u8], channel_map: Vec<u8>, options: &ParseOptions) -> LstReturnU16 {
+        let channels = create_channels_u16("5", options.filter_channel_map(channel_map), &options.channel_names(),
+                                           data.len()).unwrap();
+        parse_5(data, 1000, &TimepatchBits::new("5", &options.device).unwrap(), channels, options)
+    }
 
-    let fname = "1000nm_Pulsatile_Modulation_-9000mV_to_9500mV_1_sweep_each_32s_long009.lst"; // 002
-    let start_of_data = 1568usize;  // 1565
-    let range = 80000u64;
-    let timepatch = "43";
-    let channel_map = vec![1, 0, 0, 0, 0, 1];
+    fn parse_5_data(data: &[u8]) -> LstReturnU16 {
+        parse_5_with(data, vec![1, 0, 0, 0, 0, 1], &ParseOptions::default())
+    }
+
+    #[test]
+    fn damaged_channel_codes_are_skipped() {
+        let mut data = Vec::new();
+        for idx in 0..300 {
+            if idx == 100 { record_5(&mut data, 0, 5, 1) };
+            if idx == 200 { record_5(&mut data, 7, 5, 1) };
+            record_5(&mut data, 1, idx * 3, 1);
+        }
+        let parsed = parse_5_data(&data);
+        assert_eq!(parsed.channel(0).unwrap().time.len(), 300);
+        let skipped = &parsed.stats().skipped;
+        assert_eq!(skipped.len(), 2, "{:?}", skipped);
+        assert_eq!((skipped[0].start, skipped[0].stop), (400, 404));
+        assert_eq!((skipped[1].start, skipped[1].stop), (804, 808));
+        assert!(skipped.iter().all(|range| range.reason == SkipReason::InvalidRecord));
+    }
+
+    #[test]
+    fn counter_wraps() {
+        let mut unwrapper = CounterUnwrapper::new(256);
+        let unwrapped: Vec<(u64, bool)> = [250, 255, 3, 1, 10, 200, 5].iter()
+            .map(|value| unwrapper.unwrap(*value))
+            .collect();
+        // going back by less than half the modulus isn't a wrap
+        assert_eq!(unwrapped, vec![(250, false), (255, false), (259, true), (257, false), (266, false), (456, false),
+                                   (517, true)]);
+    }
+
+    #[test]
+    fn wraps_after_long_gaps_are_missed() {
+        // 200 sweeps without events end up 56 counts lower, which looks like
+        // an out of order record
+        let mut unwrapper = CounterUnwrapper::new(256);
+        assert_eq!(unwrapper.unwrap(100), (100, false));
+        assert_eq!(unwrapper.unwrap((100 + 200) % 256), (44, false));
+        assert_eq!(unwrapper.epoch, 0);
+    }
+
+    #[test]
+    fn sweep_wraps_are_counted() {
+        // one event in each of 700 sweeps, so the 8-bit sweep counter wraps twice
+        let mut data = Vec::new();
+        for sweep in 1..701 {
+            record_5(&mut data, 1, 10, sweep % 256);
+        }
+        let parsed = parse_5_data(&data);
+        assert_eq!(parsed.stats().sweep_wraps, 2);
+        let times = &parsed.channel(0).unwrap().time;
+        assert_eq!(times.len(), 700);
+        assert!(times.windows(2).all(|pair| pair[1] == pair[0] + 1000), "{:?}", times);
+    }
+
+    #[test]
+    fn dead_time_is_applied_per_channel() {
+        let mut data = Vec::new();
+        for &(code, time) in [(1, 10), (6, 11), (2, 12), (6, 12), (1, 14), (2, 15), (2, 17), (1, 20), (1, 31)].iter() {
+            record_5(&mut data, code, time, 1);
+        }
+        let options = ParseOptions { dead_time: vec![5, 3], ..ParseOptions::default() };
+        let parsed = parse_5_with(&data, vec![1, 1, 0, 0, 0, 1], &options);
+        // 14 follows 10 by less than 5, 20 is measured from 10 since 14 was dropped,
+        // and 15 follows 12 by exactly the dead time of stop2
+        assert_eq!(parsed.channel(0).unwrap().time, vec![10, 20, 31]);
+        assert_eq!(parsed.channel(1).unwrap().time, vec![12, 15]);
+        assert_eq!(parsed.channel(5).unwrap().time, vec![11, 12]);
+        assert_eq!(parsed.stats().dead_time_dropped, vec![1, 1, 0, 0, 0, 0]);
+    }
 
-    let data_with_headers = FileBuffer::open(fname).expect("bad file name");
-    let data_size: usize = (fs::metadata(fname)?.len() - start_of_data as u64) as usize;
-    let data = &data_with_headers[start_of_data..];
-    // Open the file and convert it to a usable format
+    /// Writes a "5" file with events on stop1 and stop3, whose header marks
+    /// stop3 as inactive.
+    fn inactive_file(name: &str) -> (String, LstHeader) {
+        let header = LstHeader { start_of_data: 0, range: 1000, timepatch: "5".to_string(), device: Device::mcs6a(),
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let events: Vec<Event> = (0..6u64)
+            .map(|idx| Event { channel: 2 * (idx % 2) as usize, time: 10 * (idx + 1), sweep: 1, ..Event::default() })
+            .collect();
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        write_lst(&path, &header, &events).unwrap();
+        let text = fs::read(&path).unwrap();
+        let (active, inactive) = (b"[CHN3]\r\nactive=1", b"[CHN3]\r\nactive=0");
+        let pos = text.windows(active.len()).position(|window| window == active).unwrap();
+        let patched = [&text[..pos], &inactive[..], &text[pos + active.len()..]].concat();
+        fs::write(&path, patched).unwrap();
+        let header = LstHeader::read(&path).unwrap();
+        assert_eq!(header.channel_map, vec![1, 1, 0, 1, 1, 1]);
+        (path, header)
+    }
+
+    fn parse_inactive(name: &str, inactive_events: InactivePolicy) -> Result<LstReturnU16, Error> {
+        let (path, header) = inactive_file(name);
+        let options = ParseOptions { inactive_events, ..ParseOptions::default() };
+        analyze_lst_u16(&path, header.start_of_data, header.range, &header.timepatch, header.channel_map, &options)
+    }
+
+    #[test]
+    fn inactive_events_fail_in_strict_mode() {
+        let err = parse_inactive("libps_inactive_strict.lst", InactivePolicy::Strict).unwrap_err();
+        assert_eq!(err.to_string(), "Events on inactive channels: stop3 (3 events)");
+    }
+
+    #[test]
+    fn inactive_events_are_kept_in_lenient_mode() {
+        let parsed = parse_inactive("libps_inactive_lenient.lst", InactivePolicy::Lenient).unwrap();
+        assert_eq!(parsed.channel(0).unwrap().time, vec![10, 30, 50]);
+        assert_eq!(parsed.channel(2).unwrap().time, vec![20, 40, 60]);
+        assert_eq!(parsed.stats().inactive, vec![0, 0, 3, 0, 0, 0]);
+    }
 
-    let chan_map = create_channel_vec_seq(data_size, channel_map.to_vec());
+    #[test]
+    fn inactive_events_are_dropped_by_default() {
+        let parsed = parse_inactive("libps_inactive_drop.lst", InactivePolicy::default()).unwrap();
+        assert_eq!(parsed.channel(0).unwrap().time, vec![10, 30, 50]);
+        assert!(parsed.channel(2).is_none());
+        assert_eq!(parsed.stats().inactive, vec![0, 0, 3, 0, 0, 0]);
+    }
 
-    let tp_enum = TimepatchBits::new(timepatch);
-    let processed_data = parse_with_sweep_8bytes(data, range, &tp_enum, chan_map);
-    processed_data
+    #[test]
+    fn excluded_edges_and_channels_are_dropped() {
+        let mut data = Vec::new();
+        for idx in 0..12 {
+            // alternating edges on stop1, stop2 and the start
+            edge_record_5(&mut data, [1, 2, 6][idx as usize % 3], idx % 2 == 1, 10 * (idx + 1), 1);
+        }
+        let options = ParseOptions {
+            channel_filter: vec![ChannelFilter::Rising, ChannelFilter::Off, ChannelFilter::Falling],
+            ..ParseOptions::default()
+        };
+        let parsed = parse_5_with(&data, vec![1, 1, 0, 0, 0, 1], &options);
+        let stop1 = parsed.channel(0).unwrap();
+        assert_eq!(stop1.time, vec![10, 70]);
+        assert!(stop1.edge.iter().all(|edge| !edge));
+        assert!(parsed.channel(1).is_none());
+        // the start has no filter entry, so both of its edges are kept
+        assert_eq!(parsed.channel(5).unwrap().time, vec![30, 60, 90, 120]);
+        assert_eq!(parsed.channel(5).unwrap().edge, vec![false, true, false, true]);
+        assert_eq!(parsed.stats().filtered, vec![2, 4, 0, 0, 0, 0]);
+    }
 
+    #[test]
+    fn starts_are_counted() {
+        let mut data = Vec::new();
+        for &(code, time) in [(1, 5), (2, 8), (1, 9), (6, 0), (1, 100), (6, 0), (1, 50), (2, 70), (6, 0), (2, 3)]
+            .iter() {
+            record_5(&mut data, code, time, 1);
+        }
+        let options = ParseOptions { start_clock: StartClock::Period(1000), ..ParseOptions::default() };
+        let parsed = parse_5_with(&data, vec![1, 1, 0, 0, 0, 1], &options);
+        // the stops before the first start keep their relative times
+        assert_eq!(parsed.channel(0).unwrap().time, vec![5, 9, 100, 1050]);
+        assert_eq!(parsed.channel(1).unwrap().time, vec![8, 1070, 2003]);
+        assert_eq!(parsed.channel(5).unwrap().time, vec![0, 1000, 2000]);
+        assert_eq!(parsed.stats().starts, 3);
+        assert_eq!(parsed.stats().stops_before_start, vec![2, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sweeps_and_sweep_relative_times() {
+        let header = LstHeader { start_of_data: 0, range: 1000, timepatch: "5".to_string(), device: Device::mcs6a(),
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        // two events in every third sweep up to sweep 600, so the 8-bit counter wraps twice
+        let events: Vec<Event> = (0..400u64)
+            .map(|idx| {
+                let sweep = 1 + 3 * (idx / 2);
+                Event { time: 1000 * (sweep - 1) + 100 * (idx % 2) + idx % 7, sweep, ..Event::default() }
+            })
+            .collect();
+        let path = env::temp_dir().join("libps_parsing_sweeps.lst").to_str().unwrap().to_string();
+        let written = write_lst(&path, &header, &events).unwrap();
+        let parse = |time_mode| {
+            let options = ParseOptions { sweep_output: true, time_mode, ..ParseOptions::default() };
+            analyze_lst_u16(&path, written.start_of_data, 1000, "5", written.channel_map.clone(), &options).unwrap()
+        };
+        let absolute = parse(TimeMode::Absolute);
+        let stop1 = absolute.channel(0).unwrap();
+        assert_eq!(stop1.sweep, events.iter().map(|event| event.sweep).collect::<Vec<_>>());
+        assert_eq!(stop1.time, events.iter().map(|event| event.time).collect::<Vec<_>>());
+        assert_eq!(absolute.stats().sweep_wraps, 2);
+        let relative = parse(TimeMode::SweepRelative);
+        let stop1 = relative.channel(0).unwrap();
+        assert_eq!(stop1.sweep, events.iter().map(|event| event.sweep).collect::<Vec<_>>());
+        assert_eq!(stop1.time, events.iter().map(|event| event.time % 1000).collect::<Vec<_>>());
+        // without sweep_output the sweeps aren't kept
+        let options = ParseOptions::default();
+        let parsed = analyze_lst_u16(&path, written.start_of_data, 1000, "5", written.channel_map, &options).unwrap();
+        assert!(parsed.channel(0).unwrap().sweep.is_empty());
+    }
 }
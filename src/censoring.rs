@@ -0,0 +1,192 @@
+use rayon::prelude::*;
+use pyo3::prelude::*;
+use pyo3::{IntoPyObject, PyObject, PyDict};
+
+use crate::binary_parsing::*;
+use crate::laser::*;
+
+/// The delays after a laser pulse, in bins, in which fluorescence photons are
+/// expected. A gate with `start > stop` wraps around the end of the laser period.
+#[derive(Debug, Clone, Copy)]
+pub struct CensorGate {
+    pub start: f64,
+    pub stop: f64,
+}
+
+impl CensorGate {
+    pub fn new(start: f64, stop: f64) -> Self {
+        CensorGate { start, stop }
+    }
+
+    /// Chooses the gate from the histogram of photon delays after the pulse.
+    /// Dark counts and afterpulses form a flat background, estimated by the median
+    /// of the histogram, and the gate is the contiguous run of delays around the peak
+    /// that rises above that background by more than `sigmas` standard deviations.
+    /// None if no delay rises above the background, or if the run covers the
+    /// whole period, which leaves nothing to censor.
+    pub fn auto(delays: &[f64], period: f64, sigmas: f64) -> Option<Self> {
+        let bins = period.ceil().max(1.0) as usize;
+        let mut hist = vec![0u64; bins];
+        for delay in delays.iter().filter(|delay| delay.is_finite() && **delay >= 0.0) {
+            hist[((*delay % period) as usize).min(bins - 1)] += 1;
+        }
+        let (peak, peak_count) = hist.iter().enumerate()
+            .max_by_key(|(_, count)| **count)
+            .map(|(idx, count)| (idx, *count))?;
+        let mut sorted = hist.clone();
+        sorted.sort_unstable();
+        let background = sorted[bins / 2] as f64;
+        let threshold = background + sigmas * background.sqrt();
+        if (peak_count as f64) <= threshold { return None };
+
+        let mut first = peak;
+        let mut last = peak;
+        for step in 1..bins {
+            let idx = (peak + bins - step) % bins;
+            if (hist[idx] as f64) <= threshold { break };
+            first = idx;
+        }
+        for step in 1..bins {
+            let idx = (peak + step) % bins;
+            if (hist[idx] as f64) <= threshold || idx == first { break };
+            last = idx;
+        }
+        // a gate from a bin to itself would be empty, not the whole period
+        if (last + 1) % bins == first { return None };
+        Some(CensorGate::new(first as f64, ((last + 1) % bins) as f64))
+    }
+
+    pub fn contains(&self, delay: f64) -> bool {
+        if self.start <= self.stop {
+            delay >= self.start && delay < self.stop
+        } else {
+            delay >= self.start || delay < self.stop
+        }
+    }
+}
+
+/// Summary of a censoring pass over a single channel.
+#[derive(Debug, Clone, Copy)]
+pub struct CensorStats {
+    pub gate: CensorGate,
+    pub kept: usize,
+    pub removed: usize,
+}
+
+/// Marks the photons whose delay after the preceding laser pulse falls inside the
+/// gate. When no gate is given it's chosen automatically from the photons.
+/// Photons that arrived before the first laser pulse are removed.
+pub fn censor_mask(times: &[u64], laser: &LaserPulses, gate: Option<CensorGate>)
+    -> Option<(Vec<bool>, CensorStats)> {
+    let (_, delays) = laser.locate_all(times);
+    let gate = match gate {
+        Some(gate) => gate,
+        None => CensorGate::auto(&delays, laser.mean_period()?, 3.0)?,
+    };
+    let mask: Vec<bool> = delays.par_iter().map(|delay| delay.is_finite() && gate.contains(*delay)).collect();
+    let kept = mask.iter().filter(|keep| **keep).count();
+    let stats = CensorStats { gate, kept, removed: mask.len() - kept };
+    Some((mask, stats))
+}
+
+/// Removes the photons of a PMT channel that arrived outside the fluorescence
+/// window following each laser pulse. Meant to run on the parsed channel data
+/// before it's binned into an image.
+pub fn censor_channel(data: &ParsedDataU16, laser: &LaserPulses, gate: Option<CensorGate>)
+    -> Option<(ParsedDataU16, CensorStats)> {
+    let (mask, stats) = censor_mask(&data.time, laser, gate)?;
+    Some((data.select(&mask), stats))
+}
+
+/// Like `censor_channel`, for the data of timepatches with 8-bit tags.
+pub fn censor_channel_u8(data: &ParsedDataU8, laser: &LaserPulses, gate: Option<CensorGate>)
+    -> Option<(ParsedDataU8, CensorStats)> {
+    let (mask, stats) = censor_mask(&data.time, laser, gate)?;
+    Some((data.select(&mask), stats))
+}
+
+impl IntoPyObject for CensorStats {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        dict.set_item("gate", (self.gate.start, self.gate.stop)).expect("Gate insertion error.");
+        dict.set_item("kept", self.kept).expect("Kept insertion error.");
+        dict.set_item("removed", self.removed).expect("Removed insertion error.");
+        dict.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: u64 = 100;
+
+    /// Delays of a flat background of 20 photons per bin, with 100 more
+    /// photons in each bin from `first` on, for `width` bins.
+    fn delays(first: u64, width: u64) -> Vec<f64> {
+        let background = (0..20 * PERIOD).map(|idx| (idx % PERIOD) as f64 + 0.5);
+        let peak = (0..100 * width).map(|idx| ((first + idx % width) % PERIOD) as f64 + 0.5);
+        background.chain(peak).collect()
+    }
+
+    #[test]
+    fn auto_gate_around_the_peak() {
+        let gate = CensorGate::auto(&delays(20, 10), PERIOD as f64, 3.0).unwrap();
+        assert_eq!((gate.start, gate.stop), (20.0, 30.0));
+        assert!(gate.contains(20.0) && gate.contains(29.9));
+        assert!(!gate.contains(19.9) && !gate.contains(30.0));
+    }
+
+    #[test]
+    fn auto_gate_wraps_around_the_period() {
+        let gate = CensorGate::auto(&delays(95, 10), PERIOD as f64, 3.0).unwrap();
+        assert_eq!((gate.start, gate.stop), (95.0, 5.0));
+        assert!(gate.contains(97.0) && gate.contains(2.0));
+        assert!(!gate.contains(50.0) && !gate.contains(5.0));
+    }
+
+    #[test]
+    fn no_auto_gate_without_a_background() {
+        // a flat histogram has no peak, and a negative threshold lets the run
+        // cover the whole period
+        assert!(CensorGate::auto(&delays(0, 0), PERIOD as f64, 3.0).is_none());
+        assert!(CensorGate::auto(&delays(20, 10), PERIOD as f64, -10.0).is_none());
+        assert!(CensorGate::auto(&[0.5, 0.2], 1.0, 3.0).is_none());
+    }
+
+    #[test]
+    fn photons_outside_the_gate_are_censored() {
+        let laser = LaserPulses::from_channel(&(1..1000).map(|pulse| pulse * PERIOD).collect::<Vec<_>>());
+        // one photon right before the first pulse, then photons at delays 0..100
+        let times: Vec<u64> = (PERIOD - 1..90_000).step_by(7).collect();
+        let gate = CensorGate::new(20.0, 30.0);
+        let (mask, stats) = censor_mask(&times, &laser, Some(gate)).unwrap();
+        let expected: Vec<bool> = times.iter()
+            .map(|time| *time >= PERIOD && (20..30).contains(&(time % PERIOD)))
+            .collect();
+        assert!(!mask[0]);
+        assert_eq!(mask, expected);
+        assert_eq!((stats.kept, stats.removed), (expected.iter().filter(|keep| **keep).count(),
+                                                 expected.iter().filter(|keep| !**keep).count()));
+
+        let data = ParsedDataU8::new(vec![], vec![], vec![false; times.len()], times.clone());
+        let (censored, _) = censor_channel_u8(&data, &laser, Some(gate)).unwrap();
+        assert_eq!(censored.time.len(), stats.kept);
+        assert_eq!(censored.edge.len(), stats.kept);
+        assert!(censored.lost.is_empty());
+        assert!(censored.time.iter().all(|time| (20..30).contains(&(time % PERIOD))));
+    }
+
+    #[test]
+    fn automatic_censoring() {
+        let laser = LaserPulses::from_channel(&(1..1000).map(|pulse| pulse * PERIOD).collect::<Vec<_>>());
+        // a photon at every other delay, and ten more at delays 40 to 44 after every pulse
+        let mut times: Vec<u64> = (PERIOD..99_900).step_by(2).collect();
+        times.extend((1..999).flat_map(|pulse| (0..10).map(move |idx| pulse * PERIOD + 40 + idx / 2)));
+        times.sort_unstable();
+        let (mask, stats) = censor_mask(&times, &laser, None).unwrap();
+        assert_eq!((stats.gate.start, stats.gate.stop), (40.0, 45.0));
+        assert_eq!(stats.kept + stats.removed, times.len());
+        assert!(times.iter().zip(&mask).all(|(time, keep)| *keep == (40..45).contains(&(time % PERIOD))));
+    }
+}
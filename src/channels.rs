@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::slice;
+use std::vec;
+
+use failure::{Error, format_err};
+
+use crate::device::Device;
+
+/// Keys of the Python output that can't be used as channel names
+const RESERVED_NAMES: [&str; 3] = ["stats", "bin_width_ps", "time_unit"];
+
+/// The data of a single input, with its id, the index of its channel code, and
+/// the name it's addressed by.
+#[derive(Debug, Clone)]
+pub struct Channel<T> {
+    pub id: usize,
+    pub name: String,
+    pub data: T,
+}
+
+/// The parsed data of the active inputs of a device, ordered by id. Each
+/// channel can be looked up by its id or by its name.
+#[derive(Debug, Clone)]
+pub struct Channels<T> {
+    entries: Vec<Channel<T>>,
+    /// Position of each id in `entries`, None for inactive inputs
+    slots: Vec<Option<usize>>,
+    /// Name of each id, active or not
+    id_names: Vec<String>,
+}
+
+impl<T: Clone> Channels<T> {
+    /// A copy of `prototype` for each input with a 1 in `active_channels`.
+    /// `names` holds the name of every input, active or not, and inputs missing
+    /// from `active_channels` are inactive.
+    pub fn from_active(active_channels: &[u8], names: &[String], prototype: T) -> Self {
+        let num_of_ids = active_channels.len().max(names.len());
+        let id_names: Vec<String> = (0..num_of_ids)
+            .map(|id| names.get(id).cloned().unwrap_or_else(|| format!("input{}", id + 1)))
+            .collect();
+        let mut entries = Vec::with_capacity(num_of_ids);
+        let mut slots = Vec::with_capacity(num_of_ids);
+        for (id, name) in id_names.iter().enumerate() {
+            if active_channels.get(id) == Some(&1) {
+                slots.push(Some(entries.len()));
+                entries.push(Channel { id, name: name.clone(), data: prototype.clone() });
+            } else {
+                slots.push(None);
+            }
+        }
+        Channels { entries, slots, id_names }
+    }
+}
+
+impl<T> Channels<T> {
+    /// Data of the input with id `id`, or None if it isn't active.
+    pub fn get(&self, id: usize) -> Option<&T> {
+        let slot = self.slots.get(id).and_then(|slot| *slot)?;
+        Some(&self.entries[slot].data)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut T> {
+        let slot = self.slots.get(id).and_then(|slot| *slot)?;
+        Some(&mut self.entries[slot].data)
+    }
+
+    /// Data of the channel called `name`.
+    pub fn by_name(&self, name: &str) -> Option<&T> {
+        self.entries.iter().find(|channel| channel.name == name).map(|channel| &channel.data)
+    }
+
+    pub fn is_active(&self, id: usize) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Adds the inactive input `id` with `data`, keeping the channels ordered
+    /// by id. Does nothing if the input is already active or has no id.
+    pub fn activate(&mut self, id: usize, data: T) {
+        if id >= self.slots.len() || self.is_active(id) { return };
+        let pos = self.entries.iter().position(|channel| channel.id > id).unwrap_or(self.entries.len());
+        self.entries.insert(pos, Channel { id, name: self.id_names[id].clone(), data });
+        for (slot, channel) in self.entries.iter().enumerate() {
+            self.slots[channel.id] = Some(slot);
+        }
+    }
+
+    /// Number of active channels
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of inputs, active or not
+    pub fn num_of_ids(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.entries.iter().map(|channel| channel.id).collect()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|channel| channel.name.as_str()).collect()
+    }
+
+    /// Names of all inputs, active or not, in the order of their ids.
+    pub fn id_names(&self) -> &[String] {
+        &self.id_names
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Channel<T>> {
+        self.entries.iter()
+    }
+}
+
+impl<T> IntoIterator for Channels<T> {
+    type Item = Channel<T>;
+    type IntoIter = vec::IntoIter<Channel<T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+/// Names of the inputs of `device`, with the ones in `roles` renamed after the
+/// role they play in the experiment, e.g. "stop1" to "pmt1" or "start" to
+/// "laser". Every key has to be an input of the device, and the resulting
+/// names have to be unique.
+pub fn role_names(device: &Device, roles: &HashMap<String, String>) -> Result<Vec<String>, Error> {
+    if let Some(input) = roles.keys().find(|input| device.channel_index(input).is_none()) {
+        return Err(format_err!("{} has no input called {}", device.name, input));
+    }
+    let names: Vec<String> = device.channel_names.iter()
+        .map(|input| roles.get(input).unwrap_or(input).clone())
+        .collect();
+    for (idx, name) in names.iter().enumerate() {
+        if RESERVED_NAMES.contains(&name.as_str()) {
+            return Err(format_err!("{} can't be used as a channel name", name));
+        }
+        if names[..idx].contains(name) {
+            return Err(format_err!("Channel name {} is used twice", name));
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn only_active_inputs_are_kept() {
+        let channels = Channels::from_active(&[1, 0, 1], &names(&["stop1", "stop2", "stop3", "start"]), 7);
+        assert_eq!((channels.len(), channels.num_of_ids()), (2, 4));
+        assert_eq!(channels.ids(), vec![0, 2]);
+        assert_eq!(channels.names(), vec!["stop1", "stop3"]);
+        assert_eq!(channels.id_names(), &names(&["stop1", "stop2", "stop3", "start"])[..]);
+        assert_eq!((channels.get(2), channels.get(1), channels.get(3), channels.get(9)), (Some(&7), None, None, None));
+        assert_eq!((channels.by_name("stop3"), channels.by_name("stop2")), (Some(&7), None));
+        // inputs without a name are numbered
+        let unnamed = Channels::from_active(&[0, 1], &[], 0);
+        assert_eq!(unnamed.names(), vec!["input2"]);
+    }
+
+    #[test]
+    fn activated_inputs_keep_the_order() {
+        let mut channels = Channels::from_active(&[0, 1, 0, 1], &names(&["a", "b", "c", "d"]), 0);
+        channels.activate(2, 3);
+        channels.activate(0, 1);
+        // already active, and out of range
+        channels.activate(1, 9);
+        channels.activate(4, 9);
+        assert_eq!(channels.ids(), vec![0, 1, 2, 3]);
+        *channels.get_mut(3).unwrap() = 4;
+        let data: Vec<(usize, String, i32)> = channels.into_iter().map(|chan| (chan.id, chan.name, chan.data)).collect();
+        assert_eq!(data, vec![(0, "a".to_string(), 1), (1, "b".to_string(), 0), (2, "c".to_string(), 3),
+                              (3, "d".to_string(), 4)]);
+    }
+
+    #[test]
+    fn roles_rename_inputs() {
+        let device = Device::mcs6a();
+        let mut roles = HashMap::new();
+        roles.insert("stop1".to_string(), "pmt1".to_string());
+        roles.insert("start".to_string(), "laser".to_string());
+        assert_eq!(role_names(&device, &roles).unwrap(), names(&["pmt1", "stop2", "stop3", "stop4", "stop5", "laser"]));
+
+        roles.insert("stop9".to_string(), "pmt9".to_string());
+        assert_eq!(role_names(&device, &roles).unwrap_err().to_string(), "MCS6A has no input called stop9");
+        roles.remove("stop9");
+        roles.insert("stop2".to_string(), "pmt1".to_string());
+        assert_eq!(role_names(&device, &roles).unwrap_err().to_string(), "Channel name pmt1 is used twice");
+        roles.insert("stop2".to_string(), "stats".to_string());
+        assert_eq!(role_names(&device, &roles).unwrap_err().to_string(), "stats can't be used as a channel name");
+        // an input can't take the name of another one either
+        let mut roles = HashMap::new();
+        roles.insert("stop1".to_string(), "stop2".to_string());
+        assert!(role_names(&device, &roles).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use failure::{Error, format_err};
+use rayon::prelude::*;
+use pyo3::prelude::*;
+use pyo3::{IntoPyObject, PyObject, PyDict};
+
+/// Times of a channel sorted in ascending order, each paired with its index in
+/// the original vector.
+fn sorted_with_index(times: &[u64]) -> Vec<(u64, usize)> {
+    let mut sorted: Vec<(u64, usize)> = times.iter().cloned().zip(0..).collect();
+    sorted.par_sort_unstable();
+    sorted
+}
+
+/// Duration, in bins, in which all channels were recording simultaneously.
+fn overlap(channels: &[Vec<(u64, usize)>]) -> u64 {
+    let start = channels.iter().filter_map(|chan| chan.first()).map(|first| first.0).max();
+    let stop = channels.iter().filter_map(|chan| chan.last()).map(|last| last.0).min();
+    match (start, stop) {
+        (Some(start), Some(stop)) if stop > start => stop - start,
+        _ => 0,
+    }
+}
+
+/// Events detected on several channels within a coincidence window.
+/// `indices` holds, for each coincidence, the index of the matching event in each
+/// of the channels, in the order the channels were given.
+#[derive(Debug, Clone)]
+pub struct Coincidences {
+    pub indices: Vec<Vec<usize>>,
+    pub count: usize,
+    /// Number of coincidences expected from uncorrelated events with the same rates
+    pub accidentals: f64,
+}
+
+/// Finds the events of `b` that are within `window` bins of an event of `a`.
+/// Every pair that matches is reported, so an event may appear in several pairs.
+pub fn coincidences(a: &[u64], b: &[u64], window: u64) -> Coincidences {
+    let sorted_a = sorted_with_index(a);
+    let sorted_b = sorted_with_index(b);
+    let mut indices = Vec::new();
+    let mut first_b = 0;
+    for (time_a, idx_a) in sorted_a.iter() {
+        while first_b < sorted_b.len() && sorted_b[first_b].0 + window < *time_a {
+            first_b += 1;
+        }
+        for (time_b, idx_b) in sorted_b[first_b..].iter() {
+            if *time_b > time_a + window { break };
+            indices.push(vec![*idx_a, *idx_b]);
+        }
+    }
+    let span = overlap(&[sorted_a, sorted_b]);
+    let accidentals = if span > 0 {
+        a.len() as f64 * b.len() as f64 * (2 * window + 1) as f64 / span as f64
+    } else { 0.0 };
+    Coincidences { count: indices.len(), indices, accidentals }
+}
+
+/// Finds the events of the first channel for which every other channel has an event
+/// within `window` bins. The closest event of each of the other channels is reported.
+/// Fails if fewer than two channels are given.
+pub fn coincidences_multi(channels: &[Vec<u64>], window: u64) -> Result<Coincidences, Error> {
+    if channels.len() < 2 {
+        return Err(format_err!("coincidences need at least two channels"));
+    }
+    let sorted: Vec<Vec<(u64, usize)>> = channels.par_iter().map(|chan| sorted_with_index(chan)).collect();
+    let indices: Vec<Vec<usize>> = sorted[0]
+        .par_iter()
+        .filter_map(|(anchor, anchor_idx)| {
+            let mut matched = vec![*anchor_idx];
+            for other in sorted[1..].iter() {
+                let pos = match other.binary_search(&(*anchor, 0)) {
+                    Ok(pos) | Err(pos) => pos,
+                };
+                let closest = [pos.checked_sub(1), Some(pos)]
+                    .iter()
+                    .filter_map(|pos| pos.and_then(|pos| other.get(pos)))
+                    .min_by_key(|(time, _)| time.abs_diff(*anchor))
+                    .cloned();
+                match closest {
+                    Some((time, idx)) if time + window >= *anchor && time <= anchor + window => matched.push(idx),
+                    _ => return None,
+                }
+            }
+            Some(matched)
+        })
+        .collect();
+    let span = overlap(&sorted);
+    let accidentals = if span > 0 {
+        let coincidence_prob: f64 = channels[1..].iter()
+            .map(|chan| (chan.len() as f64 * (2 * window + 1) as f64 / span as f64).min(1.0))
+            .product();
+        channels[0].len() as f64 * coincidence_prob
+    } else { 0.0 };
+    Ok(Coincidences { count: indices.len(), indices, accidentals })
+}
+
+/// Histogram of the delays between the events of two channels, g(2)(τ).
+/// `lags` holds the start of each delay bin, `b - a`, in bins of the multiscaler.
+#[derive(Debug, Clone)]
+pub struct G2Histogram {
+    pub lags: Vec<i64>,
+    pub counts: Vec<u64>,
+    /// The counts divided by the number expected from uncorrelated events
+    pub g2: Vec<f64>,
+    pub bin_width: u64,
+}
+
+/// Computes g(2)(τ) between two channels for delays in `[-max_lag, max_lag)`.
+/// Both channels are swept once with a sliding window, so the run time grows
+/// linearly with the number of events for a fixed `max_lag`. If `2 * max_lag`
+/// isn't a multiple of `bin_width`, the last bin is narrower, and it's
+/// normalized by its own width.
+pub fn g2_histogram(a: &[u64], b: &[u64], bin_width: u64, max_lag: u64) -> G2Histogram {
+    let bin_width = bin_width.max(1);
+    let num_of_bins = (2 * max_lag).div_ceil(bin_width) as usize;
+    let mut counts = vec![0u64; num_of_bins];
+    let mut sorted_a = a.to_vec();
+    let mut sorted_b = b.to_vec();
+    sorted_a.par_sort_unstable();
+    sorted_b.par_sort_unstable();
+
+    let mut first_b = 0;
+    for time_a in sorted_a.iter() {
+        while first_b < sorted_b.len() && sorted_b[first_b] + max_lag < *time_a {
+            first_b += 1;
+        }
+        for time_b in sorted_b[first_b..].iter() {
+            if *time_b >= time_a + max_lag { break };
+            let shifted = time_b + max_lag - time_a;
+            counts[(shifted / bin_width) as usize] += 1;
+        }
+    }
+
+    let span = match (sorted_a.first(), sorted_a.last(), sorted_b.first(), sorted_b.last()) {
+        (Some(a0), Some(a1), Some(b0), Some(b1)) => a1.min(b1).saturating_sub(*a0.max(b0)),
+        _ => 0,
+    };
+    // expected counts of uncorrelated events per bin of delay
+    let rate = a.len() as f64 * b.len() as f64 / span.max(1) as f64;
+    let g2 = counts.iter().enumerate()
+        .map(|(idx, count)| {
+            let width = bin_width.min(2 * max_lag - idx as u64 * bin_width);
+            let expected = rate * width as f64;
+            if expected > 0.0 { *count as f64 / expected } else { 0.0 }
+        })
+        .collect();
+    let lags = (0..num_of_bins).map(|idx| idx as i64 * bin_width as i64 - max_lag as i64).collect();
+    G2Histogram { lags, counts, g2, bin_width }
+}
+
+impl IntoPyObject for G2Histogram {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        dict.set_item("lags", self.lags).expect("Lags insertion error.");
+        dict.set_item("counts", self.counts).expect("Counts insertion error.");
+        dict.set_item("g2", self.g2).expect("G2 insertion error.");
+        dict.set_item("bin_width", self.bin_width).expect("Bin width insertion error.");
+        dict.into()
+    }
+}
+
+impl IntoPyObject for Coincidences {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        dict.set_item("indices", self.indices).expect("Indices insertion error.");
+        dict.set_item("count", self.count).expect("Count insertion error.");
+        dict.set_item("accidentals", self.accidentals).expect("Accidentals insertion error.");
+        dict.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_channel_coincidences() {
+        // 105 is exactly a window after 100, 206 is one bin too far from 200, and
+        // 300 matches two events of b
+        let a = vec![300, 100, 200];
+        let b = vec![298, 105, 304, 206, 500];
+        let result = coincidences(&a, &b, 5);
+        assert_eq!(result.indices, vec![vec![1, 1], vec![0, 0], vec![0, 2]]);
+        assert_eq!(result.count, 3);
+        // the channels overlap from 105 to 300, with 3 * 5 events in 11-bin windows
+        assert!((result.accidentals - 15.0 * 11.0 / 195.0).abs() < 1e-9, "{}", result.accidentals);
+        assert_eq!(coincidences(&a, &b, 4).indices, vec![vec![0, 0], vec![0, 2]]);
+        assert_eq!(coincidences(&a, &[], 5).count, 0);
+    }
+
+    #[test]
+    fn multi_channel_coincidences() {
+        let channels = vec![vec![100, 200, 300], vec![302, 105, 150], vec![99, 298]];
+        let result = coincidences_multi(&channels, 5).unwrap();
+        assert_eq!(result.indices, vec![vec![0, 1, 0], vec![2, 0, 1]]);
+        assert_eq!(result.count, 2);
+        let single = coincidences_multi(&channels[..1], 5).unwrap_err();
+        assert_eq!(single.to_string(), "coincidences need at least two channels");
+        assert!(coincidences_multi(&[], 5).is_err());
+    }
+
+    /// Times of a Poisson process with a mean interval of `interval` bins.
+    fn poisson(events: usize, interval: f64, seed: u64) -> Vec<u64> {
+        let mut state = seed;
+        let mut time = 0.0;
+        (0..events)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let uniform = ((state >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+                time += -interval * uniform.ln();
+                time as u64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn g2_of_uncorrelated_streams_is_one() {
+        let a = poisson(20_000, 100.0, 0x2545_f491_4f6c_dd1d);
+        let b = poisson(20_000, 100.0, 0x9e37_79b9_7f4a_7c15);
+        // 1000 bins of delay don't fill the last 30-bin bin
+        let hist = g2_histogram(&a, &b, 30, 500);
+        assert_eq!(hist.g2.len(), 34);
+        assert_eq!((hist.lags[0], hist.lags[33]), (-500, 490));
+        for (lag, g2) in hist.lags.iter().zip(hist.g2.iter()) {
+            assert!((g2 - 1.0).abs() < 0.1, "g2 at {} is {}", lag, g2);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+/// Layout of the inputs of a multiscaler. Every record starts with a channel
+/// field holding the input's code, 1 for the first input, followed by the edge
+/// bit. The timepatch describes the fields after these.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub name: String,
+    /// Names of the inputs, in the order of their channel codes. The last input
+    /// is the start.
+    pub channel_names: Vec<String>,
+    /// Width of the channel field, in bits
+    pub channel_bits: u8,
+}
+
+impl Device {
+    pub fn new(name: &str, channel_names: &[&str], channel_bits: u8) -> Self {
+        Device {
+            name: name.to_string(),
+            channel_names: channel_names.iter().map(|name| name.to_string()).collect(),
+            channel_bits,
+        }
+    }
+
+    /// The MCS6A, with five stops, a start and a 3-bit channel field.
+    pub fn mcs6a() -> Self {
+        Device::new("MCS6A", &["stop1", "stop2", "stop3", "stop4", "stop5", "start"], 3)
+    }
+
+    /// The MCS8A, with eight stops, a start and a 4-bit channel field.
+    pub fn mcs8a() -> Self {
+        Device::new("MCS8A", &["stop1", "stop2", "stop3", "stop4", "stop5", "stop6", "stop7",
+                               "stop8", "start"], 4)
+    }
+
+    /// The device called `name`, "MCS6A" or "MCS8A", in any case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "MCS6A" => Some(Device::mcs6a()),
+            "MCS8A" => Some(Device::mcs8a()),
+            _ => None,
+        }
+    }
+
+    /// The device that wrote a list file, from the section names of its header.
+    /// Files of unknown devices are read as MCS6A files.
+    pub fn from_header(header: &str) -> Self {
+        if header.contains("[MCS8A") { Device::mcs8a() } else { Device::mcs6a() }
+    }
+
+    pub fn num_of_channels(&self) -> usize {
+        self.channel_names.len()
+    }
+
+    /// Index of the start input
+    pub fn start_channel(&self) -> usize {
+        self.num_of_channels() - 1
+    }
+
+    /// Index of the input called `name`, e.g. "stop2".
+    pub fn channel_index(&self, name: &str) -> Option<usize> {
+        self.channel_names.iter().position(|chan| chan == name)
+    }
+
+    /// Index of the input whose code is in the lowest bits of `line`, or None if
+    /// the code doesn't belong to an input.
+    pub fn decode_channel(&self, line: u64) -> Option<usize> {
+        let code = (line & ((1u64 << self.channel_bits) - 1)) as usize;
+        code.checked_sub(1).filter(|idx| *idx < self.num_of_channels())
+    }
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Device::mcs6a()
+    }
+}
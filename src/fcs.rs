@@ -0,0 +1,204 @@
+use rayon::prelude::*;
+use pyo3::prelude::*;
+use pyo3::{IntoPyObject, PyObject, PyDict};
+
+/// Multi-tau correlation curve of one or two photon streams.
+/// `g` is the normalised fluctuation correlation, G(τ) = g(2)(τ) - 1, and `error`
+/// is its standard error estimated from independent segments of the acquisition.
+#[derive(Debug, Clone)]
+pub struct MultiTau {
+    /// Lags in bins of the multiscaler
+    pub lags: Vec<u64>,
+    pub g: Vec<f64>,
+    pub error: Vec<f64>,
+}
+
+/// Parameters of the multi-tau lag scheme. The first level has `2 * lags_per_level`
+/// lags spaced by `base` bins, and every following level doubles the spacing and
+/// adds `lags_per_level` lags.
+#[derive(Debug, Clone, Copy)]
+pub struct MultiTauParams {
+    pub base: u64,
+    pub levels: usize,
+    pub lags_per_level: usize,
+    /// Number of segments used for the error estimate
+    pub segments: usize,
+}
+
+/// Photon times in units of the current lag spacing, with the number of photons
+/// that share each time.
+type Weighted = Vec<(u64, f64)>;
+
+fn merge_equal(times: impl Iterator<Item = (u64, f64)>) -> Weighted {
+    let mut merged: Weighted = Vec::new();
+    for (time, weight) in times {
+        match merged.last_mut() {
+            Some(last) if last.0 == time => last.1 += weight,
+            _ => merged.push((time, weight)),
+        }
+    }
+    merged
+}
+
+/// Sum of `w_a * w_b` over all pairs where `t_b - t_a == lag`.
+fn matches(a: &Weighted, b: &Weighted, lag: u64) -> f64 {
+    let (mut idx_a, mut idx_b) = (0, 0);
+    let mut sum = 0.0;
+    while idx_a < a.len() && idx_b < b.len() {
+        let shifted = a[idx_a].0 + lag;
+        if shifted == b[idx_b].0 {
+            sum += a[idx_a].1 * b[idx_b].1;
+            idx_a += 1;
+            idx_b += 1;
+        } else if shifted < b[idx_b].0 {
+            idx_a += 1;
+        } else {
+            idx_b += 1;
+        }
+    }
+    sum
+}
+
+/// Number of elements of `sorted` that are smaller than `limit`.
+fn count_below(sorted: &[u64], limit: u64) -> usize {
+    match sorted.binary_search(&limit) {
+        Ok(mut idx) => {
+            while idx > 0 && sorted[idx - 1] == limit { idx -= 1 };
+            idx
+        },
+        Err(idx) => idx,
+    }
+}
+
+/// Lags of every level, as (level, lag in units of that level's spacing).
+fn lag_scheme(params: &MultiTauParams) -> Vec<(usize, u64)> {
+    let per_level = params.lags_per_level.max(1) as u64;
+    let mut lags: Vec<(usize, u64)> = (1..2 * per_level).map(|lag| (0, lag)).collect();
+    for level in 1..params.levels {
+        lags.extend((per_level..2 * per_level).map(|lag| (level, lag)));
+    }
+    lags
+}
+
+/// Correlates the photons of `a` and `b` that fall in `[start, stop)`.
+fn correlate(a: &[u64], b: &[u64], start: u64, stop: u64, params: &MultiTauParams) -> Vec<f64> {
+    let scheme = lag_scheme(params);
+    let in_range = |times: &[u64]| -> Vec<u64> {
+        times.iter().cloned().filter(|time| *time >= start && *time < stop).collect()
+    };
+    let (times_a, times_b) = (in_range(a), in_range(b));
+    let duration = (stop - start) as f64;
+    let base = params.base.max(1);
+    let mut coarse_a = merge_equal(times_a.iter().map(|time| ((time - start) / base, 1.0)));
+    let mut coarse_b = merge_equal(times_b.iter().map(|time| ((time - start) / base, 1.0)));
+
+    let mut result = vec![0.0; scheme.len()];
+    for level in 0..params.levels.max(1) {
+        let spacing = base << level;
+        let level_lags: Vec<(usize, u64)> = scheme.iter().enumerate()
+            .filter(|(_, (lag_level, _))| *lag_level == level)
+            .map(|(idx, (_, lag))| (idx, *lag))
+            .collect();
+        let values: Vec<(usize, f64)> = level_lags
+            .par_iter()
+            .map(|(idx, lag)| {
+                let lag_bins = lag * spacing;
+                if lag_bins as f64 >= duration { return (*idx, 0.0) };
+                // only photons that have a partner inside the segment at this lag
+                let num_a = count_below(&times_a, stop - lag_bins) as f64;
+                let num_b = (times_b.len() - count_below(&times_b, start + lag_bins)) as f64;
+                if num_a == 0.0 || num_b == 0.0 { return (*idx, 0.0) };
+                let norm = (duration - lag_bins as f64) / (num_a * num_b * spacing as f64);
+                (*idx, matches(&coarse_a, &coarse_b, *lag) * norm - 1.0)
+            })
+            .collect();
+        for (idx, value) in values {
+            result[idx] = value;
+        }
+        coarse_a = merge_equal(coarse_a.iter().map(|(time, weight)| (time / 2, *weight)));
+        coarse_b = merge_equal(coarse_b.iter().map(|(time, weight)| (time / 2, *weight)));
+    }
+    result
+}
+
+/// Multi-tau cross-correlation of two photon streams, or autocorrelation when `b`
+/// is the same as `a`. The correlation is computed directly on the photon times:
+/// at every level equal coarse times are merged into weighted photons, so the cost
+/// depends on the number of photons and not on the length of the acquisition.
+pub fn multi_tau(a: &[u64], b: &[u64], params: &MultiTauParams) -> MultiTau {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.par_sort_unstable();
+    b.par_sort_unstable();
+    let base = params.base.max(1);
+    let lags: Vec<u64> = lag_scheme(params).iter().map(|(level, lag)| lag * (base << level)).collect();
+    let start = a.first().cloned().unwrap_or(0).min(b.first().cloned().unwrap_or(0));
+    let stop = a.last().cloned().unwrap_or(0).max(b.last().cloned().unwrap_or(0)) + 1;
+    let g = correlate(&a, &b, start, stop, params);
+
+    let num_of_segments = params.segments as u64;
+    let mut error = vec![0.0; lags.len()];
+    if num_of_segments > 1 {
+        let length = (stop - start) / num_of_segments;
+        let curves: Vec<Vec<f64>> = (0..num_of_segments)
+            .map(|seg| correlate(&a, &b, start + seg * length, start + (seg + 1) * length, params))
+            .collect();
+        let num = curves.len() as f64;
+        for (idx, err) in error.iter_mut().enumerate() {
+            let mean = curves.iter().map(|curve| curve[idx]).sum::<f64>() / num;
+            let var = curves.iter().map(|curve| (curve[idx] - mean).powi(2)).sum::<f64>() / (num - 1.0);
+            *err = (var / num).sqrt();
+        }
+    }
+    MultiTau { lags, g, error }
+}
+
+impl IntoPyObject for MultiTau {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        dict.set_item("lags", self.lags).expect("Lags insertion error.");
+        dict.set_item("g", self.g).expect("G insertion error.");
+        dict.set_item("error", self.error).expect("Error insertion error.");
+        dict.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!((value - expected).abs() < 0.01, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn periodic_autocorrelation() {
+        // a photon every 10 bins, so only lags that are multiples of the period
+        // correlate, with g(2) of 10 at the finest spacing
+        let photons: Vec<u64> = (0..10_000u64).map(|num| 500 + 10 * num).collect();
+        let params = MultiTauParams { base: 1, levels: 2, lags_per_level: 8, segments: 4 };
+        let curve = multi_tau(&photons, &photons, &params);
+        assert_eq!(curve.lags, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 26, 28, 30]);
+        for (lag, g) in curve.lags.iter().zip(&curve.g).take(15) {
+            assert_close(*g, if lag % 10 == 0 { 9.0 } else { -1.0 });
+        }
+        // one photon in every fifth bin of 2 bins at the second level
+        for (lag, g) in curve.lags.iter().zip(&curve.g).skip(15) {
+            assert_close(*g, if lag % 10 == 0 { 4.0 } else { -1.0 });
+        }
+        // every segment sees the same curve
+        assert!(curve.error.iter().all(|err| *err < 0.01), "{:?}", curve.error);
+    }
+
+    #[test]
+    fn shifted_cross_correlation() {
+        let a: Vec<u64> = (0..10_000u64).map(|num| 10 * num).collect();
+        let b: Vec<u64> = a.iter().map(|time| time + 3).collect();
+        let params = MultiTauParams { base: 1, levels: 1, lags_per_level: 8, segments: 1 };
+        let curve = multi_tau(&a, &b, &params);
+        for (lag, g) in curve.lags.iter().zip(&curve.g) {
+            assert_close(*g, if lag % 10 == 3 { 9.0 } else { -1.0 });
+        }
+        assert!(curve.error.iter().all(|err| *err == 0.0));
+    }
+}
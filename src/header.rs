@@ -0,0 +1,264 @@
+use std::str;
+
+use failure::{Error, format_err};
+use filebuffer::FileBuffer;
+
+use crate::binary_parsing::CounterState;
+use crate::device::Device;
+use crate::units::BinWidth;
+
+/// The acquisition settings stored in the ASCII header of a list file.
+#[derive(Debug, Clone)]
+pub struct LstHeader {
+    /// Byte offset of the first record, right after the "[DATA]" line
+    pub start_of_data: usize,
+    pub range: u64,
+    pub timepatch: String,
+    /// The multiscaler that wrote the file
+    pub device: Device,
+    /// 1 for each active input of the device, in the order of their channel
+    /// codes. Inputs without an "active" entry are assumed to be active.
+    pub channel_map: Vec<u8>,
+    /// Binary exponent of the bin width, 0 if it isn't in the header
+    pub bitshift: u8,
+    /// Calibrated bin width in nanoseconds, if it's in the header
+    pub calfact: Option<f64>,
+    /// Counters before the first record, for files that continue another one,
+    /// like the pieces written by `split_lst`. The default is a whole acquisition.
+    pub resume: CounterState,
+}
+
+/// Width of each value of the "resume" line, so the line can be rewritten in place.
+const RESUME_WIDTH: usize = 20;
+
+/// Value of the first `key=value` line of `section`, or of the whole header if
+/// `section` is None.
+fn find_value<'a>(header: &'a str, section: Option<&str>, key: &str) -> Option<&'a str> {
+    let mut in_section = section.is_none();
+    for line in header.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            if let Some(section) = section {
+                in_section = line.starts_with(section);
+            }
+            continue;
+        }
+        if !in_section { continue };
+        let mut parts = line.splitn(2, '=');
+        if parts.next() == Some(key) {
+            return parts.next().map(|val| val.trim());
+        }
+    }
+    None
+}
+
+/// Parses an integer header value, which may be written in hexadecimal with a
+/// "0x" prefix.
+fn parse_int(value: &str) -> Result<u8, Error> {
+    let parsed = if let Some(hex) = value.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else {
+        value.parse()
+    };
+    parsed.map_err(|_| format_err!("Invalid integer in the header: {}", value))
+}
+
+/// Parses the "resume" value, the sweep and time epochs, the starts, and the
+/// last sweep and time counters, where "-" is a counter that wasn't seen yet.
+fn parse_resume(value: &str) -> Result<CounterState, Error> {
+    let invalid = || format_err!("Invalid resume line in the header: {}", value);
+    let fields: Vec<&str> = value.split(',').map(|field| field.trim()).collect();
+    if fields.len() != 5 { return Err(invalid()) };
+    let number = |field: &str| field.parse::<u64>().map_err(|_| invalid());
+    let seen = |field: &str| if field == "-" { Ok(None) } else { number(field).map(Some) };
+    Ok(CounterState { sweep_epoch: number(fields[0])?, time_epoch: number(fields[1])?, starts: number(fields[2])?,
+                      last_sweep: seen(fields[3])?, last_time: seen(fields[4])? })
+}
+
+impl LstHeader {
+    /// Reads the header of a list file.
+    pub fn read(fname: &str) -> Result<Self, Error> {
+        let data = FileBuffer::open(fname)?;
+        LstHeader::parse(&data)
+    }
+
+    /// Parses the header at the start of the file contents.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        const DATA_MARK: &[u8] = b"[DATA]";
+        let mark = data.windows(DATA_MARK.len())
+            .position(|window| window == DATA_MARK)
+            .ok_or_else(|| format_err!("No [DATA] section in the file"))?;
+        let newline = data[mark..].iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or_else(|| format_err!("The [DATA] line isn't terminated"))?;
+        let header = str::from_utf8(&data[..mark])?;
+
+        let range = find_value(header, None, "range")
+            .ok_or_else(|| format_err!("No range in the header"))?
+            .parse::<u64>()?;
+        let timepatch = find_value(header, None, "time_patch")
+            .ok_or_else(|| format_err!("No time_patch in the header"))?
+            .to_string();
+        let device = Device::from_header(header);
+        let channel_map = (1..device.num_of_channels() + 1)
+            .map(|chan| {
+                let section = format!("[CHN{}]", chan);
+                match find_value(header, Some(&section), "active") {
+                    Some("0") => 0,
+                    _ => 1,
+                }
+            })
+            .collect();
+        let bitshift = match find_value(header, None, "bitshift") {
+            Some(value) => parse_int(value)?,
+            None => 0,
+        };
+        let calfact = match find_value(header, None, "calfact") {
+            Some(value) => Some(value.parse::<f64>()?),
+            None => None,
+        };
+        let resume = match find_value(header, None, "resume") {
+            Some(value) => parse_resume(value)?,
+            None => CounterState::default(),
+        };
+        Ok(LstHeader { start_of_data: mark + newline + 1, range, timepatch, device, channel_map, bitshift,
+                       calfact, resume })
+    }
+
+    /// The header as the multiscaler writes it, with the settings the parsers
+    /// read, up to and including the "[DATA]" line. Inputs are written as
+    /// active or inactive according to `channel_map`.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("[{} A] {}\r\n", self.device.name, self.device.num_of_channels());
+        text.push_str(&format!("range={}\r\n", self.range));
+        text.push_str(&format!("bitshift={}\r\n", self.bitshift));
+        text.push_str(&format!("time_patch={}\r\n", self.timepatch));
+        if let Some(calfact) = self.calfact {
+            text.push_str(&format!("calfact={}\r\n", calfact));
+        }
+        for chan in 0..self.device.num_of_channels() {
+            let active = self.channel_map.get(chan).cloned().unwrap_or(1);
+            text.push_str(&format!("[CHN{}]\r\nactive={}\r\n", chan + 1, active));
+        }
+        text.push_str("[DATA]\r\n");
+        text
+    }
+
+    /// The header `source` of another file, up to and including its "[DATA]"
+    /// line, with range, bitshift, time_patch, calfact and the "active" line of
+    /// each input set like `to_text` writes them, so the rest of what the
+    /// acquisition software wrote is kept. As in `parse`, the first line with a
+    /// setting is the one that's replaced. Settings and inputs that `source`
+    /// lacks are added, and "resume" lines are left out.
+    pub fn to_text_from(&self, source: &[u8]) -> String {
+        let source = String::from_utf8_lossy(source);
+        let newline = if source.split('\n').next().is_some_and(|line| line.ends_with('\r')) { "\r\n" } else { "\n" };
+        let mut settings = vec![("range", Some(self.range.to_string())), ("bitshift", Some(self.bitshift.to_string())),
+                                ("time_patch", Some(self.timepatch.clone())),
+                                ("calfact", self.calfact.map(|calfact| calfact.to_string()))];
+        let num_of_channels = self.device.num_of_channels();
+        let active = |chan: usize| self.channel_map.get(chan).cloned().unwrap_or(1);
+        let mut has_active = vec![false; num_of_channels];
+        let mut seen_section = vec![false; num_of_channels];
+        // the input whose section the line is in, counting from 0
+        let mut section: Option<usize> = None;
+        let mut text = String::with_capacity(source.len() + 64);
+        for (idx, line) in source.split_inclusive('\n').enumerate() {
+            let content = line.trim();
+            let ending = if line.ends_with("\r\n") { "\r\n" } else { newline };
+            if idx > 0 && content.starts_with('[') {
+                if let Some(chan) = section.filter(|chan| !has_active[*chan]) {
+                    text.push_str(&format!("active={}{}", active(chan), newline));
+                    has_active[chan] = true;
+                }
+                if content.starts_with("[DATA]") {
+                    for (key, value) in settings.drain(..) {
+                        if let Some(value) = value { text.push_str(&format!("{}={}{}", key, value, newline)) };
+                    }
+                    for chan in (0..num_of_channels).filter(|chan| !seen_section[*chan]) {
+                        text.push_str(&format!("[CHN{}]{}active={}{}", chan + 1, newline, active(chan), newline));
+                    }
+                    text.push_str(content);
+                    text.push_str(ending);
+                    break;
+                }
+                section = content.strip_prefix("[CHN")
+                    .and_then(|rest| rest.split(']').next())
+                    .and_then(|number| number.parse::<usize>().ok())
+                    .filter(|number| *number >= 1 && *number <= num_of_channels)
+                    .map(|number| number - 1);
+                if let Some(chan) = section { seen_section[chan] = true };
+                text.push_str(line);
+                continue;
+            }
+            let key = content.split('=').next().unwrap_or("");
+            if key == "resume" { continue };
+            if let Some(chan) = section.filter(|chan| key == "active" && !has_active[*chan]) {
+                text.push_str(&format!("active={}{}", active(chan), ending));
+                has_active[chan] = true;
+                continue;
+            }
+            if let Some(pos) = settings.iter().position(|(name, _)| *name == key) {
+                let (key, value) = settings.remove(pos);
+                if let Some(value) = value { text.push_str(&format!("{}={}{}", key, value, ending)) };
+                continue;
+            }
+            text.push_str(line);
+        }
+        text
+    }
+
+    /// The "resume" line that carries `state`, without its line ending. Its
+    /// length doesn't depend on `state`.
+    pub fn resume_line(state: &CounterState) -> String {
+        let seen = |value: Option<u64>| value.map_or_else(|| "-".to_string(), |value| value.to_string());
+        format!("resume={:>w$},{:>w$},{:>w$},{:>w$},{:>w$}", state.sweep_epoch, state.time_epoch, state.starts,
+                seen(state.last_sweep), seen(state.last_time), w = RESUME_WIDTH)
+    }
+
+    /// Duration of a time bin. The calibrated `calfact` is used if it's in the
+    /// header, and 100 ps times 2^bitshift otherwise.
+    pub fn bin_width(&self) -> BinWidth {
+        match self.calfact {
+            Some(calfact) if calfact > 0.0 => BinWidth::from_picoseconds(calfact * 1000.0),
+            _ => BinWidth::from_bitshift(self.bitshift),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_text_is_patched() {
+        let source = "[MCS6A A] 6\r\nsweepmode=1234\r\nrange=10\r\nbitshift=3\r\ntime_patch=5\r\ncalfact=0.8\r\n\
+                      resume=1,2,3,-,-\r\n[CHN1]\r\nactive=0\r\nholdafter=0\r\n[CHN2]\r\nholdafter=5\r\n\
+                      [CHN3]\r\nactive=1\r\n[DATA]\r\n";
+        let header = LstHeader { range: 20, timepatch: "1a".to_string(), calfact: None,
+                                 channel_map: vec![1, 1, 0, 1, 1, 0], ..LstHeader::parse(source.as_bytes()).unwrap() };
+        let text = header.to_text_from(source.as_bytes());
+        for kept in &["[MCS6A A] 6\r\n", "sweepmode=1234\r\n", "holdafter=0\r\n", "holdafter=5\r\n"] {
+            assert!(text.contains(kept), "{} in {}", kept, text);
+        }
+        assert!(!text.contains("calfact") && !text.contains("resume"), "{}", text);
+        assert!(text.split_inclusive('\n').all(|line| line.ends_with("\r\n")), "{}", text);
+        let parsed = LstHeader::parse(text.as_bytes()).unwrap();
+        assert_eq!((parsed.range, parsed.timepatch.as_str(), parsed.bitshift, parsed.calfact), (20, "1a", 3, None));
+        assert_eq!(parsed.channel_map, header.channel_map);
+        assert_eq!(parsed.start_of_data, text.len());
+    }
+
+    #[test]
+    fn missing_settings_are_added() {
+        let source = "[MCS6A A] 6\nrange=10\ntime_patch=5\n[DATA]\n";
+        let header = LstHeader { bitshift: 2, calfact: Some(0.5), channel_map: vec![0, 1, 1, 1, 1, 1],
+                                 ..LstHeader::parse(source.as_bytes()).unwrap() };
+        let text = header.to_text_from(source.as_bytes());
+        assert!(text.split_inclusive('\n').all(|line| !line.ends_with("\r\n")), "{}", text);
+        assert!(text.ends_with("[DATA]\n"), "{}", text);
+        let parsed = LstHeader::parse(text.as_bytes()).unwrap();
+        assert_eq!((parsed.range, parsed.bitshift, parsed.calfact), (10, 2, Some(0.5)));
+        assert_eq!(parsed.channel_map, header.channel_map);
+    }
+}
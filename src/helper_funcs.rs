@@ -1,51 +1,33 @@
-use std::sync::Mutex;
-
 use crate::binary_parsing::*;
-
-/// Populates a vector of mutex-controlled vectors with the valid active channels of the
-/// experiment. Each channel is a vector, which contains another vector, holding four
-/// other vectors - each of them holding a parsed value - either "lost", "tag", "edge"
-/// or "time".The maximal size of each of these vectors is `data_size + 1` if there was
-/// any data in that channel. Otherwise, its size is 0. Each channel vector is also wrapped in a
-/// mutex to allow for multi-threaded parsing.
-/// Note - I don't use an Option<Mutex<Vec>>> here since I wasn't able to make it compile,
-/// although it probably is the more ergonomic version.
-pub fn create_channel_vec_u8(timepatch: &str, active_channels: Vec<u8>,
-                             data_size: usize) -> Vec<Mutex<ParsedDataU8>> {
-    let chan_with_data = generate_data_vectors_u8(data_size, timepatch);
-    let empty_chan = ParsedDataU8::new(vec![], vec![], vec![], vec![]);
-    let mut chans = Vec::with_capacity(NUM_OF_INPUT_CHANNELS);
-    for is_active in active_channels.iter() {
-        if is_active == &1u8 {
-            chans.push(Mutex::new(chan_with_data.clone()));
-        } else {
-            chans.push(Mutex::new(empty_chan.clone()));
-        };
-    };
-    chans
+use crate::channels::Channels;
+use crate::device::Device;
+use failure::{Error, format_err};
+
+/// Populates a container with the active channels of the experiment, named after
+/// `names`. Each channel holds four vectors - each of them holding a parsed value -
+/// either "lost", "tag", "edge" or "time" - and a "sweep" vector that's only filled
+/// on request. The maximal size of each of these vectors is `data_size + 1`.
+/// Inactive channels aren't part of the container. The channels are filled in file
+/// order by `distribute`, so they don't need to be shared between threads.
+/// Fails for timepatches that aren't read with 8-bit tags.
+pub fn create_channels_u8(timepatch: &str, active_channels: Vec<u8>, names: &[String],
+                          data_size: usize) -> Result<Channels<ParsedDataU8>, Error> {
+    let chan_with_data = generate_data_vectors_u8(data_size, timepatch)?;
+    Ok(Channels::from_active(&active_channels, names, chan_with_data))
 }
 
-pub fn create_channel_vec_u16(timepatch: &str, active_channels: Vec<u8>,
-                              data_size: usize) -> Vec<Mutex<ParsedDataU16>> {
-    let chan_with_data = generate_data_vectors_u16(data_size, timepatch);
-    let empty_chan = ParsedDataU16::new(vec![], vec![], vec![], vec![]);
-    let mut chans = Vec::with_capacity(NUM_OF_INPUT_CHANNELS);
-    for is_active in active_channels.iter() {
-        if is_active == &1u8 {
-            chans.push(Mutex::new(chan_with_data.clone()));
-        } else {
-            chans.push(Mutex::new(empty_chan.clone()));
-        };
-    };
-    chans
+pub fn create_channels_u16(timepatch: &str, active_channels: Vec<u8>, names: &[String],
+                           data_size: usize) -> Result<Channels<ParsedDataU16>, Error> {
+    let chan_with_data = generate_data_vectors_u16(data_size, timepatch)?;
+    Ok(Channels::from_active(&active_channels, names, chan_with_data))
 }
 
 
-fn generate_data_vectors_u8(data_size: usize, timepatch: &str) -> ParsedDataU8 {
+fn generate_data_vectors_u8(data_size: usize, timepatch: &str) -> Result<ParsedDataU8, Error> {
     let num_of_lines: usize = match timepatch {
         "2a" | "22" => calc_num_of_lines(data_size, 6),
         "3" => calc_num_of_lines(data_size, 8),
-        _ => panic!("Timepatch not found for u8 variant {}", timepatch),
+        _ => return Err(format_err!("Unknown timepatch {} for the u8 variant", timepatch)),
     };
     let ve: ParsedDataU8 = match timepatch {
         "2a" => ParsedDataU8::new(vec![],
@@ -60,20 +42,20 @@ fn generate_data_vectors_u8(data_size: usize, timepatch: &str) -> ParsedDataU8 {
                                Vec::with_capacity(num_of_lines + 1),
                                Vec::with_capacity(num_of_lines),
                                Vec::with_capacity(data_size + 1)),
-        _ => panic!("Invalid timepatch value: {}", timepatch),
+        _ => return Err(format_err!("Unknown timepatch {} for the u8 variant", timepatch)),
     };
-    ve
+    Ok(ve)
 }
 
 /// Each timepatch value correlates to a specific vector composition which is detailed here.
 /// The order of vecs is lost, tag, edge, and time.
-fn generate_data_vectors_u16(data_size: usize, timepatch: &str) -> ParsedDataU16 {
+fn generate_data_vectors_u16(data_size: usize, timepatch: &str) -> Result<ParsedDataU16, Error> {
     let num_of_lines: usize = match timepatch {
         "0" => calc_num_of_lines(data_size, 2),
         "5" | "1" => calc_num_of_lines(data_size, 4),
         "1a" | "32" | "2" => calc_num_of_lines(data_size, 6),
         "5b" | "Db" | "f3" | "43" | "c3" => calc_num_of_lines(data_size, 8),
-        _ => panic!("Timepatch not found for variant u16 {}", timepatch),
+        _ => return Err(format_err!("Unknown timepatch {} for the u16 variant", timepatch)),
     };
 
     let ve: ParsedDataU16 = match timepatch {
@@ -88,30 +70,78 @@ fn generate_data_vectors_u16(data_size: usize, timepatch: &str) -> ParsedDataU16
         "f3" => ParsedDataU16::new(Vec::with_capacity(num_of_lines + 1), Vec::with_capacity(num_of_lines * 2 + 1), Vec::with_capacity(num_of_lines), Vec::with_capacity(data_size + 1)),
         "43" => ParsedDataU16::new(Vec::with_capacity(num_of_lines + 1), Vec::with_capacity(num_of_lines * 2 + 1), Vec::with_capacity(num_of_lines), Vec::with_capacity(data_size + 1)),
         "c3" => ParsedDataU16::new(vec![], Vec::with_capacity(num_of_lines * 2 + 1), Vec::with_capacity(num_of_lines), Vec::with_capacity(data_size + 1)),
-        _ => panic!("Invalid timepatch value for u16 variant: {}", timepatch),
+        _ => return Err(format_err!("Unknown timepatch {} for the u16 variant", timepatch)),
     };
-    ve
+    Ok(ve)
 }
 
-/// Find how many events were in the recording
-fn calc_num_of_lines(data_size: usize, bytes: u8) -> usize {
-    ((data_size / bytes as usize) + 1) as usize
+/// All timepatches, from the smallest records to the largest.
+pub const TIMEPATCHES: [&str; 14] = ["0", "5", "1", "1a", "2a", "22", "32", "2", "5b", "Db", "f3", "43", "c3", "3"];
+
+/// Size of a single record of the list file, in bytes. Fails for unknown
+/// timepatches.
+pub fn bytes_per_record(timepatch: &str) -> Result<usize, Error> {
+    match timepatch {
+        "0" => Ok(2),
+        "5" | "1" => Ok(4),
+        "1a" | "32" | "2" | "2a" | "22" => Ok(6),
+        "5b" | "Db" | "f3" | "43" | "c3" | "3" => Ok(8),
+        _ => Err(format_err!("Unknown timepatch {}", timepatch)),
+    }
 }
 
+/// Whether the lost bit of the timepatch lies below the tag bits, right after
+/// the sweep counter, instead of being the highest bit of the record.
+pub fn lost_below_tag(timepatch: &str) -> bool {
+    timepatch == "f3"
+}
+
+/// Widths of the fields of each timepatch, in bits.
+pub struct TimepatchBits;
+
+impl TimepatchBits {
+    /// The widths of the lost, tag, sweep and time fields of `timepatch` in the
+    /// records of `device`, in that order. The channel and edge bits below them
+    /// aren't included. The layouts are the ones of the MCS6A, whose records are
+    /// filled by them. Devices with a wider channel field, like the MCS8A, keep
+    /// the other fields and have a time field that's narrower by as many bits.
+    /// Fails for unknown timepatches.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(timepatch: &str, device: &Device) -> Result<[u8; 4], Error> {
+        let [lost, tag, sweep, _] = TimepatchBits::mcs6a(timepatch)?;
+        let fixed = u32::from(device.channel_bits) + 1 + u32::from(lost) + u32::from(tag) + u32::from(sweep);
+        let time = (bytes_per_record(timepatch)? as u32 * 8).saturating_sub(fixed);
+        Ok([lost, tag, sweep, time as u8])
+    }
+
+    /// The field widths of `timepatch` in records of the MCS6A, with its 3-bit
+    /// channel field.
+    fn mcs6a(timepatch: &str) -> Result<[u8; 4], Error> {
+        let bits = match timepatch {
+            "0" => [0, 0, 0, 12],
+            "5" => [0, 0, 8, 20],
+            "1" => [0, 0, 0, 28],
+            "1a" => [0, 0, 16, 28],
+            "2a" => [0, 8, 8, 28],
+            "22" => [0, 8, 0, 36],
+            "32" => [1, 0, 7, 36],
+            "2" => [0, 0, 0, 44],
+            "5b" => [1, 15, 16, 28],
+            "Db" => [0, 16, 16, 28],
+            "f3" => [1, 16, 7, 36],
+            "43" => [1, 16, 0, 43],
+            "c3" => [0, 16, 0, 44],
+            "3" => [1, 5, 0, 54],
+            _ => return Err(format_err!("Unknown timepatch {}", timepatch)),
+        };
+        Ok(bits)
+    }
+}
 
-// pub fn create_channel_vec_seq(data_size: usize, active_channels: Vec<u8>) -> Vec<Vec<ParsedData>> {
-//     let vec_with_data = Vec::with_capacity(data_size + 1);
-//     let vec_empty_chan = Vec::with_capacity(0);
-//     let mut chans = Vec::with_capacity(NUM_OF_INPUT_CHANNELS);
-//     for is_active in active_channels.iter() {
-//         if is_active == &1u8 {
-//             chans.push(vec_with_data.clone());
-//         } else {
-//             chans.push(vec_empty_chan.clone());
-//         };
-//     };
-//     chans
-// }
+/// Find how many events were in the recording
+fn calc_num_of_lines(data_size: usize, bytes: u8) -> usize {
+    (data_size / bytes as usize) + 1
+}
 
 
 pub fn to_bits_u16(bitarray: &[u8; 4]) -> [u16; 4] {
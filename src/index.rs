@@ -0,0 +1,350 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::time::UNIX_EPOCH;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use failure::{Error, format_err};
+use filebuffer::FileBuffer;
+
+use crate::binary_parsing::*;
+use crate::header::LstHeader;
+use crate::helper_funcs::*;
+use crate::window::Window;
+
+const INDEX_MAGIC: &[u8; 8] = b"LSTIDX02";
+/// Default number of records between two checkpoints
+pub const CHECKPOINT_RECORDS: usize = 1 << 16;
+
+/// A position in the data section from which parsing can be resumed.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    /// Byte offset of the record from the start of the data section
+    pub offset: u64,
+    /// Absolute time and unwrapped sweep of the record
+    pub time: u64,
+    pub sweep: u64,
+    /// The counters right before the record
+    pub state: CounterState,
+}
+
+/// Sidecar index of a list file, mapping absolute times and sweep numbers to
+/// byte offsets in its data section. The times depend on the layout of the
+/// records and on how overflows and starts were handled, so the index only
+/// serves readers with the same settings.
+#[derive(Debug, Clone)]
+pub struct SeekIndex {
+    /// Size and modification time of the list file when it was indexed
+    pub file_size: u64,
+    pub modified: u64,
+    pub timepatch: String,
+    pub range: u64,
+    /// Name of the device whose channel layout the records were decoded with
+    pub device: String,
+    /// The counters before the first record, from the header of the file
+    pub resume: CounterState,
+    pub time_overflow: TimeOverflow,
+    pub start_clock: StartClock,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// Size and modification time, in nanoseconds since the epoch, of a file.
+fn file_stamp(fname: &str) -> Result<(u64, u64), Error> {
+    let metadata = fs::metadata(fname)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((metadata.len(), modified.as_secs() * 1_000_000_000 + u64::from(modified.subsec_nanos())))
+}
+
+impl SeekIndex {
+    /// Path of the sidecar index of a list file.
+    pub fn path_for(fname: &str) -> String {
+        format!("{}.idx", fname)
+    }
+
+    /// Indexes a list file with one pass over its records, placing a checkpoint
+    /// every `checkpoint_records` records. The channel layout is the one of the
+    /// device in the header.
+    pub fn build(fname: &str, header: &LstHeader, options: &ParseOptions, checkpoint_records: usize)
+        -> Result<Self, Error> {
+        let (file_size, modified) = file_stamp(fname)?;
+        let data_with_headers = FileBuffer::open(fname)?;
+        let data = &data_with_headers[header.start_of_data..];
+        let checkpoint_bytes = bytes_per_record(&header.timepatch)? * checkpoint_records.max(1);
+        let options = ParseOptions { device: header.device.clone(), resume: header.resume, ..options.clone() };
+        let mut checkpoints = Vec::new();
+        let mut next_checkpoint = 0;
+        for_each_record(data, &header.timepatch, header.range, &options, |offset, _, followed| {
+            if let Some(FollowedEvent { event, time, state }) = followed {
+                if offset >= next_checkpoint {
+                    checkpoints.push(Checkpoint { offset: offset as u64, time, sweep: event.sweep, state });
+                    next_checkpoint = (offset / checkpoint_bytes + 1) * checkpoint_bytes;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(SeekIndex { file_size, modified, timepatch: header.timepatch.clone(), range: header.range,
+                       device: header.device.name.clone(), resume: header.resume,
+                       time_overflow: options.time_overflow, start_clock: options.start_clock, checkpoints })
+    }
+
+    /// Whether the list file is still the one that was indexed.
+    pub fn is_current(&self, fname: &str) -> Result<bool, Error> {
+        Ok(file_stamp(fname)? == (self.file_size, self.modified))
+    }
+
+    /// Reads the sidecar index of a list file. None if there's no index, if it
+    /// was written in an older format, or if the file changed since it was
+    /// indexed.
+    pub fn load(fname: &str) -> Result<Option<Self>, Error> {
+        let path = SeekIndex::path_for(fname);
+        if fs::metadata(&path).is_err() { return Ok(None) };
+        let mut magic = [0u8; 8];
+        if File::open(&path)?.read_exact(&mut magic).is_err() || &magic != INDEX_MAGIC {
+            return Ok(None);
+        }
+        let index = SeekIndex::read(&path)?;
+        Ok(if index.is_current(fname)? { Some(index) } else { None })
+    }
+
+    /// Whether the index was built for records of `timepatch` and `range`, with
+    /// the same device and time settings as `options`.
+    pub fn serves(&self, timepatch: &str, range: u64, options: &ParseOptions) -> bool {
+        self.timepatch == timepatch && self.range == range && self.device == options.device.name
+            && self.time_overflow == options.time_overflow && self.start_clock == options.start_clock
+    }
+
+    /// Byte range of the data section that holds `window`, and the counters to
+    /// resume parsing from. The range reaches one checkpoint beyond the window
+    /// on both sides, in case records arrive slightly out of order. A range from
+    /// the start of the data resumes from the counters in the header. None if
+    /// the checkpoints aren't sorted by the window's unit, so they can't be
+    /// searched.
+    pub fn slice(&self, window: &Window, data_len: usize) -> Option<(Range<usize>, CounterState)> {
+        let (key, low, high): (fn(&Checkpoint) -> u64, u64, u64) = match *window {
+            Window::Time { start, stop } => (|cp: &Checkpoint| cp.time, start, stop),
+            Window::Sweeps { first, stop } => (|cp: &Checkpoint| cp.sweep, first, stop),
+        };
+        if self.checkpoints.windows(2).any(|pair| key(&pair[1]) < key(&pair[0])) {
+            return None;
+        }
+        let before = self.checkpoints.iter().rposition(|cp| key(cp) < low);
+        let after = self.checkpoints.iter().position(|cp| key(cp) >= high);
+        let (start, state) = match before {
+            Some(idx) => {
+                let checkpoint = self.checkpoints[idx.saturating_sub(1)];
+                (checkpoint.offset as usize, checkpoint.state)
+            },
+            None => (0, self.resume),
+        };
+        let stop = match after {
+            Some(idx) => self.checkpoints.get(idx + 1).map_or(data_len, |cp| cp.offset as usize),
+            None => data_len,
+        };
+        Some((start..stop.max(start).min(data_len), state))
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(INDEX_MAGIC)?;
+        file.write_u64::<LE>(self.file_size)?;
+        file.write_u64::<LE>(self.modified)?;
+        file.write_u8(self.timepatch.len() as u8)?;
+        file.write_all(self.timepatch.as_bytes())?;
+        file.write_u64::<LE>(self.range)?;
+        file.write_u8(self.device.len() as u8)?;
+        file.write_all(self.device.as_bytes())?;
+        write_state(&mut file, &self.resume)?;
+        file.write_u8(match self.time_overflow {
+            TimeOverflow::Disabled => 0,
+            TimeOverflow::BackwardJumps => 1,
+            TimeOverflow::Range => 2,
+        })?;
+        let (clock, period) = match self.start_clock {
+            StartClock::Disabled => (0, 0),
+            StartClock::Period(period) => (1, period),
+            StartClock::Range => (2, 0),
+        };
+        file.write_u8(clock)?;
+        file.write_u64::<LE>(period)?;
+        file.write_u64::<LE>(self.checkpoints.len() as u64)?;
+        for cp in self.checkpoints.iter() {
+            for value in [cp.offset, cp.time, cp.sweep].iter() {
+                file.write_u64::<LE>(*value)?;
+            }
+            write_state(&mut file, &cp.state)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn read(path: &str) -> Result<Self, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(format_err!("{} isn't a list file index", path));
+        }
+        let file_size = file.read_u64::<LE>()?;
+        let modified = file.read_u64::<LE>()?;
+        let mut timepatch = vec![0u8; file.read_u8()? as usize];
+        file.read_exact(&mut timepatch)?;
+        let range = file.read_u64::<LE>()?;
+        let mut device = vec![0u8; file.read_u8()? as usize];
+        file.read_exact(&mut device)?;
+        let resume = read_state(&mut file)?;
+        let time_overflow = match file.read_u8()? {
+            0 => TimeOverflow::Disabled,
+            1 => TimeOverflow::BackwardJumps,
+            2 => TimeOverflow::Range,
+            other => return Err(format_err!("Invalid time overflow mode in the index: {}", other)),
+        };
+        let clock = file.read_u8()?;
+        let period = file.read_u64::<LE>()?;
+        let start_clock = match clock {
+            0 => StartClock::Disabled,
+            1 => StartClock::Period(period),
+            2 => StartClock::Range,
+            other => return Err(format_err!("Invalid start clock in the index: {}", other)),
+        };
+        let num_of_checkpoints = file.read_u64::<LE>()? as usize;
+        let mut checkpoints = Vec::with_capacity(num_of_checkpoints);
+        for _ in 0..num_of_checkpoints {
+            let mut values = [0u64; 3];
+            file.read_u64_into::<LE>(&mut values)?;
+            let state = read_state(&mut file)?;
+            checkpoints.push(Checkpoint { offset: values[0], time: values[1], sweep: values[2], state });
+        }
+        Ok(SeekIndex { file_size, modified, timepatch: String::from_utf8(timepatch)?, range,
+                       device: String::from_utf8(device)?, resume, time_overflow, start_clock, checkpoints })
+    }
+}
+
+/// Writes the counters of `state`, where a counter that wasn't seen yet is
+/// stored as u64::MAX.
+fn write_state<W: Write>(file: &mut W, state: &CounterState) -> Result<(), Error> {
+    for value in [state.sweep_epoch, state.time_epoch, state.starts, state.last_sweep.unwrap_or(u64::MAX),
+                  state.last_time.unwrap_or(u64::MAX)].iter() {
+        file.write_u64::<LE>(*value)?;
+    }
+    Ok(())
+}
+
+fn read_state<R: Read>(file: &mut R) -> Result<CounterState, Error> {
+    let mut values = [0u64; 5];
+    file.read_u64_into::<LE>(&mut values)?;
+    let seen = |value: u64| if value == u64::MAX { None } else { Some(value) };
+    Ok(CounterState { sweep_epoch: values[0], time_epoch: values[1], starts: values[2],
+                      last_sweep: seen(values[3]), last_time: seen(values[4]) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::OpenOptions;
+
+    use super::*;
+    use crate::device::Device;
+    use crate::split::split_lst;
+    use crate::writer::write_lst;
+
+    const RANGE: u64 = 1000;
+
+    /// Writes a "5" file with four events per sweep over 500 sweeps, so the
+    /// 8-bit sweep counter wraps once.
+    fn sweep_file(name: &str) -> (String, LstHeader) {
+        let device = Device::mcs6a();
+        let header = LstHeader { start_of_data: 0, range: RANGE, timepatch: "5".to_string(), device,
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let events: Vec<Event> = (0..2000u64)
+            .map(|idx| Event { time: RANGE * (idx / 4) + (idx * 7) % RANGE, sweep: idx / 4 + 1, ..Event::default() })
+            .collect();
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        let written = write_lst(&path, &header, &events).unwrap();
+        (path, written)
+    }
+
+    fn times(data: &[u8], options: &ParseOptions) -> Vec<u64> {
+        let channels = create_channels_u16("5", vec![1, 0, 0, 0, 0, 1], &options.channel_names(), data.len()).unwrap();
+        let parsed = parse_5(data, RANGE, &TimepatchBits::new("5", &options.device).unwrap(), channels, options);
+        parsed.channel(0).unwrap().time.clone()
+    }
+
+    #[test]
+    fn slices_parse_like_the_whole_file() {
+        let (path, header) = sweep_file("libps_index_slice.lst");
+        let index = SeekIndex::build(&path, &header, &ParseOptions::default(), 16).unwrap();
+        assert_eq!(index.checkpoints.len(), 2000 / 16);
+        let data = fs::read(&path).unwrap();
+        let data = &data[header.start_of_data..];
+        let windows = [Window::Sweeps { first: 250, stop: 270 }, Window::Time { start: 400_500, stop: 402_000 }];
+        for window in windows.iter() {
+            let mut options = ParseOptions { window: Some(*window), ..ParseOptions::default() };
+            let expected = times(data, &options);
+            let (slice, state) = index.slice(window, data.len()).unwrap();
+            assert!(slice.len() < data.len() / 4);
+            options.resume = state;
+            options.data_offset = slice.start;
+            assert!(!expected.is_empty());
+            assert_eq!(times(&data[slice], &options), expected);
+        }
+    }
+
+    #[test]
+    fn slices_of_a_resumed_piece_start_from_its_header() {
+        let (path, header) = sweep_file("libps_index_resumed.lst");
+        let piece_paths = vec![format!("{}_piece.lst", path)];
+        let piece_path = &piece_paths[0];
+        split_lst(&path, &header, &ParseOptions::default(), &[Window::Sweeps { first: 300, stop: 400 }],
+                  &piece_paths).unwrap();
+        let piece = LstHeader::read(piece_path).unwrap();
+        assert_eq!(piece.resume.sweep_epoch, 1);
+        let index = SeekIndex::build(piece_path, &piece, &ParseOptions::default(), 16).unwrap();
+        let data = fs::read(piece_path).unwrap();
+        let data = &data[piece.start_of_data..];
+        // the window starts at the first checkpoint, so the slice starts at the data
+        let window = Window::Sweeps { first: 300, stop: 302 };
+        let mut options = ParseOptions { window: Some(window), resume: piece.resume, ..ParseOptions::default() };
+        let expected = times(data, &options);
+        let (slice, state) = index.slice(&window, data.len()).unwrap();
+        assert_eq!(slice.start, 0);
+        assert_eq!((state.sweep_epoch, state.last_sweep), (piece.resume.sweep_epoch, piece.resume.last_sweep));
+        options.resume = state;
+        assert_eq!(expected.len(), 8);
+        assert_eq!(times(&data[slice], &options), expected);
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let (path, header) = sweep_file("libps_index_round_trip.lst");
+        let options = ParseOptions { time_overflow: TimeOverflow::Range, ..ParseOptions::default() };
+        let index = SeekIndex::build(&path, &header, &options, 100).unwrap();
+        let index_path = SeekIndex::path_for(&path);
+        index.write(&index_path).unwrap();
+        let read = SeekIndex::read(&index_path).unwrap();
+        assert_eq!((read.file_size, read.modified, &read.timepatch),
+                   (index.file_size, index.modified, &index.timepatch));
+        assert_eq!((read.time_overflow, read.start_clock), (TimeOverflow::Range, StartClock::Disabled));
+        let fields = |cp: &Checkpoint| (cp.offset, cp.time, cp.sweep, cp.state.sweep_epoch, cp.state.time_epoch,
+                                        cp.state.starts, cp.state.last_sweep, cp.state.last_time);
+        assert_eq!(read.checkpoints.iter().map(fields).collect::<Vec<_>>(),
+                   index.checkpoints.iter().map(fields).collect::<Vec<_>>());
+        assert!(read.checkpoints.iter().any(|cp| cp.state.sweep_epoch == 1));
+        assert_eq!((read.range, &read.device), (RANGE, &index.device));
+        assert!(read.serves("5", RANGE, &options));
+        assert!(!read.serves("5", RANGE, &ParseOptions::default()));
+        assert!(!read.serves("5", RANGE / 2, &options));
+        assert!(!read.serves("5", RANGE, &ParseOptions { device: Device::mcs8a(), ..options.clone() }));
+    }
+
+    #[test]
+    fn stale_index_is_ignored() {
+        let (path, header) = sweep_file("libps_index_stale.lst");
+        let index = SeekIndex::build(&path, &header, &ParseOptions::default(), 100).unwrap();
+        index.write(&SeekIndex::path_for(&path)).unwrap();
+        assert!(SeekIndex::load(&path).unwrap().is_some());
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0; 4]).unwrap();
+        assert!(!index.is_current(&path).unwrap());
+        assert!(SeekIndex::load(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,403 @@
+use rayon::prelude::*;
+use pyo3::prelude::*;
+use pyo3::{IntoPyObject, PyObject, PyDict};
+
+/// Parameters for inferring the laser pulse timing from photon arrival times.
+/// Periods are given in time bins of the multiscaler.
+#[derive(Debug, Clone)]
+pub struct LaserInference {
+    pub min_period: f64,
+    pub max_period: f64,
+    /// Number of bins in the phase histogram used to score a candidate period
+    pub phase_bins: usize,
+    /// Photons used to estimate the period and phase of each drift segment
+    pub photons_per_segment: usize,
+    /// Maximal number of candidate periods scored in the initial coarse search
+    pub max_candidates: usize,
+    /// Largest relative change of the period between consecutive segments
+    pub max_drift: f64,
+}
+
+impl LaserInference {
+    pub fn new(min_period: f64, max_period: f64) -> Self {
+        LaserInference { min_period, max_period, phase_bins: 64,
+                         photons_per_segment: 50_000, max_candidates: 20_000,
+                         max_drift: 1e-3 }
+    }
+}
+
+/// A stretch of the acquisition over which the laser period is constant.
+/// `reference` is the time of a pulse at or before `start`, and `first_pulse`
+/// is the running index of that pulse.
+#[derive(Debug, Clone)]
+pub struct LaserSegment {
+    pub start: u64,
+    pub period: f64,
+    pub reference: f64,
+    pub first_pulse: u64,
+}
+
+/// Laser pulse timing that was estimated from the photons themselves.
+#[derive(Debug, Clone)]
+pub struct InferredLaser {
+    pub segments: Vec<LaserSegment>,
+}
+
+/// The laser pulses of an experiment, either recorded on one of the inputs
+/// of the multiscaler or inferred from the photon arrival times.
+#[derive(Debug, Clone)]
+pub enum LaserPulses {
+    Recorded(Vec<u64>),
+    Inferred(InferredLaser),
+}
+
+impl LaserPulses {
+    /// Uses the times of a dedicated laser sync channel.
+    pub fn from_channel(times: &[u64]) -> Self {
+        let mut times = times.to_vec();
+        times.sort_unstable();
+        LaserPulses::Recorded(times)
+    }
+
+    /// Estimates the laser timing from the photons of a PMT channel.
+    pub fn infer(photons: &[u64], params: &LaserInference) -> Option<Self> {
+        infer_laser(photons, params).map(LaserPulses::Inferred)
+    }
+
+    /// Returns the index of the pulse preceding `time` and the delay of `time`
+    /// after that pulse, in bins. None is returned for times before the first pulse.
+    pub fn locate(&self, time: u64) -> Option<(u64, f64)> {
+        match self {
+            LaserPulses::Recorded(pulses) => {
+                let idx = match pulses.binary_search(&time) {
+                    Ok(idx) => idx,
+                    Err(0) => return None,
+                    Err(idx) => idx - 1,
+                };
+                Some((idx as u64, (time - pulses[idx]) as f64))
+            },
+            LaserPulses::Inferred(laser) => laser.locate(time),
+        }
+    }
+
+    /// Vectorized version of `locate`. Photons before the first pulse get a
+    /// NaN delay and a pulse index of `u64::MAX`.
+    pub fn locate_all(&self, times: &[u64]) -> (Vec<u64>, Vec<f64>) {
+        times
+            .par_iter()
+            .map(|time| self.locate(*time).unwrap_or((u64::MAX, f64::NAN)))
+            .unzip()
+    }
+
+    /// Times of all pulses between `start` and `stop`, rounded to the nearest bin.
+    pub fn pulses_between(&self, start: u64, stop: u64) -> Vec<u64> {
+        match self {
+            LaserPulses::Recorded(pulses) => {
+                pulses.iter().cloned().filter(|t| *t >= start && *t < stop).collect()
+            },
+            LaserPulses::Inferred(laser) => laser.pulses_between(start, stop),
+        }
+    }
+
+    /// Mean period of the laser, in bins.
+    pub fn mean_period(&self) -> Option<f64> {
+        match self {
+            LaserPulses::Recorded(pulses) => {
+                if pulses.len() < 2 { return None };
+                Some((pulses[pulses.len() - 1] - pulses[0]) as f64 / (pulses.len() - 1) as f64)
+            },
+            LaserPulses::Inferred(laser) => {
+                let num = laser.segments.len() as f64;
+                Some(laser.segments.iter().map(|seg| seg.period).sum::<f64>() / num)
+            },
+        }
+    }
+}
+
+impl InferredLaser {
+    fn segment_of(&self, time: u64) -> Option<&LaserSegment> {
+        let idx = match self.segments.binary_search_by_key(&time, |seg| seg.start) {
+            Ok(idx) => idx,
+            Err(0) => 0,
+            Err(idx) => idx - 1,
+        };
+        self.segments.get(idx)
+    }
+
+    pub fn locate(&self, time: u64) -> Option<(u64, f64)> {
+        let seg = self.segment_of(time)?;
+        let since_ref = time as f64 - seg.reference;
+        if since_ref < 0.0 { return None };
+        let pulses = (since_ref / seg.period).floor();
+        Some((seg.first_pulse + pulses as u64, since_ref - pulses * seg.period))
+    }
+
+    pub fn pulses_between(&self, start: u64, stop: u64) -> Vec<u64> {
+        let mut pulses = Vec::new();
+        for (num, seg) in self.segments.iter().enumerate() {
+            let seg_stop = self.segments.get(num + 1).map_or(stop, |next| next.start.min(stop));
+            let seg_start = seg.start.max(start);
+            if seg_start >= seg_stop { continue };
+            let mut pulse = ((seg_start as f64 - seg.reference) / seg.period).ceil().max(0.0);
+            loop {
+                let time = (seg.reference + pulse * seg.period).round() as u64;
+                if time >= seg_stop { break };
+                pulses.push(time);
+                pulse += 1.0;
+            }
+        }
+        pulses
+    }
+}
+
+/// Estimates the laser repetition period and phase from photon arrival times.
+/// Photons of a pulsed excitation cluster at a fixed phase of the laser period,
+/// so the period is the one for which the histogram of `time % period` is the
+/// least uniform. The photons are split into segments of
+/// `params.photons_per_segment`, and the period and phase are refined in each of
+/// them to follow a slow drift of the laser.
+pub fn infer_laser(photons: &[u64], params: &LaserInference) -> Option<InferredLaser> {
+    let mut photons = photons.to_vec();
+    photons.sort_unstable();
+    if photons.len() < 2 || params.min_period <= 0.0 || params.max_period < params.min_period {
+        return None
+    }
+    let (mut period, mut uncertainty) = coarse_period(&photons, params)?;
+
+    let mut segments: Vec<LaserSegment> = Vec::new();
+    for chunk in photons.chunks(params.photons_per_segment.max(2)) {
+        // each segment starts from the period of the previous one
+        period = refine_period(chunk, period, uncertainty, params.phase_bins);
+        uncertainty = period * params.max_drift;
+        let start = chunk[0];
+        let phase = pulse_phase(chunk, period, params.phase_bins);
+        // the pulse at or before the first photon of the segment
+        let reference = start as f64 - (start as f64 - phase).rem_euclid(period);
+        let first_pulse = match segments.last() {
+            Some(prev) => {
+                let pulses = (2.0 * (reference - prev.reference) / (prev.period + period)).round();
+                prev.first_pulse + pulses.max(0.0) as u64
+            },
+            None => 0,
+        };
+        segments.push(LaserSegment { start, period, reference, first_pulse });
+    }
+    Some(InferredLaser { segments })
+}
+
+/// Scores how far the phase histogram of the photons is from a uniform one.
+fn phase_contrast(photons: &[u64], period: f64, bins: usize) -> f64 {
+    let origin = photons[0];
+    let mut hist = vec![0u64; bins];
+    for photon in photons {
+        let phase = ((photon - origin) as f64 / period).fract();
+        hist[((phase * bins as f64) as usize).min(bins - 1)] += 1;
+    }
+    let mean = photons.len() as f64 / bins as f64;
+    hist.iter().map(|count| (*count as f64 - mean).powi(2)).sum::<f64>() / mean
+}
+
+/// Share of the best contrast that a multiple of the best period has to reach
+/// to be taken as the laser period instead.
+const HARMONIC_CONTRAST: f64 = 0.9;
+
+/// Finds the best period on a coarse grid spanning the whole allowed range, and
+/// returns it with its uncertainty. Only the first photons are used, so that the
+/// phase error accumulated across them stays below one histogram bin between
+/// neighbouring candidates. Photons that cluster at a single phase of the laser
+/// period also do so at a single phase of each of its fractions, which then
+/// score about as high as the period itself, so the longest multiple of the
+/// best candidate that scores nearly as high is taken.
+fn coarse_period(photons: &[u64], params: &LaserInference) -> Option<(f64, f64)> {
+    let bins = params.phase_bins as f64;
+    let span = params.max_candidates as f64 * params.min_period.powi(2)
+        / ((params.max_period - params.min_period).max(params.min_period * 1e-3) * bins);
+    let last = photons.iter().position(|t| (t - photons[0]) as f64 > span).unwrap_or(photons.len());
+    let sample = &photons[..last.max(2)];
+    let sample_span = (sample[sample.len() - 1] - sample[0]).max(1) as f64;
+    let step = params.min_period.powi(2) / (bins * sample_span);
+    let num = (((params.max_period - params.min_period) / step).ceil() as usize).max(1);
+    let (best, contrast) = (0..num + 1)
+        .into_par_iter()
+        .map(|idx| {
+            let period = params.min_period + idx as f64 * step;
+            (period, phase_contrast(sample, period, params.phase_bins))
+        })
+        .reduce_with(|a, b| if b.1 > a.1 { b } else { a })?;
+    let fundamental = (2..)
+        .map(|multiple| (multiple as f64, multiple as f64 * best))
+        .take_while(|(_, period)| *period <= params.max_period)
+        .filter_map(|(multiple, period)| {
+            let period = refine_period(sample, period, multiple * step, params.phase_bins);
+            let multiple_contrast = phase_contrast(sample, period, params.phase_bins);
+            if multiple_contrast >= HARMONIC_CONTRAST * contrast { Some((period, multiple)) } else { None }
+        })
+        .last()
+        .unwrap_or((best, 1.0));
+    // with few photons the contrast peak spans several grid steps
+    Some((fundamental.0, fundamental.1 * step * 8.0))
+}
+
+/// Refines a period estimate known to within `uncertainty` bins. Every round scores
+/// a grid of candidates that is fine enough for neighbouring candidates to drift
+/// apart by less than half a histogram bin across the photons in use, and then
+/// narrows the grid around the best one and uses a longer span of photons,
+/// until all photons are used.
+fn refine_period(photons: &[u64], mut period: f64, mut uncertainty: f64, bins: usize) -> f64 {
+    const MIN_PHOTONS: usize = 2000;
+    const GROWTH: u64 = 16;
+    if photons.len() < 2 { return period };
+    let mut last = photons.len().clamp(2, MIN_PHOTONS);
+    loop {
+        let sample = &photons[..last];
+        let span = (sample[sample.len() - 1] - sample[0]).max(1) as f64;
+        let step = (period.powi(2) / (2.0 * bins as f64 * span)).min(uncertainty);
+        let num = (2.0 * uncertainty / step).ceil() as usize;
+        period = (0..num + 1)
+            .into_par_iter()
+            .map(|idx| {
+                let candidate = period - uncertainty + idx as f64 * step;
+                (candidate, phase_contrast(sample, candidate, bins))
+            })
+            .reduce_with(|a, b| if b.1 > a.1 { b } else { a })
+            .map(|best| best.0)
+            .unwrap_or(period);
+        uncertainty = step;
+        if last == photons.len() { break };
+        let max_time = sample[0] + (span as u64) * GROWTH;
+        last = photons.iter().position(|t| *t > max_time).unwrap_or(photons.len()).max(last + 1);
+    }
+    period
+}
+
+/// Absolute time of one of the pulses in the photon stream. The pulse is placed at
+/// the rising edge of the fluorescence, i.e. where the phase histogram first crosses
+/// halfway between its background and its peak.
+fn pulse_phase(photons: &[u64], period: f64, bins: usize) -> f64 {
+    let origin = photons[0];
+    let mut hist = vec![0u64; bins];
+    for photon in photons {
+        let phase = ((photon - origin) as f64 / period).fract();
+        hist[((phase * bins as f64) as usize).min(bins - 1)] += 1;
+    }
+    let (peak, peak_count) = hist.iter().enumerate()
+        .max_by_key(|(_, count)| **count)
+        .map(|(idx, count)| (idx, *count))
+        .unwrap_or((0, 0));
+    let mut sorted = hist.clone();
+    sorted.sort_unstable();
+    let background = sorted[bins / 2];
+    let half_max = (background + peak_count) / 2;
+    let mut edge = peak;
+    for back in 1..bins {
+        let idx = (peak + bins - back) % bins;
+        if hist[idx] < half_max { break };
+        edge = idx;
+    }
+    origin as f64 + edge as f64 * period / bins as f64
+}
+
+impl IntoPyObject for InferredLaser {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        let start: Vec<u64> = self.segments.iter().map(|seg| seg.start).collect();
+        let period: Vec<f64> = self.segments.iter().map(|seg| seg.period).collect();
+        let reference: Vec<f64> = self.segments.iter().map(|seg| seg.reference).collect();
+        let first_pulse: Vec<u64> = self.segments.iter().map(|seg| seg.first_pulse).collect();
+        dict.set_item("start", start).expect("Start insertion error.");
+        dict.set_item("period", period).expect("Period insertion error.");
+        dict.set_item("reference", reference).expect("Reference insertion error.");
+        dict.set_item("first_pulse", first_pulse).expect("First pulse insertion error.");
+        dict.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Photons of a pulse train whose `n`-th pulse comes `period(n)` after the
+    /// previous one, with a photon after a third of the pulses, delayed by up
+    /// to `spread` bins.
+    fn pulse_train(pulses: usize, spread: f64, period: impl Fn(usize) -> f64) -> (Vec<f64>, Vec<u64>) {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut pulse_times = Vec::with_capacity(pulses);
+        let mut photons = Vec::new();
+        let mut time = 1000.0;
+        for num in 0..pulses {
+            pulse_times.push(time);
+            if random() < 1.0 / 3.0 {
+                photons.push((time + spread * random()) as u64);
+            }
+            time += period(num);
+        }
+        (pulse_times, photons)
+    }
+
+    /// Checks that the photons are located right after a pulse.
+    fn assert_after_pulses(laser: &LaserPulses, photons: &[u64], period: f64, spread: f64) {
+        let tolerance = 2.0 * period / 64.0;
+        let located = photons.iter()
+            .filter_map(|photon| laser.locate(*photon))
+            .filter(|(_, delay)| *delay <= spread + tolerance || *delay >= period - tolerance)
+            .count();
+        assert!(located as f64 >= 0.99 * photons.len() as f64, "{} of {}", located, photons.len());
+    }
+
+    #[test]
+    fn constant_period() {
+        let period = 1250.37;
+        let (pulses, photons) = pulse_train(60_000, 100.0, |_| period);
+        let params = LaserInference { photons_per_segment: 5000, ..LaserInference::new(1000.0, 1500.0) };
+        let laser = LaserPulses::infer(&photons, &params).unwrap();
+        assert!((laser.mean_period().unwrap() - period).abs() < 1e-3 * period / 64.0);
+        assert_after_pulses(&laser, &photons, period, 100.0);
+        // the pulse index follows the pulses from the first segment on
+        let (index, _) = laser.locate(photons[photons.len() - 1]).unwrap();
+        let first = laser.locate(photons[0]).unwrap().0;
+        let last_pulse = pulses.iter().rposition(|pulse| *pulse <= photons[photons.len() - 1] as f64).unwrap();
+        let first_pulse = pulses.iter().rposition(|pulse| *pulse <= photons[0] as f64).unwrap();
+        assert_eq!(index - first, (last_pulse - first_pulse) as u64);
+    }
+
+    #[test]
+    fn drifting_period() {
+        // the period grows by 2e-4 across the acquisition
+        let (start, pulses) = (1250.0, 60_000);
+        let drift = |num: usize| start * (1.0 + 2e-4 * num as f64 / pulses as f64);
+        let (pulse_times, photons) = pulse_train(pulses, 100.0, drift);
+        let params = LaserInference { photons_per_segment: 2000, ..LaserInference::new(1000.0, 1500.0) };
+        let laser = infer_laser(&photons, &params).unwrap();
+        assert_eq!(laser.segments.len(), photons.len().div_ceil(2000));
+        for seg in &laser.segments {
+            let pulse = pulse_times.iter().rposition(|time| *time <= seg.start as f64).unwrap();
+            assert!((seg.period - drift(pulse)).abs() < 2e-5 * start, "{} != {}", seg.period, drift(pulse));
+        }
+        assert_after_pulses(&LaserPulses::Inferred(laser), &photons, start, 100.0);
+    }
+
+    #[test]
+    fn fractions_of_the_period_are_not_taken() {
+        // the fluorescence fits in one phase bin of half the period, which then
+        // scores as high as the period itself
+        let period = 1250.37;
+        let (_, photons) = pulse_train(60_000, 5.0, |_| period);
+        let params = LaserInference { photons_per_segment: 5000, ..LaserInference::new(400.0, 1400.0) };
+        let laser = LaserPulses::infer(&photons, &params).unwrap();
+        assert!((laser.mean_period().unwrap() - period).abs() < 1e-3 * period / 64.0,
+                "{}", laser.mean_period().unwrap());
+        assert_after_pulses(&laser, &photons, period, 5.0);
+    }
+
+    #[test]
+    fn too_few_photons() {
+        assert!(infer_laser(&[10], &LaserInference::new(1000.0, 1500.0)).is_none());
+        assert!(infer_laser(&[10, 2000], &LaserInference::new(1500.0, 1000.0)).is_none());
+    }
+}
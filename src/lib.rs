@@ -6,87 +6,772 @@ extern crate failure;
 extern crate failure_derive;
 #[macro_use] extern crate pyo3;
 
-use std::fs;
-
 use pyo3::prelude::*;
+use pyo3::{exc, IntoPyObject, PyDict};
 use filebuffer::FileBuffer;
+use numpy::{IntoPyArray, PyArrayModule};
+use failure::{Error, format_err};
+use std::collections::HashMap;
 
 pub mod binary_parsing;
+pub mod channels;
+pub mod device;
 pub mod helper_funcs;
+pub mod laser;
+pub mod censoring;
+pub mod photon_stats;
+pub mod header;
+pub mod correlation;
+pub mod fcs;
+pub mod rates;
+pub mod units;
+pub mod window;
+pub mod index;
+pub mod resync;
+pub mod validate;
+pub mod merge;
+pub mod split;
+pub mod writer;
+pub mod transcode;
 
 use binary_parsing::*;
+use channels::*;
+use device::*;
 use helper_funcs::*;
+use laser::*;
+use censoring::*;
+use photon_stats::*;
+use header::*;
+use correlation::*;
+use fcs::*;
+use rates::*;
+use units::*;
+use window::*;
+use index::*;
+use resync::*;
+use validate::*;
+use merge::*;
+use split::*;
+use writer::*;
+use transcode::*;
 
 /// Python bindings to this library
 #[pymodinit]
 fn libps(_py: Python, m: &PyModule) -> PyResult<()> {
    
-   #[pyfn(m, "read_binary_lst_u8")]
+   #[pyfn(m, "read_binary_lst_u8", dead_time = "None", sweep = "false", sweep_relative = "false", time_overflow = "None",
+             count_starts = "false", start_period = "None", time_unit = "None",
+             edges = "None", time_window = "None", sweep_window = "None", device = "None",
+             roles = "None", inactive = "None")]
    fn py_read_lst_u8(py: Python, file_path: String, start_of_data_pos: usize, range: u64, timepatch: String,
-            channel_map: Vec<u8>) -> PyResult<LstReturnU8> {
-        let result = py.allow_threads(move || analyze_lst_u8(&file_path, start_of_data_pos, range, &timepatch, channel_map));
-        Ok(result)
+            channel_map: Vec<u8>, dead_time: Option<Vec<u64>>, sweep: bool,
+            sweep_relative: bool, time_overflow: Option<String>, count_starts: bool,
+            start_period: Option<u64>, time_unit: Option<String>,
+            edges: Option<Vec<String>>, time_window: Option<(u64, u64)>,
+            sweep_window: Option<(u64, u64)>, device: Option<String>,
+            roles: Option<&PyDict>, inactive: Option<String>) -> PyResult<LstReturnU8> {
+        let options = parse_options(py, &file_path, dead_time, sweep, sweep_relative, time_overflow,
+                                    count_starts, start_period, time_unit, edges, device,
+                                    roles_from_dict(roles)?, inactive)?;
+        let window = window_from_args(time_window, sweep_window)?;
+        let result = py.allow_threads(move || match window {
+            Some(window) => analyze_lst_u8_window(&file_path, start_of_data_pos, range, &timepatch,
+                                                  channel_map, &options, window),
+            None => analyze_lst_u8(&file_path, start_of_data_pos, range, &timepatch, channel_map, &options),
+        });
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
     }
 
-    #[pyfn(m, "read_binary_lst_u16")]
+    #[pyfn(m, "read_binary_lst_u16", dead_time = "None", sweep = "false", sweep_relative = "false", time_overflow = "None",
+             count_starts = "false", start_period = "None", time_unit = "None",
+             edges = "None", time_window = "None", sweep_window = "None", device = "None",
+             roles = "None", inactive = "None")]
    fn py_read_lst_u16(py: Python, file_path: String, start_of_data_pos: usize, range: u64, timpatch: String,
-            channel_map: Vec<u8>) -> PyResult<LstReturnU16> {
-        let result = py.allow_threads(move || analyze_lst_u16(&file_path, start_of_data_pos, range, &timpatch, channel_map));
+            channel_map: Vec<u8>, dead_time: Option<Vec<u64>>, sweep: bool,
+            sweep_relative: bool, time_overflow: Option<String>, count_starts: bool,
+            start_period: Option<u64>, time_unit: Option<String>,
+            edges: Option<Vec<String>>, time_window: Option<(u64, u64)>,
+            sweep_window: Option<(u64, u64)>, device: Option<String>,
+            roles: Option<&PyDict>, inactive: Option<String>) -> PyResult<LstReturnU16> {
+        let options = parse_options(py, &file_path, dead_time, sweep, sweep_relative, time_overflow,
+                                    count_starts, start_period, time_unit, edges, device,
+                                    roles_from_dict(roles)?, inactive)?;
+        let window = window_from_args(time_window, sweep_window)?;
+        let result = py.allow_threads(move || match window {
+            Some(window) => analyze_lst_u16_window(&file_path, start_of_data_pos, range, &timpatch,
+                                                   channel_map, &options, window),
+            None => analyze_lst_u16(&file_path, start_of_data_pos, range, &timpatch, channel_map, &options),
+        });
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// Parses consecutive files of one acquisition into one timeline. Each file
+    /// starts `gap` bins after the last event of the files before it, or at its
+    /// entry of `starts`.
+    #[pyfn(m, "merge_binary_lst_u8", gap = "0", starts = "None", dead_time = "None", sweep = "false",
+             time_overflow = "None", count_starts = "false", start_period = "None", time_unit = "None",
+             edges = "None", device = "None", roles = "None", inactive = "None")]
+    fn py_merge_lst_u8(py: Python, file_paths: Vec<String>, gap: u64, starts: Option<Vec<u64>>,
+                       dead_time: Option<Vec<u64>>, sweep: bool, time_overflow: Option<String>,
+                       count_starts: bool, start_period: Option<u64>, time_unit: Option<String>,
+                       edges: Option<Vec<String>>, device: Option<String>, roles: Option<&PyDict>,
+                       inactive: Option<String>) -> PyResult<LstReturnU8> {
+        let first = file_paths.first().cloned().ok_or_else(|| exc::ValueError::new("No files to merge"))?;
+        let options = parse_options(py, &first, dead_time, sweep, false, time_overflow, count_starts,
+                                    start_period, time_unit, edges, device, roles_from_dict(roles)?, inactive)?;
+        let offsets = match starts {
+            Some(starts) => FileOffsets::Starts(starts),
+            None => FileOffsets::Gap(gap),
+        };
+        let result = py.allow_threads(move || merge_lst_u8(&file_paths, &options, &offsets));
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// Parses consecutive files of one acquisition into one timeline. Each file
+    /// starts `gap` bins after the last event of the files before it, or at its
+    /// entry of `starts`.
+    #[pyfn(m, "merge_binary_lst_u16", gap = "0", starts = "None", dead_time = "None", sweep = "false",
+             time_overflow = "None", count_starts = "false", start_period = "None", time_unit = "None",
+             edges = "None", device = "None", roles = "None", inactive = "None")]
+    fn py_merge_lst_u16(py: Python, file_paths: Vec<String>, gap: u64, starts: Option<Vec<u64>>,
+                        dead_time: Option<Vec<u64>>, sweep: bool, time_overflow: Option<String>,
+                        count_starts: bool, start_period: Option<u64>, time_unit: Option<String>,
+                        edges: Option<Vec<String>>, device: Option<String>, roles: Option<&PyDict>,
+                        inactive: Option<String>) -> PyResult<LstReturnU16> {
+        let first = file_paths.first().cloned().ok_or_else(|| exc::ValueError::new("No files to merge"))?;
+        let options = parse_options(py, &first, dead_time, sweep, false, time_overflow, count_starts,
+                                    start_period, time_unit, edges, device, roles_from_dict(roles)?, inactive)?;
+        let offsets = match starts {
+            Some(starts) => FileOffsets::Starts(starts),
+            None => FileOffsets::Gap(gap),
+        };
+        let result = py.allow_threads(move || merge_lst_u16(&file_paths, &options, &offsets));
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// Writes the records of each window to a new list file in `out_paths`, and
+    /// returns the number of records of each. The windows are (start, stop)
+    /// pairs of absolute times, of sweeps, or of frames, which start at the
+    /// events of `frame_channel`. The pieces parse like the original file with
+    /// the window, since their headers carry the counters before them.
+    #[pyfn(m, "split_lst", time_windows = "None", sweep_windows = "None", frame_windows = "None",
+             frame_channel = "None", time_overflow = "None", count_starts = "false", start_period = "None")]
+    fn py_split_lst(py: Python, file_path: String, out_paths: Vec<String>,
+                    time_windows: Option<Vec<(u64, u64)>>, sweep_windows: Option<Vec<(u64, u64)>>,
+                    frame_windows: Option<Vec<(usize, usize)>>, frame_channel: Option<String>,
+                    time_overflow: Option<String>, count_starts: bool, start_period: Option<u64>)
+        -> PyResult<Vec<usize>> {
+        let options = parse_options(py, &file_path, None, false, false, time_overflow, count_starts, start_period,
+                                    None, None, None, HashMap::new(), None)?;
+        let result = py.allow_threads(move || -> Result<Vec<usize>, Error> {
+            let header = LstHeader::read(&file_path)?;
+            let windows: Vec<Window> = match (time_windows, sweep_windows, frame_windows) {
+                (Some(windows), None, None) => windows.iter()
+                    .map(|(start, stop)| Window::Time { start: *start, stop: *stop })
+                    .collect(),
+                (None, Some(windows), None) => windows.iter()
+                    .map(|(first, stop)| Window::Sweeps { first: *first, stop: *stop })
+                    .collect(),
+                (None, None, Some(windows)) => {
+                    let name = frame_channel.ok_or_else(|| format_err!("Frame windows need a frame_channel"))?;
+                    let channel = header.device.channel_index(&name)
+                        .ok_or_else(|| format_err!("Unknown channel: {}", name))?;
+                    let times = analyze_lst_times(&file_path, &header, &options)?;
+                    windows.iter()
+                        .map(|(first, stop)| frame_window(&times[channel], *first, *stop))
+                        .collect::<Result<_, _>>()?
+                },
+                _ => return Err(format_err!("Exactly one of time_windows, sweep_windows and frame_windows \
+                                             has to be given")),
+            };
+            let pieces = split_lst(&file_path, &header, &options, &windows, &out_paths)?;
+            Ok(pieces.iter().map(|piece| piece.records).collect())
+        });
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// Writes events to a new list file with the header of the file at
+    /// `template_path`, and returns the number of records. The events are given
+    /// as the input index of each event, counting from 0, and its absolute time
+    /// in bins, in file order. Fields that aren't given are written as 0.
+    #[pyfn(m, "write_lst", edge = "None", sweep = "None", tag = "None", lost = "None")]
+    fn py_write_lst(py: Python, out_path: String, template_path: String, channel: Vec<usize>, time: Vec<u64>,
+                    edge: Option<Vec<bool>>, sweep: Option<Vec<u64>>, tag: Option<Vec<u16>>,
+                    lost: Option<Vec<bool>>) -> PyResult<usize> {
+        if channel.len() != time.len() {
+            return Err(exc::ValueError::new("channel and time must have the same length."));
+        }
+        let result = py.allow_threads(move || -> Result<usize, Error> {
+            let template = FileBuffer::open(&template_path)?;
+            let header = LstHeader::parse(&template)?;
+            let events: Vec<Event> = channel.iter().zip(time.iter()).enumerate()
+                .map(|(idx, (channel, time))| Event {
+                    channel: *channel,
+                    edge: edge.as_ref().and_then(|edge| edge.get(idx)).cloned().unwrap_or(false),
+                    time: *time,
+                    sweep: sweep.as_ref().and_then(|sweep| sweep.get(idx)).cloned().unwrap_or(0),
+                    tag: tag.as_ref().and_then(|tag| tag.get(idx)).cloned().unwrap_or(0),
+                    lost: lost.as_ref().and_then(|lost| lost.get(idx)).cloned().unwrap_or(false),
+                })
+                .collect();
+            write_lst_with_text(&out_path, &header, &template[..header.start_of_data], &events)?;
+            Ok(events.len())
+        });
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// Bin width of the multiscaler in picoseconds, as set in the header of the file.
+    #[pyfn(m, "bin_width")]
+    fn py_bin_width(file_path: String) -> PyResult<f64> {
+        let header = LstHeader::read(&file_path).map_err(|err| exc::ValueError::new(err.to_string()))?;
+        Ok(header.bin_width().picoseconds)
+    }
+
+    /// Integrity report of a list file as a JSON string. Times are followed
+    /// with the given overflow and start settings, like the readers do, but
+    /// without a sweep counter the time counter is unwrapped at backward
+    /// jumps unless `time_overflow` says otherwise. The report names the mode
+    /// that was used.
+    #[pyfn(m, "validate", time_overflow = "None", start_period = "None")]
+    fn py_validate(py: Python, file_path: String, time_overflow: Option<String>,
+                   start_period: Option<u64>) -> PyResult<String> {
+        let options = parse_options(py, &file_path, None, false, false, time_overflow, start_period.is_some(),
+                                    start_period, None, None, None, HashMap::new(), None)?;
+        let result = py.allow_threads(move || {
+            let header = LstHeader::read(&file_path)?;
+            validate(&file_path, &header, &options)?.to_json()
+        });
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// Re-encodes a list file with `timepatch`, or with the most compact
+    /// timepatch that holds its content if it's None. Returns the report as
+    /// JSON; its `target` is null if the file couldn't be transcoded.
+    #[pyfn(m, "transcode_lst", timepatch = "None", time_overflow = "None")]
+    fn py_transcode_lst(py: Python, file_path: String, out_path: String, timepatch: Option<String>,
+                        time_overflow: Option<String>) -> PyResult<String> {
+        let options = parse_options(py, &file_path, None, false, false, time_overflow, false, None, None, None, None,
+                                    HashMap::new(), None)?;
+        let result = py.allow_threads(move || {
+            let header = LstHeader::read(&file_path)?;
+            transcode_lst(&file_path, &header, &options, timepatch.as_ref().map(String::as_str), &out_path)?
+                .to_json()
+        });
+        result.map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    #[pyfn(m, "infer_laser")]
+    fn py_infer_laser(py: Python, photons: Vec<u64>, min_period: f64, max_period: f64)
+        -> PyResult<Option<InferredLaser>> {
+        let params = LaserInference::new(min_period, max_period);
+        let result = py.allow_threads(move || infer_laser(&photons, &params));
+        Ok(result)
+    }
+
+    /// Returns the pulse index and the delay after that pulse of every photon.
+    /// If `laser` is None the pulses are inferred from the photons themselves.
+    #[pyfn(m, "laser_pulse_index")]
+    fn py_laser_pulse_index(py: Python, photons: Vec<u64>, laser: Option<Vec<u64>>,
+                            min_period: f64, max_period: f64) -> PyResult<(Vec<u64>, Vec<f64>)> {
+        let result = py.allow_threads(move || {
+            let pulses = match laser {
+                Some(laser) => Some(LaserPulses::from_channel(&laser)),
+                None => LaserPulses::infer(&photons, &LaserInference::new(min_period, max_period)),
+            };
+            pulses.map(|pulses| pulses.locate_all(&photons))
+        });
+        result.ok_or_else(|| exc::ValueError::new("Couldn't infer the laser timing from the photons."))
+    }
+
+    /// A virtual laser channel, holding the times of all inferred pulses between
+    /// the first and last photons.
+    #[pyfn(m, "virtual_laser_channel")]
+    fn py_virtual_laser_channel(py: Python, photons: Vec<u64>, min_period: f64, max_period: f64)
+        -> PyResult<Vec<u64>> {
+        let result = py.allow_threads(move || {
+            let stop = photons.iter().max().map_or(0, |last| last + 1);
+            let start = photons.iter().min().cloned().unwrap_or(0);
+            LaserPulses::infer(&photons, &LaserInference::new(min_period, max_period))
+                .map(|pulses| pulses.pulses_between(start, stop))
+        });
+        result.ok_or_else(|| exc::ValueError::new("Couldn't infer the laser timing from the photons."))
+    }
+
+    /// Marks the photons arriving inside the fluorescence gate after each laser
+    /// pulse. The laser is inferred from the photons if it wasn't recorded, and
+    /// the gate is chosen automatically if it isn't given.
+    #[pyfn(m, "censor_photons")]
+    fn py_censor_photons(py: Python, photons: Vec<u64>, laser: Option<Vec<u64>>, min_period: f64,
+                         max_period: f64, gate: Option<(f64, f64)>) -> PyResult<(Vec<bool>, CensorStats)> {
+        let result = py.allow_threads(move || {
+            let pulses = match laser {
+                Some(laser) => Some(LaserPulses::from_channel(&laser)),
+                None => LaserPulses::infer(&photons, &LaserInference::new(min_period, max_period)),
+            };
+            pulses.and_then(|pulses| censor_mask(&photons, &pulses,
+                                                 gate.map(|(start, stop)| CensorGate::new(start, stop))))
+        });
+        result.ok_or_else(|| exc::ValueError::new("Couldn't determine the censoring gate."))
+    }
+
+    /// Histogram of the number of photons per laser pulse.
+    #[pyfn(m, "photons_per_pulse")]
+    fn py_photons_per_pulse(py: Python, photons: Vec<u64>, laser: Option<Vec<u64>>, min_period: f64,
+                            max_period: f64, max_photons: usize) -> PyResult<Vec<u64>> {
+        let result = py.allow_threads(move || {
+            let pulses = match laser {
+                Some(laser) => Some(LaserPulses::from_channel(&laser)),
+                None => LaserPulses::infer(&photons, &LaserInference::new(min_period, max_period)),
+            };
+            pulses.map(|pulses| photons_per_pulse(&photons, &pulses, max_photons))
+        });
+        result.ok_or_else(|| exc::ValueError::new("Couldn't infer the laser timing from the photons."))
+    }
+
+    /// Per-pixel histograms of the number of photons per laser pulse, returned as
+    /// one photon-number-resolved image per number of photons.
+    #[pyfn(m, "photons_per_pulse_per_pixel")]
+    fn py_photons_per_pulse_per_pixel(py: Python, photons: Vec<u64>, laser: Option<Vec<u64>>,
+                                      min_period: f64, max_period: f64, max_photons: usize,
+                                      lines: Vec<u64>, frames: Vec<u64>, x_pixels: usize,
+                                      y_pixels: usize, line_duration: f64)
+        -> PyResult<PixelPhotonStats> {
+        let result = py.allow_threads(move || {
+            let pulses = match laser {
+                Some(laser) => Some(LaserPulses::from_channel(&laser)),
+                None => LaserPulses::infer(&photons, &LaserInference::new(min_period, max_period)),
+            };
+            let grid = ScanGrid { lines: &lines, frames: &frames, x_pixels, y_pixels, line_duration };
+            pulses.map(|pulses| photons_per_pulse_per_pixel(&photons, &pulses, &grid, max_photons))
+        });
+        result.ok_or_else(|| exc::ValueError::new("Couldn't infer the laser timing from the photons."))
+    }
+
+    /// Pairs of events of two channels that are within `window` bins of each other.
+    #[pyfn(m, "coincidences")]
+    fn py_coincidences(py: Python, a: Vec<u64>, b: Vec<u64>, window: u64) -> PyResult<Coincidences> {
+        let result = py.allow_threads(move || coincidences(&a, &b, window));
         Ok(result)
     }
+
+    /// Events of the first channel that coincide with an event on each of the others.
+    #[pyfn(m, "coincidences_multi")]
+    fn py_coincidences_multi(py: Python, channels: Vec<Vec<u64>>, window: u64) -> PyResult<Coincidences> {
+        py.allow_threads(move || coincidences_multi(&channels, window))
+            .map_err(|err| exc::ValueError::new(err.to_string()))
+    }
+
+    /// g(2)(τ) histogram between two channels for delays in [-max_lag, max_lag).
+    #[pyfn(m, "g2")]
+    fn py_g2(py: Python, a: Vec<u64>, b: Vec<u64>, bin_width: u64, max_lag: u64) -> PyResult<G2Histogram> {
+        let result = py.allow_threads(move || g2_histogram(&a, &b, bin_width, max_lag));
+        Ok(result)
+    }
+
+    /// Multi-tau correlation of two channels, or the autocorrelation of `a` if `b` is None.
+    #[pyfn(m, "multi_tau", b = "None", base = "1", levels = "16", lags_per_level = "8", segments = "10")]
+    fn py_multi_tau(py: Python, a: Vec<u64>, b: Option<Vec<u64>>, base: u64, levels: usize,
+                    lags_per_level: usize, segments: usize) -> PyResult<MultiTau> {
+        let params = MultiTauParams { base, levels, lags_per_level, segments };
+        let result = py.allow_threads(move || {
+            let b = b.unwrap_or_else(|| a.clone());
+            multi_tau(&a, &b, &params)
+        });
+        Ok(result)
+    }
+
+    /// Count-rate traces of the given channels of a list file on a common time
+    /// axis, with bins of `bin_width` seconds. The times are in bins of the
+    /// multiscaler, whose duration is read from the header of `file_path`.
+    /// Returned as a dict with the start time of every bin and the bin width in
+    /// seconds, and lists of NumPy arrays of counts and of rates in counts per
+    /// second.
+    #[pyfn(m, "count_rates")]
+    fn py_count_rates(py: Python, file_path: String, channels: Vec<Vec<u64>>, bin_width: f64)
+        -> PyResult<PyObject> {
+        let header = LstHeader::read(&file_path).map_err(|err| exc::ValueError::new(err.to_string()))?;
+        let traces = py.allow_threads(move || count_rates(&channels, bin_width, header.bin_width()))
+            .map_err(|err| exc::ValueError::new(err.to_string()))?;
+        let np = PyArrayModule::import(py)?;
+        let bin_starts = traces.first().map(|trace| trace.bin_starts()).unwrap_or_default();
+        let bin_seconds = traces.first().map(|trace| trace.bin_seconds());
+        let rates: Vec<PyObject> = traces.iter()
+            .map(|trace| trace.rates().into_pyarray(py, &np).into_object(py))
+            .collect();
+        let counts: Vec<PyObject> = traces.into_iter()
+            .map(|trace| trace.counts.into_pyarray(py, &np).into_object(py))
+            .collect();
+        let dict = PyDict::new(py);
+        dict.set_item("bin_starts", bin_starts.into_pyarray(py, &np)).expect("Bin starts insertion error.");
+        dict.set_item("bin_width", bin_seconds).expect("Bin width insertion error.");
+        dict.set_item("counts", counts).expect("Counts insertion error.");
+        dict.set_item("rates", rates).expect("Rates insertion error.");
+        Ok(dict.into())
+    }
     Ok(())
 }
 /// Parse binary list files generated by a multiscaler.
 /// Parameters:
 /// fname - str
+/// options - optional processing done while parsing, like the dead-time filter
+/// The bin width and the device are read from the header of the file, if it can
+/// be parsed.
+/// Fails if the file can't be opened, or if inactive inputs had events and
+/// `options.inactive_events` is strict.
 pub fn analyze_lst_u8(fname: &str, start_of_data: usize, range: u64,
-                      timepatch: &str, channel_map: Vec<u8>)
-    -> LstReturnU8 {
+                      timepatch: &str, channel_map: Vec<u8>, options: &ParseOptions)
+    -> Result<LstReturnU8, Error> {
 
     // Open the file and convert it to a usable format
-    let data_with_headers = FileBuffer::open(fname).expect("bad file name");
+    let data_with_headers = FileBuffer::open(fname)?;
     let data = &data_with_headers[start_of_data..];
-    let data_size: usize = (fs::metadata(fname).unwrap().len() - start_of_data as u64) as usize;
-    let chan_map = create_channel_vec_u8(timepatch, channel_map, data_size);
-    let inputs = (data, range, &TimepatchBits::new(timepatch), chan_map);
-
-    let processed_data = match timepatch {
-        "2a" => parse_2a(inputs.0, inputs.1, inputs.2, inputs.3),
-        "22" => parse_22(inputs.0, inputs.1, inputs.2, inputs.3),
-        "3" => parse_3(inputs.0, inputs.1, inputs.2, inputs.3),
-        _ => panic!("Invalid timepatch value: {}", timepatch),
-    };
-    processed_data
+    let header = LstHeader::parse(&data_with_headers).ok();
+    let bin_width = header.as_ref().map(|header| header.bin_width());
+    let options = &resumed(options, header.as_ref());
+    Ok(parse_data_u8(data, range, timepatch, channel_map, options)?.with_units(bin_width, options.time_unit))
 }
 
 /// Parse binary list files generated by a multiscaler.
 /// Parameters:
 /// fname - str
+/// options - optional processing done while parsing, like the dead-time filter
+/// The bin width and the device are read from the header of the file, if it can
+/// be parsed.
+/// Fails if the file can't be opened, or if inactive inputs had events and
+/// `options.inactive_events` is strict.
 pub fn analyze_lst_u16(fname: &str, start_of_data: usize, range: u64,
-                       timepatch: &str, channel_map: Vec<u8>)
-    -> LstReturnU16 {
+                       timepatch: &str, channel_map: Vec<u8>, options: &ParseOptions)
+    -> Result<LstReturnU16, Error> {
 
     // Open the file and convert it to a usable format
-    let data_with_headers = FileBuffer::open(fname).expect("bad file name");
+    let data_with_headers = FileBuffer::open(fname)?;
     let data = &data_with_headers[start_of_data..];
-    let data_size: usize = (fs::metadata(fname).unwrap().len() - start_of_data as u64) as usize;
-    let chan_map = create_channel_vec_u16(timepatch, channel_map, data_size);
-
-    let inputs = (data, range, &TimepatchBits::new(timepatch), chan_map);
-
-    let processed_data = match timepatch {
-        "0" => parse_0(inputs.0, inputs.1, inputs.2, inputs.3),
-        "5" => parse_5(inputs.0, inputs.1, inputs.2, inputs.3),
-        "1" => parse_1(inputs.0, inputs.1, inputs.2, inputs.3),
-        "1a" => parse_1a(inputs.0, inputs.1, inputs.2, inputs.3),
-        "32" => parse_32(inputs.0, inputs.1, inputs.2, inputs.3),
-        "2" => parse_2(inputs.0, inputs.1, inputs.2, inputs.3),
-        "5b" => parse_5b(inputs.0, inputs.1, inputs.2, inputs.3),
-        "Db" => parse_Db(inputs.0, inputs.1, inputs.2, inputs.3),
-        "f3" => parse_f3(inputs.0, inputs.1, inputs.2, inputs.3),
-        "43" => parse_43(inputs.0, inputs.1, inputs.2, inputs.3),
-        "c3" => parse_c3(inputs.0, inputs.1, inputs.2, inputs.3),
-        _ => panic!("Invalid timepatch value: {}", timepatch),
+    let header = LstHeader::parse(&data_with_headers).ok();
+    let bin_width = header.as_ref().map(|header| header.bin_width());
+    let options = &resumed(options, header.as_ref());
+    Ok(parse_data_u16(data, range, timepatch, channel_map, options)?.with_units(bin_width, options.time_unit))
+}
+
+/// Parses consecutive list files of one acquisition into a single timeline.
+/// The files have to share their settings, see `merge_headers`, and the times
+/// of each file are moved by its offset. Sweep numbers keep counting up from
+/// the highest sweep of the files before.
+pub fn merge_lst_u8(fnames: &[String], options: &ParseOptions, offsets: &FileOffsets)
+    -> Result<LstReturnU8, Error> {
+    let headers = merge_headers(fnames)?;
+    check_mergeable(options)?;
+    let mut merged: Option<LstReturnU8> = None;
+    let mut data_offset = 0;
+    for (idx, (fname, header)) in fnames.iter().zip(headers.iter()).enumerate() {
+        let result = analyze_lst_u8(fname, header.start_of_data, header.range, &header.timepatch,
+                                    header.channel_map.clone(), options)?;
+        let offset = offsets.offset(idx, merged.as_ref().and_then(|merged| merged.last_time()))?;
+        let merged = merged.get_or_insert_with(|| {
+            let channels = Channels::from_active(&[], result.channels().id_names(), ParsedDataU8::default());
+            LstReturnU8::from_channels(channels, ParseStats::default())
+                .with_units(result.bin_width(), options.time_unit)
+        });
+        let sweep_offset = merged.last_sweep().unwrap_or(0);
+        merged.append(result, offset, sweep_offset, data_offset);
+        data_offset += merge::data_size(fname, header)?;
+    }
+    merged.ok_or_else(|| format_err!("No files to merge"))
+}
+
+/// Parses consecutive list files of one acquisition into a single timeline.
+/// The files have to share their settings, see `merge_headers`, and the times
+/// of each file are moved by its offset. Sweep numbers keep counting up from
+/// the highest sweep of the files before.
+pub fn merge_lst_u16(fnames: &[String], options: &ParseOptions, offsets: &FileOffsets)
+    -> Result<LstReturnU16, Error> {
+    let headers = merge_headers(fnames)?;
+    check_mergeable(options)?;
+    let mut merged: Option<LstReturnU16> = None;
+    let mut data_offset = 0;
+    for (idx, (fname, header)) in fnames.iter().zip(headers.iter()).enumerate() {
+        let result = analyze_lst_u16(fname, header.start_of_data, header.range, &header.timepatch,
+                                     header.channel_map.clone(), options)?;
+        let offset = offsets.offset(idx, merged.as_ref().and_then(|merged| merged.last_time()))?;
+        let merged = merged.get_or_insert_with(|| {
+            let channels = Channels::from_active(&[], result.channels().id_names(), ParsedDataU16::default());
+            LstReturnU16::from_channels(channels, ParseStats::default())
+                .with_units(result.bin_width(), options.time_unit)
+        });
+        let sweep_offset = merged.last_sweep().unwrap_or(0);
+        merged.append(result, offset, sweep_offset, data_offset);
+        data_offset += merge::data_size(fname, header)?;
+    }
+    merged.ok_or_else(|| format_err!("No files to merge"))
+}
+
+/// Times relative to their sweep restart in every sweep, so they can't be
+/// placed on a common timeline.
+fn check_mergeable(options: &ParseOptions) -> Result<(), Error> {
+    if options.time_mode == TimeMode::SweepRelative {
+        return Err(format_err!("Times relative to their sweep can't be merged"));
+    }
+    Ok(())
+}
+
+/// Like `analyze_lst_u8`, but only returns the events inside `window`. Only the
+/// records around the window are decoded if the file has a current seek index, or
+/// if its timepatch has a sweep counter.
+pub fn analyze_lst_u8_window(fname: &str, start_of_data: usize, range: u64, timepatch: &str,
+                             channel_map: Vec<u8>, options: &ParseOptions, window: Window)
+    -> Result<LstReturnU8, Error> {
+    let data_with_headers = FileBuffer::open(fname)?;
+    let header = LstHeader::parse(&data_with_headers).ok();
+    let bin_width = header.as_ref().map(|header| header.bin_width());
+    let options = resumed(options, header.as_ref());
+    let (data, options) = window_data(fname, &data_with_headers[start_of_data..], range, timepatch, &options, window)?;
+    Ok(parse_data_u8(data, range, timepatch, channel_map, &options)?.with_units(bin_width, options.time_unit))
+}
+
+/// Like `analyze_lst_u16`, but only returns the events inside `window`. Only the
+/// records around the window are decoded if the file has a current seek index, or
+/// if its timepatch has a sweep counter.
+pub fn analyze_lst_u16_window(fname: &str, start_of_data: usize, range: u64, timepatch: &str,
+                              channel_map: Vec<u8>, options: &ParseOptions, window: Window)
+    -> Result<LstReturnU16, Error> {
+    let data_with_headers = FileBuffer::open(fname)?;
+    let header = LstHeader::parse(&data_with_headers).ok();
+    let bin_width = header.as_ref().map(|header| header.bin_width());
+    let options = resumed(options, header.as_ref());
+    let (data, options) = window_data(fname, &data_with_headers[start_of_data..], range, timepatch, &options, window)?;
+    Ok(parse_data_u16(data, range, timepatch, channel_map, &options)?.with_units(bin_width, options.time_unit))
+}
+
+/// `options` with the device of the file and the counters before its first
+/// record that the header carries, e.g. in a piece written by `split_lst`, like
+/// `analyze_lst_times` does.
+fn resumed(options: &ParseOptions, header: Option<&LstHeader>) -> ParseOptions {
+    match header {
+        Some(header) => ParseOptions { device: header.device.clone(), resume: header.resume, ..options.clone() },
+        None => options.clone(),
+    }
+}
+
+/// The part of the data that has to be decoded for `window`, and the options
+/// to parse it with. A seek index of the file is used if it's current and was
+/// built with the same time settings.
+fn window_data<'a>(fname: &str, data: &'a [u8], range: u64, timepatch: &str, options: &ParseOptions,
+                   window: Window) -> Result<(&'a [u8], ParseOptions), Error> {
+    let bit_order = TimepatchBits::new(timepatch, &options.device)?;
+    if let Window::Sweeps { .. } = window {
+        if bit_order[2] == 0 {
+            return Err(format_err!("Timepatch {} has no sweep counter", timepatch));
+        }
+    }
+    let mut options = options.clone();
+    options.window = Some(window);
+    if let Some(index) = SeekIndex::load(fname)? {
+        if index.serves(timepatch, range, &options) {
+            if let Some((slice, state)) = index.slice(&window, data.len()) {
+                options.resume = state;
+                options.data_offset = slice.start;
+                return Ok((&data[slice], options));
+            }
+        }
+    }
+    // counting starts needs all the records before the window
+    if options.start_clock != StartClock::Disabled {
+        return Ok((data, options));
+    }
+    match window_slice(data, bytes_per_record(timepatch)?, range, &bit_order, &options, &window) {
+        Some((slice, state)) => {
+            options.resume = state;
+            options.data_offset = slice.start;
+            Ok((&data[slice], options))
+        },
+        None => Ok((data, options)),
+    }
+}
+
+/// Parses the data with the parser of `timepatch`, and applies the policy for
+/// events of inactive inputs.
+fn parse_data_u8(data: &[u8], range: u64, timepatch: &str, channel_map: Vec<u8>,
+                 options: &ParseOptions) -> Result<LstReturnU8, Error> {
+    let bit_order = TimepatchBits::new(timepatch, &options.device)?;
+    let chan_map = create_channels_u8(timepatch, options.filter_channel_map(channel_map),
+                                      &options.channel_names(), data.len())?;
+    let inputs = (data, range, &bit_order, chan_map);
+
+    let result = match timepatch {
+        "2a" => parse_2a(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "22" => parse_22(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "3" => parse_3(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        _ => return Err(format_err!("Unknown timepatch {}", timepatch)),
     };
-    processed_data
+    result.check_inactive(options.inactive_events)?;
+    Ok(result)
+}
+
+fn parse_data_u16(data: &[u8], range: u64, timepatch: &str, channel_map: Vec<u8>,
+                  options: &ParseOptions) -> Result<LstReturnU16, Error> {
+    let bit_order = TimepatchBits::new(timepatch, &options.device)?;
+    let chan_map = create_channels_u16(timepatch, options.filter_channel_map(channel_map),
+                                       &options.channel_names(), data.len())?;
+    let inputs = (data, range, &bit_order, chan_map);
+
+    let result = match timepatch {
+        "0" => parse_0(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "5" => parse_5(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "1" => parse_1(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "1a" => parse_1a(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "32" => parse_32(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "2" => parse_2(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "5b" => parse_5b(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "Db" => parse_Db(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "f3" => parse_f3(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "43" => parse_43(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        "c3" => parse_c3(inputs.0, inputs.1, inputs.2, inputs.3, options),
+        _ => return Err(format_err!("Unknown timepatch {}", timepatch)),
+    };
+    result.check_inactive(options.inactive_events)?;
+    Ok(result)
+}
+
+/// Builds the parsing options from the keyword arguments of the Python readers.
+/// `time_overflow` is None, "jumps" or "range", `start_period` falls back to the
+/// range when starts are counted, `time_unit` is None, "bins", "ps" or "s", and
+/// `edges` holds "rising", "falling", "both" or "off" for each channel, and
+/// `device` is the name of the multiscaler, or None to take it from the header of
+/// `file_path`. If the header can't be read that falls back to the MCS6A with a
+/// warning, and a device that differs from the header's raises. `roles` renames inputs
+/// of the device, and `inactive` is None, "strict", "lenient" or "drop".
+fn parse_options(py: Python, file_path: &str, dead_time: Option<Vec<u64>>, sweep: bool, sweep_relative: bool,
+                 time_overflow: Option<String>, count_starts: bool, start_period: Option<u64>,
+                 time_unit: Option<String>, edges: Option<Vec<String>>, device: Option<String>,
+                 roles: HashMap<String, String>, inactive: Option<String>) -> PyResult<ParseOptions> {
+    let time_overflow = match time_overflow.as_ref().map(|name| name.as_str()) {
+        None => TimeOverflow::Disabled,
+        Some("jumps") => TimeOverflow::BackwardJumps,
+        Some("range") => TimeOverflow::Range,
+        Some(other) => return Err(exc::ValueError::new(format!("Unknown time overflow mode: {}", other))),
+    };
+    let start_clock = match (count_starts, start_period) {
+        (false, _) => StartClock::Disabled,
+        (true, Some(period)) => StartClock::Period(period),
+        (true, None) => StartClock::Range,
+    };
+    let time_unit = match time_unit {
+        None => TimeUnit::Bins,
+        Some(name) => TimeUnit::from_name(&name)
+            .ok_or_else(|| exc::ValueError::new(format!("Unknown time unit: {}", name)))?,
+    };
+    let channel_filter = edges.unwrap_or_default().iter()
+        .map(|name| ChannelFilter::from_name(name)
+            .ok_or_else(|| exc::ValueError::new(format!("Unknown edge filter: {}", name))))
+        .collect::<PyResult<Vec<ChannelFilter>>>()?;
+    let header_device = LstHeader::read(file_path).map(|header| header.device);
+    let device = match (device, header_device) {
+        (Some(name), header_device) => {
+            let device = Device::from_name(&name)
+                .ok_or_else(|| exc::ValueError::new(format!("Unknown device: {}", name)))?;
+            if let Ok(header_device) = header_device {
+                if header_device != device {
+                    return Err(exc::ValueError::new(format!("The header of {} was written by the {}, not the {}",
+                                                            file_path, header_device.name, device.name)));
+                }
+            }
+            device
+        },
+        (None, Ok(device)) => device,
+        (None, Err(err)) => {
+            let msg = format!("Can't read the device from the header ({}), assuming the MCS6A", err);
+            PyErr::warn(py, py.import("builtins")?.get("UserWarning")?, &msg, 1)?;
+            Device::mcs6a()
+        },
+    };
+    role_names(&device, &roles).map_err(|err| exc::ValueError::new(err.to_string()))?;
+    let inactive_events = match inactive {
+        None => InactivePolicy::Drop,
+        Some(name) => InactivePolicy::from_name(&name)
+            .ok_or_else(|| exc::ValueError::new(format!("Unknown inactive channel policy: {}", name)))?,
+    };
+    Ok(ParseOptions {
+        dead_time: dead_time.unwrap_or_default(),
+        sweep_output: sweep,
+        time_mode: if sweep_relative { TimeMode::SweepRelative } else { TimeMode::Absolute },
+        time_overflow,
+        start_clock,
+        time_unit,
+        channel_filter,
+        device,
+        roles,
+        inactive_events,
+        ..ParseOptions::default()
+    })
+}
+
+/// The roles of the Python readers, a dict from input names like "stop1" to the
+/// names they should have in the output, like "pmt1".
+fn roles_from_dict(roles: Option<&PyDict>) -> PyResult<HashMap<String, String>> {
+    let mut parsed = HashMap::new();
+    if let Some(roles) = roles {
+        for (input, role) in roles.iter() {
+            parsed.insert(input.extract::<String>()?, role.extract::<String>()?);
+        }
+    }
+    Ok(parsed)
+}
+
+/// The window of the Python readers, given as a (start, stop) pair of absolute
+/// times or of sweep numbers.
+fn window_from_args(time_window: Option<(u64, u64)>, sweep_window: Option<(u64, u64)>)
+    -> PyResult<Option<Window>> {
+    match (time_window, sweep_window) {
+        (Some(_), Some(_)) => Err(exc::ValueError::new("Only one of time_window and sweep_window can be given.")),
+        (Some((start, stop)), None) => Ok(Some(Window::Time { start, stop })),
+        (None, Some((first, stop))) => Ok(Some(Window::Sweeps { first, stop })),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Parses a list file using the settings in its header, and returns the times
+/// of each input of its device, in the order of their channel codes. Inactive
+/// inputs have no times.
+pub fn analyze_lst_times(fname: &str, header: &LstHeader, options: &ParseOptions)
+    -> Result<Vec<Vec<u64>>, Error> {
+    let channel_map = header.channel_map.clone();
+    let options = &ParseOptions { device: header.device.clone(), ..options.clone() };
+    match header.timepatch.as_str() {
+        "2a" | "22" | "3" => {
+            let result = analyze_lst_u8(fname, header.start_of_data, header.range,
+                                        &header.timepatch, channel_map, options)?;
+            Ok((0..header.device.num_of_channels())
+                .map(|id| result.channel(id).map_or_else(Vec::new, |channel| channel.time.clone()))
+                .collect())
+        },
+        _ => {
+            let result = analyze_lst_u16(fname, header.start_of_data, header.range,
+                                         &header.timepatch, channel_map, options)?;
+            Ok((0..header.device.num_of_channels())
+                .map(|id| result.channel(id).map_or_else(Vec::new, |channel| channel.time.clone()))
+                .collect())
+        },
+    }
+}
+
+/// The times of the events of each input of consecutive files merged into one
+/// timeline, see `merge_lst_u16`. Inactive inputs have no events.
+pub fn merge_lst_times(fnames: &[String], options: &ParseOptions, offsets: &FileOffsets)
+    -> Result<Vec<Vec<u64>>, Error> {
+    let headers = merge_headers(fnames)?;
+    let device = &headers[0].device;
+    let options = &ParseOptions { device: device.clone(), ..options.clone() };
+    match headers[0].timepatch.as_str() {
+        "2a" | "22" | "3" => {
+            let result = merge_lst_u8(fnames, options, offsets)?;
+            Ok((0..device.num_of_channels())
+                .map(|id| result.channel(id).map_or_else(Vec::new, |channel| channel.time.clone()))
+                .collect())
+        },
+        _ => {
+            let result = merge_lst_u16(fnames, options, offsets)?;
+            Ok((0..device.num_of_channels())
+                .map(|id| result.channel(id).map_or_else(Vec::new, |channel| channel.time.clone()))
+                .collect())
+        },
+    }
 }
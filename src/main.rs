@@ -1,22 +1,345 @@
-// #![feature(uniform_paths)]
-// use rread_lst;
-
-// use std::collections::HashMap;
-
-// fn main() {
-//     let mut starts_map = HashMap::new();
-//     starts_map.insert("trial1.lst", 2usize);
-//     starts_map.insert("4p5mW_850g_008.lst", 1565usize); // channels - 1, 6
-//     starts_map.insert("Mouse_LPT_189kHz_62p_Penetrating_arteries_FOV2_20x_Zoom_512lines_512px_400um_higher_than_Nominal_depth_800nm_039.lst",
-//                       1565usize); // channels - 2, 6
-//     let fname = "4-byte006.lst";
-//     let start_of_data = starts_map[fname];
-//     let range = 512u64;
-//     let timepatch = "5";
-//     let channel_map = vec![0, 0, 0, 0, 0, 1];
-//     let res = rread_lst::analyze_lst_u16(fname, start_of_data, range, timepatch, channel_map);
-//     println!("{:?}", res);
-//     // println!("{:?}", res[&0]);
-// }
-
-fn main() {}
\ No newline at end of file
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::str::FromStr;
+
+use failure::{Error, format_err};
+
+use libps::binary_parsing::*;
+use libps::correlation::*;
+use libps::device::*;
+use libps::header::*;
+use libps::index::*;
+use libps::merge::*;
+use libps::split::*;
+use libps::transcode::*;
+use libps::rates::*;
+use libps::validate::*;
+use libps::window::*;
+use libps::{analyze_lst_times, merge_lst_times};
+
+const USAGE: &str = "Usage: lst <command> <file.lst> [--option value ...]
+
+Commands:
+    coincidence    Events coinciding on a set of channels
+                   --channels stop1,stop2  --window <bins>  [--pairs]
+    g2             g(2) histogram between two channels
+                   --channels stop1,stop2  --bin-width <bins>  --max-lag <bins>
+    rate           Count rate of each channel over the acquisition
+                   --bin-width <seconds>  [--channels stop1,stop2]
+    index          Build the seek index used by the windowed readers
+                   [--every <records>]  [--check]
+    merge          Merge consecutive files into one timeline and write its
+                   events as CSV, ordered by time
+                   <file.lst> ...  [--gap <bins> | --starts <bins>,<bins>,...]
+                   [--output <file.csv>]
+    split          Write the records of each window to a new list file
+                   --times <start>:<stop>,...  |  --sweeps <first>:<stop>,...  |
+                   --frames <first>:<stop>,... --frame-channel <name>
+                   [--prefix <path>]
+    transcode      Re-encode the file with the most compact timepatch that
+                   holds its content and print a JSON report, failing if
+                   nothing smaller fits
+                   [--timepatch <tp>]  [--output <file.lst>]
+    validate       Check the integrity of the file and print a JSON report,
+                   failing if the file looks damaged
+
+Options of the parsing commands:
+    --inactive strict|lenient|drop    Events of inputs marked inactive in the
+                                      header fail, are kept or are dropped
+    --time-overflow jumps|range       Unwrap the time counter of timepatches
+                                      without a sweep counter";
+
+/// Positional arguments and `--key value` options of a command. Options that
+/// aren't followed by a value are stored as flags with an empty value.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Self {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut iter = args.iter().peekable();
+        while let Some(arg) = iter.next() {
+            if let Some(key) = arg.strip_prefix("--") {
+                let value = match iter.peek() {
+                    Some(next) if !next.starts_with("--") => iter.next().unwrap().clone(),
+                    _ => String::new(),
+                };
+                options.insert(key.to_string(), value);
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Args { positional, options }
+    }
+
+    fn file(&self) -> Result<&str, Error> {
+        self.positional.first().map(|fname| fname.as_str()).ok_or_else(|| format_err!("No file was given"))
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.options.contains_key(key)
+    }
+
+    fn get<T: FromStr>(&self, key: &str) -> Result<T, Error> {
+        let value = self.options.get(key).ok_or_else(|| format_err!("Missing --{}", key))?;
+        value.parse().map_err(|_| format_err!("Invalid value for --{}: {}", key, value))
+    }
+
+    /// Parsing options set on the command line.
+    fn parse_options(&self) -> Result<ParseOptions, Error> {
+        let mut options = ParseOptions::default();
+        if let Some(name) = self.options.get("inactive") {
+            options.inactive_events = InactivePolicy::from_name(name)
+                .ok_or_else(|| format_err!("Invalid value for --inactive: {}", name))?;
+        }
+        if let Some(name) = self.options.get("time-overflow") {
+            options.time_overflow = match name.as_str() {
+                "jumps" => TimeOverflow::BackwardJumps,
+                "range" => TimeOverflow::Range,
+                _ => return Err(format_err!("Invalid value for --time-overflow: {}", name)),
+            };
+        }
+        Ok(options)
+    }
+
+    /// The comma-separated `start:stop` pairs of option `key`.
+    fn ranges<T: FromStr>(&self, key: &str) -> Result<Vec<(T, T)>, Error> {
+        let ranges: String = self.get(key)?;
+        ranges.split(',')
+            .map(|range| {
+                let mut bounds = range.trim().splitn(2, ':').map(|bound| bound.parse::<T>().ok());
+                match (bounds.next(), bounds.next()) {
+                    (Some(Some(start)), Some(Some(stop))) => Ok((start, stop)),
+                    _ => Err(format_err!("Invalid range for --{}: {}", key, range)),
+                }
+            })
+            .collect()
+    }
+
+    /// Indices of the comma-separated channel names of option `key`, looked up
+    /// among the inputs of `device`.
+    fn channels(&self, key: &str, device: &Device) -> Result<Vec<usize>, Error> {
+        let names: String = self.get(key)?;
+        names.split(',')
+            .map(|name| device.channel_index(name.trim()).ok_or_else(|| format_err!("Unknown channel: {}", name)))
+            .collect()
+    }
+}
+
+/// Prints the coincidences between the given channels as CSV.
+fn coincidence(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let header = LstHeader::read(fname)?;
+    let channels = args.channels("channels", &header.device)?;
+    let window: u64 = args.get("window")?;
+    let times = analyze_lst_times(fname, &header, &args.parse_options()?)?;
+    let selected: Vec<Vec<u64>> = channels.iter().map(|chan| times[*chan].clone()).collect();
+    let result = match selected.len() {
+        2 => coincidences(&selected[0], &selected[1], window),
+        _ => coincidences_multi(&selected, window)?,
+    };
+    println!("coincidences,accidentals");
+    println!("{},{}", result.count, result.accidentals);
+    if args.flag("pairs") {
+        let names: Vec<&str> = channels.iter().map(|chan| header.device.channel_names[*chan].as_str()).collect();
+        println!("{}", names.join(","));
+        for indices in result.indices.iter() {
+            let indices: Vec<String> = indices.iter().map(|idx| idx.to_string()).collect();
+            println!("{}", indices.join(","));
+        }
+    }
+    Ok(())
+}
+
+/// Prints the g(2) histogram between two channels as CSV.
+fn g2(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let header = LstHeader::read(fname)?;
+    let channels = args.channels("channels", &header.device)?;
+    if channels.len() != 2 {
+        return Err(format_err!("g2 needs exactly two channels"));
+    }
+    let bin_width: u64 = args.get("bin-width")?;
+    let max_lag: u64 = args.get("max-lag")?;
+    let times = analyze_lst_times(fname, &header, &args.parse_options()?)?;
+    let result = g2_histogram(&times[channels[0]], &times[channels[1]], bin_width, max_lag);
+    println!("lag,counts,g2");
+    for ((lag, count), g2) in result.lags.iter().zip(result.counts.iter()).zip(result.g2.iter()) {
+        println!("{},{},{}", lag, count, g2);
+    }
+    Ok(())
+}
+
+/// Prints the count-rate traces of the given channels, or of all active
+/// channels, as CSV with one row per bin, starting with the start of the bin in
+/// seconds, followed by the rate of each channel in counts per second.
+fn rate(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let header = LstHeader::read(fname)?;
+    let channels = if args.flag("channels") {
+        args.channels("channels", &header.device)?
+    } else {
+        (0..header.channel_map.len()).filter(|chan| header.channel_map[*chan] == 1).collect()
+    };
+    let bin_width: f64 = args.get("bin-width")?;
+    let times = analyze_lst_times(fname, &header, &args.parse_options()?)?;
+    let selected: Vec<Vec<u64>> = channels.iter().map(|chan| times[*chan].clone()).collect();
+    let traces = count_rates(&selected, bin_width, header.bin_width())?;
+    let rates: Vec<Vec<f64>> = traces.iter().map(|trace| trace.rates()).collect();
+    let names: Vec<&str> = channels.iter().map(|chan| header.device.channel_names[*chan].as_str()).collect();
+    println!("time_s,{}", names.join(","));
+    if let Some(first) = traces.first() {
+        for (idx, time) in first.bin_starts().iter().enumerate() {
+            let rates: Vec<String> = rates.iter().map(|rates| rates[idx].to_string()).collect();
+            println!("{},{}", time, rates.join(","));
+        }
+    }
+    Ok(())
+}
+
+/// Builds the sidecar seek index of a file, or with `--check` reports whether
+/// its index is missing, stale or current.
+fn index(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let path = SeekIndex::path_for(fname);
+    if args.flag("check") {
+        let status = match SeekIndex::load(fname) {
+            Ok(Some(_)) => "current",
+            Ok(None) if std::path::Path::new(&path).exists() => "stale",
+            Ok(None) => "missing",
+            Err(err) => return Err(err),
+        };
+        println!("{}", status);
+        return Ok(());
+    }
+    let header = LstHeader::read(fname)?;
+    let every = if args.flag("every") { args.get("every")? } else { CHECKPOINT_RECORDS };
+    let index = SeekIndex::build(fname, &header, &args.parse_options()?, every)?;
+    index.write(&path)?;
+    println!("Wrote {} checkpoints to {}", index.checkpoints.len(), path);
+    Ok(())
+}
+
+/// Writes the events of consecutive files merged into one timeline as CSV, to
+/// `--output` or to stdout.
+fn merge(args: &Args) -> Result<(), Error> {
+    let header = LstHeader::read(args.file()?)?;
+    let offsets = if args.flag("starts") {
+        let starts: String = args.get("starts")?;
+        let starts = starts.split(',')
+            .map(|start| start.trim().parse().map_err(|_| format_err!("Invalid start time: {}", start)))
+            .collect::<Result<Vec<u64>, Error>>()?;
+        FileOffsets::Starts(starts)
+    } else if args.flag("gap") {
+        FileOffsets::Gap(args.get("gap")?)
+    } else {
+        FileOffsets::default()
+    };
+    let times = merge_lst_times(&args.positional, &args.parse_options()?, &offsets)?;
+    let mut events: Vec<(u64, usize)> = times.iter().enumerate()
+        .flat_map(|(chan, times)| times.iter().map(move |time| (*time, chan)))
+        .collect();
+    events.sort();
+    let mut out: Box<dyn Write> = match args.options.get("output") {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    writeln!(out, "channel,time")?;
+    for (time, chan) in events {
+        writeln!(out, "{},{}", header.device.channel_names[chan], time)?;
+    }
+    Ok(())
+}
+
+/// Writes the records of each time, sweep or frame window to its own list file,
+/// named after `--prefix` or the original file and the number of the window.
+fn split(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let header = LstHeader::read(fname)?;
+    let options = args.parse_options()?;
+    let windows: Vec<Window> = if args.flag("times") {
+        args.ranges("times")?.into_iter().map(|(start, stop)| Window::Time { start, stop }).collect()
+    } else if args.flag("sweeps") {
+        args.ranges("sweeps")?.into_iter().map(|(first, stop)| Window::Sweeps { first, stop }).collect()
+    } else if args.flag("frames") {
+        let channel = args.channels("frame-channel", &header.device)?;
+        let times = analyze_lst_times(fname, &header, &options)?;
+        let frame_times = &times[*channel.first().ok_or_else(|| format_err!("Missing --frame-channel"))?];
+        args.ranges("frames")?.into_iter()
+            .map(|(first, stop)| frame_window(frame_times, first, stop))
+            .collect::<Result<_, _>>()?
+    } else {
+        return Err(format_err!("One of --times, --sweeps and --frames is needed"));
+    };
+    let prefix = match args.options.get("prefix") {
+        Some(prefix) => prefix.clone(),
+        None => fname.trim_end_matches(".lst").to_string(),
+    };
+    let paths: Vec<String> = (1..=windows.len()).map(|idx| format!("{}_{}.lst", prefix, idx)).collect();
+    for piece in split_lst(fname, &header, &options, &windows, &paths)? {
+        println!("Wrote {} records to {}", piece.records, piece.path);
+    }
+    Ok(())
+}
+
+/// Prints the integrity report of a file as JSON, and fails if the file didn't
+/// pass, so scripts can gate on the exit status.
+fn validate_file(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let header = LstHeader::read(fname)?;
+    let report = validate(fname, &header, &args.parse_options()?)?;
+    println!("{}", report.to_json()?);
+    if !report.passed {
+        return Err(format_err!("{} didn't pass validation", fname));
+    }
+    Ok(())
+}
+
+/// Writes the file with a more compact timepatch to `--output`, by default
+/// next to the original, and prints the report as JSON. Fails if no timepatch
+/// was found that holds the content.
+fn transcode(args: &Args) -> Result<(), Error> {
+    let fname = args.file()?;
+    let header = LstHeader::read(fname)?;
+    let out_path = match args.options.get("output") {
+        Some(path) => path.clone(),
+        None => format!("{}_compact.lst", fname.trim_end_matches(".lst")),
+    };
+    let timepatch = args.options.get("timepatch").map(String::as_str);
+    let report = transcode_lst(fname, &header, &args.parse_options()?, timepatch, &out_path)?;
+    println!("{}", report.to_json()?);
+    if report.target.is_none() {
+        return Err(format_err!("{} wasn't transcoded, see the rejected timepatches", fname));
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = args.first().cloned().unwrap_or_default();
+    let parsed = Args::parse(args.get(1..).unwrap_or(&[]));
+    let result = match command.as_str() {
+        "coincidence" => coincidence(&parsed),
+        "g2" => g2(&parsed),
+        "rate" => rate(&parsed),
+        "index" => index(&parsed),
+        "merge" => merge(&parsed),
+        "split" => split(&parsed),
+        "transcode" => transcode(&parsed),
+        "validate" => validate_file(&parsed),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,138 @@
+use std::fs;
+
+use failure::{Error, format_err};
+
+use crate::header::LstHeader;
+
+/// Where the times of each file of a merged acquisition start.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileOffsets {
+    /// Each file starts `gap` bins after the last event of the files before it
+    Gap(u64),
+    /// Start time of each file in bins, one entry per file. The first file
+    /// usually starts at 0.
+    Starts(Vec<u64>),
+}
+
+impl Default for FileOffsets {
+    fn default() -> Self {
+        FileOffsets::Gap(0)
+    }
+}
+
+impl FileOffsets {
+    /// Offset of the times of file `idx`, given the latest time of the files
+    /// merged so far. Fails if the file would start before that time.
+    pub fn offset(&self, idx: usize, merged_until: Option<u64>) -> Result<u64, Error> {
+        match self {
+            FileOffsets::Gap(gap) => Ok(merged_until.map_or(0, |last| last + gap)),
+            FileOffsets::Starts(starts) => {
+                let start = *starts.get(idx).ok_or_else(|| format_err!("No start time for file {}", idx + 1))?;
+                match merged_until {
+                    Some(last) if start <= last => Err(format_err!(
+                        "File {} would start at {}, before the last event of the previous files at {}",
+                        idx + 1, start, last)),
+                    _ => Ok(start),
+                }
+            },
+        }
+    }
+}
+
+/// Reads the headers of files that are merged into one timeline, in their
+/// order. Fails unless they were acquired with the same device, timepatch,
+/// range, active inputs and bin width.
+pub fn merge_headers(fnames: &[String]) -> Result<Vec<LstHeader>, Error> {
+    let (first_name, _) = fnames.split_first().ok_or_else(|| format_err!("No files to merge"))?;
+    let headers = fnames.iter().map(|fname| LstHeader::read(fname)).collect::<Result<Vec<_>, _>>()?;
+    let first = &headers[0];
+    for (fname, header) in fnames.iter().zip(headers.iter()).skip(1) {
+        let mismatch = if header.device.name != first.device.name {
+            Some(format!("device {} instead of {}", header.device.name, first.device.name))
+        } else if header.timepatch != first.timepatch {
+            Some(format!("timepatch {} instead of {}", header.timepatch, first.timepatch))
+        } else if header.range != first.range {
+            Some(format!("range {} instead of {}", header.range, first.range))
+        } else if header.channel_map != first.channel_map {
+            Some(format!("active inputs {:?} instead of {:?}", header.channel_map, first.channel_map))
+        } else if header.bin_width() != first.bin_width() {
+            Some(format!("bin width {} ps instead of {} ps", header.bin_width().picoseconds,
+                         first.bin_width().picoseconds))
+        } else { None };
+        if let Some(mismatch) = mismatch {
+            return Err(format_err!("{} doesn't match {}: {}", fname, first_name, mismatch));
+        }
+    }
+    Ok(headers)
+}
+
+/// Size of the data section of a file, so that the skipped ranges of merged
+/// files can be placed after the data of the files before them.
+pub fn data_size(fname: &str, header: &LstHeader) -> Result<usize, Error> {
+    Ok(fs::metadata(fname)?.len() as usize - header.start_of_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::binary_parsing::*;
+    use crate::device::Device;
+    use crate::merge_lst_u16;
+    use crate::writer::write_lst;
+
+    /// Writes a file of the MCS6A with a stop1 event at each of `times`.
+    fn write_file(name: &str, timepatch: &str, range: u64, times: &[u64]) -> String {
+        let header = LstHeader { start_of_data: 0, range, timepatch: timepatch.to_string(), device: Device::mcs6a(),
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let events: Vec<Event> = times.iter().map(|time| Event { time: *time, ..Event::default() }).collect();
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        write_lst(&path, &header, &events).unwrap();
+        path
+    }
+
+    #[test]
+    fn offsets_of_the_files() {
+        assert_eq!(FileOffsets::default().offset(0, None).unwrap(), 0);
+        assert_eq!(FileOffsets::Gap(10).offset(1, Some(95)).unwrap(), 105);
+        let starts = FileOffsets::Starts(vec![0, 100]);
+        assert_eq!(starts.offset(1, Some(99)).unwrap(), 100);
+        assert!(starts.offset(1, Some(100)).is_err());
+        assert_eq!(starts.offset(2, Some(0)).unwrap_err().to_string(), "No start time for file 3");
+    }
+
+    #[test]
+    fn merged_times_follow_each_other() {
+        let first = write_file("libps_merge_first.lst", "1", 0, &[10, 20, 30]);
+        let second = write_file("libps_merge_second.lst", "1", 0, &[5, 15]);
+        let fnames = vec![first, second];
+        let options = ParseOptions::default();
+        let merged = merge_lst_u16(&fnames, &options, &FileOffsets::Gap(100)).unwrap();
+        assert_eq!(merged.channel(0).unwrap().time, vec![10, 20, 30, 135, 145]);
+        let merged = merge_lst_u16(&fnames, &options, &FileOffsets::Starts(vec![0, 1000])).unwrap();
+        assert_eq!(merged.channel(0).unwrap().time, vec![10, 20, 30, 1005, 1015]);
+        assert!(merge_lst_u16(&fnames, &options, &FileOffsets::Starts(vec![0, 20])).is_err());
+    }
+
+    #[test]
+    fn mismatched_headers_are_refused() {
+        let first = write_file("libps_merge_base.lst", "1", 0, &[10]);
+        let timepatch = write_file("libps_merge_timepatch.lst", "5", 0, &[10]);
+        let range = write_file("libps_merge_range.lst", "1", 500, &[10]);
+        assert_eq!(merge_headers(&[first.clone(), first.clone()]).unwrap().len(), 2);
+        let err = merge_headers(&[first.clone(), timepatch.clone()]).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} doesn't match {}: timepatch 5 instead of 1", timepatch, first));
+        let err = merge_headers(&[first.clone(), range]).unwrap_err();
+        assert!(err.to_string().ends_with("range 500 instead of 0"), "{}", err);
+        assert!(merge_lst_u16(&[first, timepatch], &ParseOptions::default(), &FileOffsets::default()).is_err());
+    }
+
+    #[test]
+    fn no_files_to_merge() {
+        assert_eq!(merge_headers(&[]).unwrap_err().to_string(), "No files to merge");
+        let err = merge_lst_u16(&[], &ParseOptions::default(), &FileOffsets::default()).unwrap_err();
+        assert_eq!(err.to_string(), "No files to merge");
+    }
+}
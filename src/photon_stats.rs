@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use pyo3::prelude::*;
+use pyo3::{IntoPyObject, PyObject, PyDict};
+
+use crate::laser::*;
+
+/// Geometry of a raster scan, used to assign events to image pixels.
+/// `lines` and `frames` hold the sorted start times of each scanned line and
+/// frame. An empty `frames` slice means that all lines belong to one frame.
+#[derive(Debug, Clone)]
+pub struct ScanGrid<'a> {
+    pub lines: &'a [u64],
+    pub frames: &'a [u64],
+    pub x_pixels: usize,
+    pub y_pixels: usize,
+    /// Duration of the imaged part of a single line, in bins
+    pub line_duration: f64,
+}
+
+/// Index of the last element of `sorted` that is not larger than `time`.
+fn last_at_or_before(sorted: &[u64], time: u64) -> Option<usize> {
+    match sorted.binary_search(&time) {
+        Ok(mut idx) => {
+            while idx + 1 < sorted.len() && sorted[idx + 1] == time { idx += 1 };
+            Some(idx)
+        },
+        Err(0) => None,
+        Err(idx) => Some(idx - 1),
+    }
+}
+
+impl<'a> ScanGrid<'a> {
+    /// Index of the first line of the frame holding line number `line`.
+    fn frame_first_line(&self, line: usize) -> usize {
+        match last_at_or_before(self.frames, self.lines[line]) {
+            Some(frame) => match self.lines.binary_search(&self.frames[frame]) {
+                Ok(idx) | Err(idx) => idx,
+            },
+            None => 0,
+        }
+    }
+
+    /// Row of the image scanned by line number `line`, if it's inside the image.
+    fn row_of_line(&self, line: usize) -> Option<usize> {
+        let row = line - self.frame_first_line(line);
+        if row < self.y_pixels { Some(row) } else { None }
+    }
+
+    /// Flat index, `row * x_pixels + column`, of the pixel in which `time` falls.
+    pub fn pixel_of(&self, time: u64) -> Option<usize> {
+        let line = last_at_or_before(self.lines, time)?;
+        let row = self.row_of_line(line)?;
+        let col = ((time - self.lines[line]) as f64 / self.line_duration * self.x_pixels as f64) as usize;
+        if col < self.x_pixels { Some(row * self.x_pixels + col) } else { None }
+    }
+}
+
+/// Distribution of the number of photons detected per laser pulse in every pixel.
+/// `counts` is laid out as `[row][column][photons]`, where the last entry of each
+/// histogram also holds the pulses with more than `max_photons - 1` photons.
+#[derive(Debug, Clone)]
+pub struct PixelPhotonStats {
+    pub x_pixels: usize,
+    pub y_pixels: usize,
+    pub max_photons: usize,
+    pub counts: Vec<u64>,
+}
+
+impl PixelPhotonStats {
+    /// The photon-number-resolved image for `photons` photons per pulse, i.e. the
+    /// number of pulses in each pixel in which exactly that number was detected.
+    pub fn image(&self, photons: usize) -> Vec<u64> {
+        self.counts.iter().skip(photons).step_by(self.max_photons).cloned().collect()
+    }
+
+    /// Sums the histograms of all pixels.
+    pub fn total(&self) -> Vec<u64> {
+        (0..self.max_photons).map(|num| self.image(num).iter().sum()).collect()
+    }
+}
+
+/// Number of laser pulses that occurred before `time`.
+fn pulses_before(laser: &LaserPulses, time: u64) -> u64 {
+    match laser.locate(time) {
+        Some((pulse, delay)) if delay > 0.0 => pulse + 1,
+        Some((pulse, _)) => pulse,
+        None => 0,
+    }
+}
+
+/// First photon and number of photons detected in each of the pulses that had any.
+fn photons_by_pulse(photons: &[u64], laser: &LaserPulses) -> HashMap<u64, (u64, u64)> {
+    let mut by_pulse: HashMap<u64, (u64, u64)> = HashMap::new();
+    for photon in photons {
+        if let Some((pulse, _)) = laser.locate(*photon) {
+            by_pulse.entry(pulse).or_insert((*photon, 0)).1 += 1;
+        }
+    }
+    by_pulse
+}
+
+/// Histogram of the number of photons per laser pulse, for all pulses between the
+/// first and last photon. Entry `n` holds the number of pulses with `n` photons, and
+/// the last entry also holds the pulses with more photons than that.
+pub fn photons_per_pulse(photons: &[u64], laser: &LaserPulses, max_photons: usize) -> Vec<u64> {
+    let max_photons = max_photons.max(1);
+    let mut hist = vec![0u64; max_photons];
+    let by_pulse = photons_by_pulse(photons, laser);
+    let first = by_pulse.keys().min();
+    let last = by_pulse.keys().max();
+    if let (Some(first), Some(last)) = (first, last) {
+        hist[0] = (last - first + 1) - by_pulse.len() as u64;
+    }
+    for (_, count) in by_pulse.values() {
+        hist[(*count as usize).min(max_photons - 1)] += 1;
+    }
+    hist
+}
+
+/// Per-pixel version of `photons_per_pulse`. Each pulse is assigned to the pixel
+/// of its first photon, and the empty pulses of every pixel are the ones that
+/// occurred while the pixel was scanned and had no photons.
+pub fn photons_per_pulse_per_pixel(photons: &[u64], laser: &LaserPulses, grid: &ScanGrid,
+                                   max_photons: usize) -> PixelPhotonStats {
+    let max_photons = max_photons.max(1);
+    let num_of_pixels = grid.x_pixels * grid.y_pixels;
+    let mut counts = vec![0u64; num_of_pixels * max_photons];
+    let mut pulses_in_pixel = vec![0u64; num_of_pixels];
+
+    // all pulses that occurred while each pixel was scanned
+    let dwell = grid.line_duration / grid.x_pixels as f64;
+    for line in 0..grid.lines.len() {
+        let row = match grid.row_of_line(line) {
+            Some(row) => row,
+            None => continue,
+        };
+        for col in 0..grid.x_pixels {
+            let start = grid.lines[line] + (col as f64 * dwell) as u64;
+            let stop = grid.lines[line] + ((col + 1) as f64 * dwell) as u64;
+            pulses_in_pixel[row * grid.x_pixels + col] += pulses_before(laser, stop) - pulses_before(laser, start);
+        }
+    }
+
+    for (first_photon, count) in photons_by_pulse(photons, laser).values() {
+        if let Some(pixel) = grid.pixel_of(*first_photon) {
+            counts[pixel * max_photons + (*count as usize).min(max_photons - 1)] += 1;
+            pulses_in_pixel[pixel] = pulses_in_pixel[pixel].saturating_sub(1);
+        }
+    }
+    for (pixel, empty) in pulses_in_pixel.iter().enumerate() {
+        counts[pixel * max_photons] += empty;
+    }
+    PixelPhotonStats { x_pixels: grid.x_pixels, y_pixels: grid.y_pixels, max_photons, counts }
+}
+
+impl IntoPyObject for PixelPhotonStats {
+    fn into_object(self, py: Python) -> PyObject {
+        let dict = PyDict::new(py);
+        let images: Vec<Vec<u64>> = (0..self.max_photons).map(|num| self.image(num)).collect();
+        dict.set_item("total", self.total()).expect("Total insertion error.");
+        dict.set_item("images", images).expect("Images insertion error.");
+        dict.set_item("x_pixels", self.x_pixels).expect("X pixels insertion error.");
+        dict.set_item("y_pixels", self.y_pixels).expect("Y pixels insertion error.");
+        dict.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Poisson distributed numbers of photons for `pulses` pulses, with a mean of `mean`.
+    fn poisson_counts(pulses: usize, mean: f64) -> Vec<u64> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut uniform = move || {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..pulses)
+            .map(|_| {
+                let (limit, mut product, mut count) = ((-mean).exp(), uniform(), 0);
+                while product > limit {
+                    product *= uniform();
+                    count += 1;
+                }
+                count
+            })
+            .collect()
+    }
+
+    /// Pulses every 100 bins, and the photons of each pulse, 10 bins apart after it.
+    fn pulse_train(counts: &[u64]) -> (LaserPulses, Vec<u64>) {
+        let pulses: Vec<u64> = (0..counts.len() as u64).map(|pulse| pulse * 100).collect();
+        let photons = pulses.iter().zip(counts)
+            .flat_map(|(pulse, count)| (0..*count).map(move |photon| pulse + 10 * (photon + 1)))
+            .collect();
+        (LaserPulses::from_channel(&pulses), photons)
+    }
+
+    fn histogram(counts: &[u64], max_photons: usize) -> Vec<u64> {
+        let mut hist = vec![0; max_photons];
+        for count in counts {
+            hist[(*count as usize).min(max_photons - 1)] += 1;
+        }
+        hist
+    }
+
+    #[test]
+    fn poisson_photons_per_pulse() {
+        let mut counts = poisson_counts(10_000, 1.5);
+        // pulses before the first and after the last photon aren't counted
+        counts[0] = 0;
+        counts[1] = 1;
+        counts[9_999] = 2;
+        let (laser, photons) = pulse_train(&counts);
+        let hist = photons_per_pulse(&photons, &laser, 5);
+        assert_eq!(hist, histogram(&counts[1..], 5));
+        // the share of empty pulses is close to exp(-1.5)
+        assert!((hist[0] as f64 / 9_999.0 - (-1.5f64).exp()).abs() < 0.01, "{:?}", hist);
+    }
+
+    #[test]
+    fn poisson_photons_per_pulse_per_pixel() {
+        // two frames of two lines, each 1000 bins long with 800 bins imaged in
+        // two pixels of 4 pulses
+        let counts = poisson_counts(40, 1.5);
+        let (laser, photons) = pulse_train(&counts);
+        let lines = [0, 1000, 2000, 3000];
+        let grid = ScanGrid { lines: &lines, frames: &[0, 2000], x_pixels: 2, y_pixels: 2, line_duration: 800.0 };
+        let stats = photons_per_pulse_per_pixel(&photons, &laser, &grid, 4);
+        for row in 0..2 {
+            for col in 0..2 {
+                let pixel: Vec<u64> = [row, row + 2].iter()
+                    .flat_map(|line| counts[10 * line + 4 * col..10 * line + 4 * col + 4].to_vec())
+                    .collect();
+                let expected = histogram(&pixel, 4);
+                let found: Vec<u64> = (0..4).map(|num| stats.image(num)[row * 2 + col]).collect();
+                assert_eq!(found, expected, "pixel {} {}", row, col);
+            }
+        }
+        assert_eq!(stats.total().iter().sum::<u64>(), 32);
+    }
+}
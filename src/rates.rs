@@ -0,0 +1,115 @@
+use failure::{Error, format_err};
+use rayon::prelude::*;
+
+use crate::units::{BinWidth, TimeUnit};
+
+/// Largest number of bins of a trace. The counts are kept densely, one per bin
+/// from the first to the last event, so a bin that's tiny compared to the
+/// acquisition would take up a lot of memory.
+pub const MAX_RATE_BINS: usize = 1 << 24;
+
+/// Number of events of a channel in consecutive bins of equal width.
+/// Bin `i` covers `[start + i * bin_width, start + (i + 1) * bin_width)`, in
+/// bins of the multiscaler, which last `width` each.
+#[derive(Debug, Clone)]
+pub struct RateTrace {
+    pub start: u64,
+    pub bin_width: u64,
+    pub width: BinWidth,
+    pub counts: Vec<u64>,
+}
+
+impl RateTrace {
+    /// Start time of each bin, in seconds.
+    pub fn bin_starts(&self) -> Vec<f64> {
+        (0..self.counts.len() as u64).map(|idx| self.width.to_seconds(self.start + idx * self.bin_width)).collect()
+    }
+
+    /// Duration of a bin, in seconds.
+    pub fn bin_seconds(&self) -> f64 {
+        self.width.to_seconds(self.bin_width)
+    }
+
+    /// Counts per bin divided by the duration of the bin, in counts per second.
+    pub fn rates(&self) -> Vec<f64> {
+        let seconds = self.bin_seconds();
+        self.counts.iter().map(|count| *count as f64 / seconds).collect()
+    }
+}
+
+/// Bins the absolute event times of a channel into `[start, stop)`, with
+/// `bin_width` bins of the multiscaler in each bin of the trace. Events outside
+/// that range are ignored, and the times don't have to be sorted. Fails if the
+/// trace would need more than `MAX_RATE_BINS` bins.
+pub fn count_rate(times: &[u64], bin_width: u64, width: BinWidth, start: u64, stop: u64)
+    -> Result<RateTrace, Error> {
+    let bin_width = bin_width.max(1);
+    let num_of_bins = stop.saturating_sub(start).div_ceil(bin_width);
+    if num_of_bins > MAX_RATE_BINS as u64 {
+        return Err(format_err!("The trace would need {} bins, more than {}; use wider bins", num_of_bins,
+                               MAX_RATE_BINS));
+    }
+    let mut counts = vec![0u64; num_of_bins as usize];
+    for time in times.iter().filter(|time| **time >= start && **time < stop) {
+        counts[((time - start) / bin_width) as usize] += 1;
+    }
+    Ok(RateTrace { start, bin_width, width, counts })
+}
+
+/// Count-rate traces of several channels on a common time axis, spanning the
+/// first to the last event of all channels, so that the traces can be compared
+/// bin by bin. `bin_seconds` is rounded to the closest number of bins of the
+/// multiscaler, which last `width` each.
+pub fn count_rates(channels: &[Vec<u64>], bin_seconds: f64, width: BinWidth) -> Result<Vec<RateTrace>, Error> {
+    let bin_width = width.to_bins(bin_seconds, TimeUnit::Seconds);
+    if bin_width == 0 {
+        return Err(format_err!("Bins of {} s are shorter than the {} ps bins of the multiscaler", bin_seconds,
+                               width.picoseconds));
+    }
+    let start = channels.iter().filter_map(|chan| chan.iter().min()).min().cloned().unwrap_or(0);
+    let stop = channels.iter().filter_map(|chan| chan.iter().max()).max().map(|last| last + 1).unwrap_or(0);
+    channels.par_iter().map(|chan| count_rate(chan, bin_width, width, start, stop)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(values: &[f64], expected: &[f64]) {
+        assert_eq!(values.len(), expected.len());
+        for (value, expected) in values.iter().zip(expected) {
+            assert!((value - expected).abs() <= 1e-9 * expected.abs(), "{:?} != {:?}", values, expected);
+        }
+    }
+
+    #[test]
+    fn rates_in_counts_per_second() {
+        // 100 ps bins, so 10 µs are 100_000 bins
+        let width = BinWidth::from_picoseconds(100.0);
+        let channels = vec![vec![0, 10, 20, 100_000, 250_000], vec![150_000]];
+        let traces = count_rates(&channels, 10e-6, width).unwrap();
+        assert_eq!(traces[0].bin_width, 100_000);
+        assert_eq!(traces[0].counts, vec![3, 1, 1]);
+        assert_eq!(traces[1].counts, vec![0, 1, 0]);
+        assert_close(&traces[0].rates(), &[300_000.0, 100_000.0, 100_000.0]);
+        assert_close(&traces[0].bin_starts(), &[0.0, 10e-6, 20e-6]);
+    }
+
+    #[test]
+    fn bins_follow_the_header_bin_width() {
+        // 800 ps bins with a bitshift of 3
+        let width = BinWidth::from_bitshift(3);
+        let traces = count_rates(&[vec![0, 1249, 1250, 2499]], 1e-6, width).unwrap();
+        assert_eq!(traces[0].bin_width, 1250);
+        assert_eq!(traces[0].counts, vec![2, 2]);
+        assert_close(&traces[0].rates(), &[2e6, 2e6]);
+    }
+
+    #[test]
+    fn too_narrow_or_too_many_bins() {
+        let width = BinWidth::from_picoseconds(100.0);
+        assert!(count_rates(&[vec![0, 10]], 1e-12, width).is_err());
+        assert!(count_rates(&[vec![0, 1 << 40]], 1e-9, width).is_err());
+        assert!(count_rates(&[vec![]], 1e-9, width).unwrap()[0].counts.is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+use byteorder::{ReadBytesExt, LE};
+
+use crate::binary_parsing::{decode_common, field_modulus, Event, ParseOptions, StartClock, TimeOverflow};
+use crate::device::Device;
+
+/// Number of consecutive records that have to be valid for an alignment to be
+/// trusted.
+const PROBE_RECORDS: usize = 8;
+/// Number of records compared when checking the alignment of the start of the data
+const START_RECORDS: usize = 256;
+
+/// Why a byte range of the data was skipped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    /// A single damaged record, after which the records are valid again
+    InvalidRecord,
+    /// The records weren't aligned to the record size anymore, e.g. after a
+    /// damaged stretch of the file or a wrong start of the data
+    Misaligned,
+    /// The data ends in the middle of a record
+    Truncated,
+}
+
+impl SkipReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkipReason::InvalidRecord => "invalid_record",
+            SkipReason::Misaligned => "misaligned",
+            SkipReason::Truncated => "truncated",
+        }
+    }
+}
+
+/// A half-open byte range of the data section that wasn't decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkippedRange {
+    pub start: usize,
+    pub stop: usize,
+    pub reason: SkipReason,
+}
+
+/// Checks records for damage, and finds the next offset from which records can
+/// be decoded again. A record is valid if it's all zeros, which is padding, or
+/// if its channel code belongs to an input of the device and the bits beyond
+/// its fields are zero. An alignment is trusted once `PROBE_RECORDS` records in
+/// a row are valid, and, if the time counter should increase along the file,
+/// their times don't go backwards and continue from the last valid record.
+pub(crate) struct Resync<'a> {
+    record_size: usize,
+    bit_order: &'a [u8; 4],
+    device: &'a Device,
+    /// Number of bits of the record that belong to a field
+    used_bits: u32,
+    monotonic: bool,
+    /// Modulus of the counter returned by `key`
+    key_modulus: u64,
+}
+
+impl<'a> Resync<'a> {
+    pub(crate) fn new(record_size: usize, bit_order: &'a [u8; 4], options: &'a ParseOptions) -> Self {
+        let used_bits = u32::from(options.device.channel_bits) + 1
+            + bit_order.iter().map(|bits| u32::from(*bits)).sum::<u32>();
+        // start/stop times restart at every start, so they only increase along
+        // the file if there's a sweep counter or the time counter is unwrapped
+        let monotonic = options.start_clock == StartClock::Disabled
+            && (bit_order[2] > 0 || options.time_overflow != TimeOverflow::Disabled);
+        let key_modulus = field_modulus(if bit_order[2] > 0 { bit_order[2] } else { bit_order[3] });
+        Resync { record_size, bit_order, device: &options.device, used_bits, monotonic, key_modulus }
+    }
+
+    /// Whether the record can be decoded. All-zero records are valid.
+    pub(crate) fn is_valid(&self, mut record: &[u8]) -> bool {
+        if record.len() != self.record_size { return false };
+        let line = match record.read_uint::<LE>(self.record_size) {
+            Ok(line) => line,
+            Err(_) => return false,
+        };
+        if line == 0 { return true };
+        let padding_free = self.used_bits >= 64 || line >> self.used_bits == 0;
+        padding_free && self.device.decode_channel(line).is_some()
+    }
+
+    /// The counter that should increase along the file, the sweep if there is
+    /// one and the time otherwise. `event` has to be decoded, not yet made absolute.
+    pub(crate) fn key(&self, event: &Event) -> u64 {
+        if self.bit_order[2] > 0 { event.sweep } else { event.time }
+    }
+
+    /// Whether the counter can go from `last` to `key`, either forward or by
+    /// wrapping around.
+    fn follows(&self, last: u64, key: u64) -> bool {
+        key >= last || last - key > self.key_modulus / 2
+    }
+
+    /// Whether the records starting at `offset` look like properly aligned data.
+    /// All-zero records fit any alignment, so they only count if `padding_fits`.
+    /// `last_key` is the counter of the last valid record.
+    fn probe(&self, data: &[u8], offset: usize, padding_fits: bool, last_key: Option<u64>) -> bool {
+        let records: Vec<&[u8]> = data[offset..].chunks(self.record_size)
+            .take_while(|record| record.len() == self.record_size)
+            .take(PROBE_RECORDS)
+            .collect();
+        if records.is_empty() || !records.iter().all(|record| self.is_valid(record)) {
+            return false;
+        }
+        let keys: Vec<u64> = records.iter()
+            .filter(|record| record.iter().any(|byte| *byte != 0))
+            .filter_map(|record| decode_common(record, self.bit_order, self.device))
+            .map(|event| self.key(&event))
+            .collect();
+        if keys.is_empty() { return padding_fits };
+        if !self.monotonic { return true };
+        let continues = last_key.is_none_or(|last| self.follows(last, keys[0]));
+        continues && keys.windows(2).all(|pair| self.follows(pair[0], pair[1]))
+    }
+
+    /// Number of records in a row from `offset` that are valid and, if the
+    /// counter should increase, keep increasing, up to `limit`.
+    fn run_length(&self, data: &[u8], offset: usize, limit: usize) -> usize {
+        let mut last_key = None;
+        let records = data[offset..].chunks(self.record_size).take(limit);
+        for (idx, record) in records.enumerate() {
+            if !self.is_valid(record) { return idx };
+            if let Some(event) = decode_common(record, self.bit_order, self.device) {
+                let key = self.key(&event);
+                if self.monotonic && last_key.is_some_and(|last| !self.follows(last, key)) { return idx };
+                last_key = Some(key);
+            }
+        }
+        limit
+    }
+
+    /// The offset within the first record from which the data looks aligned,
+    /// and the reason the bytes before it are skipped. None means the data
+    /// starts aligned, which is assumed as long as its first records form a
+    /// trusted run, so that a damaged record early in the file doesn't move
+    /// the alignment. Otherwise the offset with the longest run of plausible
+    /// records wins.
+    pub(crate) fn start(&self, data: &[u8]) -> Option<(usize, SkipReason)> {
+        let runs: Vec<usize> = (0..self.record_size.min(data.len()))
+            .map(|offset| self.run_length(data, offset, START_RECORDS))
+            .collect();
+        if runs.first().is_none_or(|run| *run >= PROBE_RECORDS) { return None };
+        let best = (0..runs.len()).fold(0, |best, offset| if runs[offset] > runs[best] { offset } else { best });
+        if best == 0 { None } else { Some((best, SkipReason::Misaligned)) }
+    }
+
+    /// The offset from which decoding resumes after the invalid record at
+    /// `offset`, and the reason the bytes in between are skipped. The rest of
+    /// the data is skipped if no trusted alignment follows. `last_key` is the
+    /// counter of the last valid record before `offset`.
+    pub(crate) fn resume_after(&self, data: &[u8], offset: usize, last_key: Option<u64>)
+        -> (usize, SkipReason) {
+        if data.len() - offset < self.record_size {
+            return (data.len(), SkipReason::Truncated);
+        }
+        let next = offset + self.record_size;
+        if data.len() - next < self.record_size || self.probe(data, next, true, last_key) {
+            return (next, SkipReason::InvalidRecord);
+        }
+        let found = (offset + 1..data.len()).find(|start| self.probe(data, *start, false, last_key));
+        (found.unwrap_or(data.len()), SkipReason::Misaligned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+    use crate::binary_parsing::*;
+    use crate::helper_funcs::*;
+
+    const RANGE: u64 = 1000;
+
+    /// "5" records of stop1, one per sweep from sweep 1, at time 10 of their
+    /// sweep. The 8-bit sweep counter wraps after sweep 255.
+    fn records(count: u32) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|idx| {
+                let mut record = Vec::new();
+                record.write_u32::<LE>((((idx + 1) % 256) << 24) | (10 << 4) | 1).unwrap();
+                record
+            })
+            .collect()
+    }
+
+    fn parse(data: &[u8]) -> LstReturnU16 {
+        let options = ParseOptions::default();
+        let channels = create_channels_u16("5", vec![1, 0, 0, 0, 0, 1], &options.channel_names(), data.len()).unwrap();
+        parse_5(data, RANGE, &TimepatchBits::new("5", &options.device).unwrap(), channels, &options)
+    }
+
+    fn times(parsed: &LstReturnU16) -> Vec<u64> {
+        parsed.channel(0).unwrap().time.clone()
+    }
+
+    /// The times of the records of sweeps `sweeps`, counted from 1.
+    fn expected(sweeps: impl Iterator<Item = u64>) -> Vec<u64> {
+        sweeps.map(|sweep| 10 + RANGE * (sweep - 1)).collect()
+    }
+
+    fn ranges(parsed: &LstReturnU16) -> Vec<(usize, usize, SkipReason)> {
+        parsed.stats().skipped.iter().map(|range| (range.start, range.stop, range.reason)).collect()
+    }
+
+    #[test]
+    fn aligned_data_is_read_whole() {
+        let parsed = parse(&records(40).concat());
+        assert_eq!(times(&parsed), expected(1..=40));
+        assert!(ranges(&parsed).is_empty());
+    }
+
+    #[test]
+    fn start_of_data_off_by_one() {
+        let mut data = vec![0x5a];
+        data.extend(records(40).concat());
+        let parsed = parse(&data);
+        assert_eq!(ranges(&parsed)[0], (0, 1, SkipReason::Misaligned));
+        assert_eq!(times(&parsed), expected(1..=40));
+    }
+
+    #[test]
+    fn single_corrupted_record() {
+        let mut records = records(40);
+        records[20] = vec![0xff; 4];
+        let parsed = parse(&records.concat());
+        assert_eq!(ranges(&parsed), vec![(80, 84, SkipReason::InvalidRecord)]);
+        assert_eq!(times(&parsed), expected((1..=20).chain(22..=40)));
+    }
+
+    #[test]
+    fn damaged_stretch() {
+        let records = records(40);
+        let mut data = records[..20].concat();
+        // channel code 0 in every byte, so no alignment within the stretch is valid
+        data.extend_from_slice(&[0xf8; 7]);
+        data.extend(records[20..].concat());
+        let parsed = parse(&data);
+        assert_eq!(ranges(&parsed), vec![(80, 87, SkipReason::Misaligned)]);
+        assert_eq!(times(&parsed), expected(1..=40));
+    }
+
+    #[test]
+    fn truncated_tail() {
+        let mut data = records(40).concat();
+        data.extend_from_slice(&[0x01, 0x02]);
+        let parsed = parse(&data);
+        assert_eq!(ranges(&parsed), vec![(160, 162, SkipReason::Truncated)]);
+        assert_eq!(times(&parsed), expected(1..=40));
+    }
+
+    #[test]
+    fn damaged_record_before_a_sweep_wrap() {
+        let mut records = records(300);
+        records[252] = vec![0xff; 4];
+        let parsed = parse(&records.concat());
+        assert_eq!(ranges(&parsed), vec![(1008, 1012, SkipReason::InvalidRecord)]);
+        assert_eq!(times(&parsed), expected((1..=252).chain(254..=300)));
+        assert_eq!(parsed.stats().sweep_wraps, 1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use failure::{Error, format_err};
+use filebuffer::FileBuffer;
+
+use crate::binary_parsing::*;
+use crate::header::LstHeader;
+use crate::helper_funcs::*;
+use crate::window::Window;
+
+/// A list file written by `split_lst`.
+#[derive(Debug, Clone)]
+pub struct SplitPiece {
+    pub path: String,
+    pub window: Window,
+    /// Number of records in the piece
+    pub records: usize,
+    /// Counters before the first record of the piece, which its header carries,
+    /// so the piece parses to the same times as the original file with the window
+    pub resume: CounterState,
+}
+
+/// The time window of frames `first` up to `stop`, where frame k starts at the
+/// k-th event of the frame channel, counting from 0. The last frame lasts until
+/// the end of the acquisition.
+pub fn frame_window(frame_times: &[u64], first: usize, stop: usize) -> Result<Window, Error> {
+    if first >= stop || first >= frame_times.len() {
+        return Err(format_err!("Frames {} to {} aren't in the file, which has {} frames", first, stop,
+                               frame_times.len()));
+    }
+    let stop = frame_times.get(stop).cloned().unwrap_or_else(u64::max_value);
+    Ok(Window::Time { start: frame_times[first], stop })
+}
+
+/// The header of the original file with a "resume" line right before the
+/// "[DATA]" line, replacing the one the file may already have, and the offset
+/// of the counters on that line.
+fn piece_header(text: &[u8], resume: &CounterState) -> (Vec<u8>, usize) {
+    let text = String::from_utf8_lossy(text);
+    let mut header = String::with_capacity(text.len() + 128);
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.starts_with("resume=") { continue };
+        if line.contains("[DATA]") {
+            let ending = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+            offset = header.len() + "resume=".len();
+            header.push_str(&LstHeader::resume_line(resume));
+            header.push_str(ending);
+        }
+        header.push_str(line);
+    }
+    (header.into_bytes(), offset)
+}
+
+/// Writes the records of each window to a new list file. The header of the
+/// original file is kept, since its settings still describe the records, with
+/// a "resume" line added for the counters before the first record of the
+/// piece, so the piece parses to the same times as the original file with the
+/// window. A record is written to every window that holds it, in file order,
+/// with the times followed like `options` parses them. Empty and damaged
+/// records are left out.
+pub fn split_lst(fname: &str, header: &LstHeader, options: &ParseOptions, windows: &[Window],
+                 paths: &[String]) -> Result<Vec<SplitPiece>, Error> {
+    if windows.len() != paths.len() {
+        return Err(format_err!("{} windows but {} output files", windows.len(), paths.len()));
+    }
+    let bit_order = TimepatchBits::new(&header.timepatch, &header.device)?;
+    let has_sweep_window = windows.iter().any(|window| match window {
+        Window::Sweeps { .. } => true,
+        Window::Time { .. } => false,
+    });
+    if bit_order[2] == 0 && has_sweep_window {
+        return Err(format_err!("Timepatch {} has no sweep counter", header.timepatch));
+    }
+    let data_with_headers = FileBuffer::open(fname)?;
+    let data = &data_with_headers[header.start_of_data..];
+    let options = ParseOptions { device: header.device.clone(), resume: header.resume, ..options.clone() };
+    let record_size = bytes_per_record(&header.timepatch)?;
+    let (piece_header, resume_offset) = piece_header(&data_with_headers[..header.start_of_data], &header.resume);
+    let mut outputs = paths.iter()
+        .map(|path| {
+            let mut file = BufWriter::new(File::create(path)?);
+            file.write_all(&piece_header)?;
+            Ok(file)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut pieces: Vec<SplitPiece> = paths.iter().zip(windows)
+        .map(|(path, window)| SplitPiece { path: path.clone(), window: *window, records: 0,
+                                           resume: CounterState::default() })
+        .collect();
+    for_each_record(data, &header.timepatch, header.range, &options, |offset, _, followed| {
+        let FollowedEvent { event, time, state } = match followed {
+            Some(followed) => followed,
+            None => return Ok(()),
+        };
+        for (piece, output) in pieces.iter_mut().zip(outputs.iter_mut()) {
+            if !piece.window.contains(time, event.sweep) { continue };
+            if piece.records == 0 { piece.resume = state };
+            output.write_all(&data[offset..offset + record_size])?;
+            piece.records += 1;
+        }
+        Ok(())
+    })?;
+    // the counters are only known once the first record of the piece was seen
+    for (piece, output) in pieces.iter().zip(outputs.iter_mut()) {
+        let line = LstHeader::resume_line(&piece.resume);
+        output.seek(SeekFrom::Start(resume_offset as u64))?;
+        output.write_all(&line.as_bytes()["resume=".len()..])?;
+        output.flush()?;
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::device::Device;
+    use crate::writer::write_lst;
+    use crate::{analyze_lst_u16, analyze_lst_u16_window};
+
+    /// Writes `events` to a new file of the MCS6A with `timepatch`.
+    fn write_file(name: &str, timepatch: &str, range: u64, events: &[Event]) -> (String, LstHeader) {
+        let header = LstHeader { start_of_data: 0, range, timepatch: timepatch.to_string(), device: Device::mcs6a(),
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        let written = write_lst(&path, &header, events).unwrap();
+        (path, written)
+    }
+
+    fn times(result: &LstReturnU16) -> Vec<Vec<u64>> {
+        (0..6).map(|id| result.channel(id).map_or_else(Vec::new, |channel| channel.time.clone())).collect()
+    }
+
+    /// Splits the file and checks that each piece parses like the original
+    /// file with its window.
+    fn check_pieces(path: &str, header: &LstHeader, options: &ParseOptions, windows: &[Window]) -> Vec<SplitPiece> {
+        let paths: Vec<String> = (0..windows.len()).map(|idx| format!("{}_{}.lst", path, idx)).collect();
+        let pieces = split_lst(path, header, options, windows, &paths).unwrap();
+        for (piece, window) in pieces.iter().zip(windows) {
+            let expected = analyze_lst_u16_window(path, header.start_of_data, header.range, &header.timepatch,
+                                                  header.channel_map.clone(), options, *window).unwrap();
+            let piece_header = LstHeader::read(&piece.path).unwrap();
+            let parsed = analyze_lst_u16(&piece.path, piece_header.start_of_data, piece_header.range,
+                                         &piece_header.timepatch, piece_header.channel_map.clone(), options).unwrap();
+            assert!(piece.records > 0);
+            assert_eq!(times(&parsed), times(&expected), "window {:?}", window);
+        }
+        pieces
+    }
+
+    #[test]
+    fn pieces_after_sweep_wraps() {
+        // four events per sweep over 700 sweeps, so the 8-bit sweep counter wraps twice
+        let events: Vec<Event> = (0..2800u64)
+            .map(|idx| Event { channel: (idx % 2) as usize, time: 1000 * (idx / 4) + (idx * 7) % 1000,
+                               sweep: idx / 4 + 1, ..Event::default() })
+            .collect();
+        let (path, header) = write_file("libps_split_sweeps.lst", "5", 1000, &events);
+        let windows = [Window::Sweeps { first: 1, stop: 100 }, Window::Sweeps { first: 250, stop: 300 },
+                       Window::Sweeps { first: 600, stop: 701 }, Window::Time { start: 530_500, stop: 540_000 }];
+        let pieces = check_pieces(&path, &header, &ParseOptions::default(), &windows);
+        assert_eq!(LstHeader::read(&pieces[2].path).unwrap().resume.sweep_epoch, 2);
+    }
+
+    #[test]
+    fn pieces_after_time_wraps() {
+        // the 12-bit time counter of "0" wraps every four events
+        let events: Vec<Event> = (0..200u64).map(|idx| Event { time: idx * 1000, ..Event::default() }).collect();
+        let (path, header) = write_file("libps_split_times.lst", "0", 0, &events);
+        let options = ParseOptions { time_overflow: TimeOverflow::BackwardJumps, ..ParseOptions::default() };
+        let windows = [Window::Time { start: 50_000, stop: 90_000 }, Window::Time { start: 150_500, stop: 200_000 }];
+        let pieces = check_pieces(&path, &header, &options, &windows);
+        assert_eq!(LstHeader::read(&pieces[1].path).unwrap().resume.time_epoch, 36);
+    }
+
+    #[test]
+    fn resume_line_is_replaced() {
+        let text = b"[MCS6A A] 6\r\nrange=10\r\ntime_patch=5\r\nresume=1,2,3,-,-\r\n[DATA]\r\n";
+        let state = CounterState { sweep_epoch: 4, last_sweep: Some(200), ..CounterState::default() };
+        let (header, offset) = piece_header(text, &state);
+        let parsed = LstHeader::parse(&header).unwrap();
+        assert_eq!((parsed.resume.sweep_epoch, parsed.resume.time_epoch, parsed.resume.last_sweep), (4, 0, Some(200)));
+        assert_eq!(&header[offset - "resume=".len()..offset], b"resume=");
+        assert_eq!(parsed.start_of_data, header.len());
+    }
+}
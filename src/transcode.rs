@@ -0,0 +1,383 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use byteorder::{WriteBytesExt, LE};
+use failure::{Error, format_err};
+use filebuffer::FileBuffer;
+use serde_derive::Serialize;
+
+use crate::binary_parsing::*;
+use crate::header::LstHeader;
+use crate::helper_funcs::*;
+use crate::writer::{decode_record, encode_record, record_bits};
+
+/// Number of bits needed to hold `value`.
+fn bits_for(value: u64) -> u8 {
+    (64 - value.leading_zeros()) as u8
+}
+
+/// How many bits of each field the records of a file actually use. The time
+/// and sweep counters may be narrower than their largest value, as long as
+/// they wrap around slowly enough to be unwrapped again.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FieldUsage {
+    pub records: u64,
+    /// All-zero records, which hold no event
+    pub zero_records: u64,
+    /// Records with a channel code that doesn't belong to an input
+    pub invalid_records: u64,
+    pub time_bits: u8,
+    /// Zero if the file has no sweep counter
+    pub sweep_bits: u8,
+    pub tag_bits: u8,
+    /// Records with the lost flag set
+    pub lost_records: u64,
+}
+
+/// A timepatch the file can't be transcoded to, and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    pub timepatch: String,
+    pub reasons: Vec<String>,
+}
+
+/// Outcome of `transcode_lst`. `target` is None if the file was left alone,
+/// in which case `rejected` says why no timepatch was good enough.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscodeReport {
+    pub file: String,
+    pub device: String,
+    pub timepatch: String,
+    pub usage: FieldUsage,
+    pub target: Option<String>,
+    pub output: Option<String>,
+    /// Size of the data section before and after transcoding, in bytes
+    pub data_bytes: usize,
+    pub target_data_bytes: Option<usize>,
+    /// All-zero records that were left out of the output
+    pub dropped_zero_records: u64,
+    pub rejected: Vec<Rejection>,
+}
+
+impl TranscodeReport {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Measures the fields of the records, with the time counter followed like
+/// `options` asks for.
+pub fn field_usage(data: &[u8], header: &LstHeader, options: &ParseOptions) -> Result<FieldUsage, Error> {
+    let bit_order = TimepatchBits::new(&header.timepatch, &header.device)?;
+    let record_size = bytes_per_record(&header.timepatch)?;
+    let mut usage = FieldUsage::default();
+    let (mut max_time, mut max_sweep, mut max_tag) = (0, 0, 0);
+    // the largest steps of the counters between records, which decide how
+    // narrow a counter may get and still be unwrapped
+    let (mut time_step, mut sweep_step) = (0, 0);
+    let (mut last_time, mut last_sweep) = (None, None);
+    usage.records = (data.len() / record_size) as u64;
+    usage.zero_records = usage.records;
+    for_each_record(data, &header.timepatch, header.range, options, |_, _, followed| {
+        usage.zero_records -= 1;
+        let FollowedEvent { event, time, .. } = match followed {
+            Some(followed) => followed,
+            None => {
+                usage.invalid_records += 1;
+                return Ok(());
+            },
+        };
+        max_tag = max_tag.max(event.tag);
+        if event.lost { usage.lost_records += 1 };
+        if bit_order[2] > 0 {
+            max_time = max_time.max(time - header.range * event.sweep.saturating_sub(1));
+            max_sweep = max_sweep.max(event.sweep);
+            sweep_step = sweep_step.max(event.sweep.saturating_sub(last_sweep.unwrap_or(event.sweep)));
+            last_sweep = Some(event.sweep);
+        } else {
+            max_time = max_time.max(match options.time_overflow {
+                TimeOverflow::Range if header.range > 0 => time % header.range,
+                _ => time,
+            });
+            time_step = time_step.max(time.saturating_sub(last_time.unwrap_or(time)));
+            last_time = Some(time);
+        }
+        Ok(())
+    })?;
+    usage.time_bits = match options.time_overflow {
+        TimeOverflow::BackwardJumps if bit_order[2] == 0 => bits_for(max_time).min(bits_for(time_step) + 1),
+        _ => bits_for(max_time),
+    };
+    if bit_order[2] > 0 {
+        usage.sweep_bits = bits_for(max_sweep).min(bits_for(sweep_step) + 1).max(1);
+    }
+    usage.tag_bits = bits_for(u64::from(max_tag));
+    Ok(usage)
+}
+
+/// The reasons why records of `header` with the fields of `usage` don't fit
+/// into records of `timepatch`, or none if they do.
+fn misfits(usage: &FieldUsage, header: &LstHeader, timepatch: &str) -> Result<Vec<String>, Error> {
+    let bit_order = TimepatchBits::new(timepatch, &header.device)?;
+    let mut reasons = Vec::new();
+    let record_size = bytes_per_record(timepatch)?;
+    if record_bits(&bit_order, &header.device) > record_size as u32 * 8 {
+        reasons.push(format!("its {}-byte records are too small for the channel field of {}", record_size,
+                             header.device.name));
+    }
+    match (usage.sweep_bits > 0, bit_order[2] > 0) {
+        (true, false) => reasons.push("it has no sweep counter".to_string()),
+        (false, true) => reasons.push("it has a sweep counter, which the file doesn't".to_string()),
+        _ => {},
+    }
+    for &(name, needed, bits) in [("time", usage.time_bits, bit_order[3]), ("sweep", usage.sweep_bits, bit_order[2]),
+                                  ("tag", usage.tag_bits, bit_order[1])].iter() {
+        if needed > bits && !(name == "sweep" && bits == 0) {
+            reasons.push(format!("the {} needs {} bits but the field has {}", name, needed, bits));
+        }
+    }
+    if usage.lost_records > 0 && bit_order[0] == 0 {
+        reasons.push(format!("the lost flag is set in {} records, but it has no lost bit", usage.lost_records));
+    }
+    Ok(reasons)
+}
+
+/// The header of the file with the timepatch replaced, keeping everything else
+/// the acquisition software wrote.
+fn header_with_timepatch(text: &[u8], timepatch: &str) -> Result<Vec<u8>, Error> {
+    let text = String::from_utf8_lossy(text);
+    let mut found = false;
+    let lines: Vec<String> = text.split('\n')
+        .map(|line| if line.starts_with("time_patch=") {
+            found = true;
+            let ending = if line.ends_with('\r') { "\r" } else { "" };
+            format!("time_patch={}{}", timepatch, ending)
+        } else { line.to_string() })
+        .collect();
+    if !found {
+        return Err(format_err!("No time_patch in the header"));
+    }
+    Ok(lines.join("\n").into_bytes())
+}
+
+/// Writes the records with the layout of `timepatch`, and checks that reading
+/// each new record gives back the same event and absolute time. Returns the
+/// size of the new data section, or the first record that didn't survive.
+fn write_transcoded(data_with_headers: &[u8], header: &LstHeader, options: &ParseOptions, timepatch: &str,
+                    out_path: &str) -> Result<Result<usize, String>, Error> {
+    let bit_order = TimepatchBits::new(timepatch, &options.device)?;
+    let record_size = bytes_per_record(timepatch)?;
+    let below = lost_below_tag(timepatch);
+    let device = &options.device;
+    let target = LstHeader { timepatch: timepatch.to_string(), ..header.clone() };
+    let mut timeline = Timeline::new(header.range, &bit_order, options);
+    let mut stats = ParseStats::new(device.num_of_channels());
+    let time_modulus = match options.time_overflow {
+        TimeOverflow::Range if header.range > 0 => header.range,
+        TimeOverflow::Disabled => u64::MAX,
+        _ => field_modulus(bit_order[3]),
+    };
+    let mut file = BufWriter::new(File::create(out_path)?);
+    file.write_all(&header_with_timepatch(&data_with_headers[..header.start_of_data], timepatch)?)?;
+    let mut written = 0;
+    let data = &data_with_headers[header.start_of_data..];
+    let outcome = for_each_record(data, &header.timepatch, header.range, options, |offset, _, followed| {
+        let FollowedEvent { event, time, .. } = followed.ok_or_else(|| format_err!("Damaged record at byte {}", offset))?;
+        let (raw_time, raw_sweep) = if bit_order[2] > 0 {
+            (time - target.range * event.sweep.saturating_sub(1), event.sweep % field_modulus(bit_order[2]))
+        } else { (time % time_modulus, 0) };
+        let record = Event { time: raw_time, sweep: raw_sweep, ..event };
+        let line = encode_record(&record, &bit_order, below, device)
+            .map_err(|err| format_err!("Record at byte {}: {}", offset, err))?;
+        let mut check = decode_record(line, &bit_order, below, device)
+            .ok_or_else(|| format_err!("Record at byte {} lost its channel", offset))?;
+        let check_time = timeline.absolute(&mut check, &mut stats);
+        let fields = |event: &Event| (event.channel, event.edge, event.sweep, event.tag, event.lost);
+        if check_time != time || fields(&check) != fields(&event) {
+            return Err(format_err!("the record at byte {} would be read back at time {} instead of {}", offset,
+                                   check_time, time));
+        }
+        file.write_uint::<LE>(line, record_size)?;
+        written += record_size;
+        Ok(())
+    });
+    match outcome {
+        Ok(()) => {
+            file.flush()?;
+            Ok(Ok(written))
+        },
+        Err(err) => {
+            drop(file);
+            fs::remove_file(out_path)?;
+            Ok(Err(err.to_string()))
+        },
+    }
+}
+
+/// Re-encodes a list file with a more compact timepatch, `timepatch` if it's
+/// given, otherwise the one with the smallest records that holds the content
+/// of the file. Every record is checked to read back as the same event at the
+/// same time, with the time counter followed like `options` asks for, before
+/// the new file is kept. The header is copied with only its timepatch changed.
+/// All-zero records are left out, and the report counts them as dropped. Files
+/// with damaged or incomplete records are refused. If no timepatch fits,
+/// nothing is written and the report lists what didn't fit into each candidate.
+pub fn transcode_lst(fname: &str, header: &LstHeader, options: &ParseOptions, timepatch: Option<&str>,
+                     out_path: &str) -> Result<TranscodeReport, Error> {
+    if fs::canonicalize(fname).ok() == fs::canonicalize(out_path).ok() && fs::metadata(out_path).is_ok() {
+        return Err(format_err!("{} can't be transcoded into itself", fname));
+    }
+    let data_with_headers = FileBuffer::open(fname)?;
+    let data = &data_with_headers[header.start_of_data..];
+    let record_size = bytes_per_record(&header.timepatch)?;
+    if data.len() % record_size != 0 {
+        return Err(format_err!("{} ends with an incomplete record, which would be lost", fname));
+    }
+    let options = ParseOptions { device: header.device.clone(), time_overflow: options.time_overflow,
+                                 resume: header.resume, ..ParseOptions::default() };
+    let usage = field_usage(data, header, &options)?;
+    if usage.invalid_records > 0 {
+        return Err(format_err!("{} has {} damaged records, which would be lost", fname, usage.invalid_records));
+    }
+    let candidates: Vec<&str> = match timepatch {
+        Some(timepatch) => {
+            if !TIMEPATCHES.contains(&timepatch) {
+                return Err(format_err!("Unknown timepatch {}", timepatch));
+            }
+            vec![timepatch]
+        },
+        None => TIMEPATCHES.iter().cloned()
+            .filter(|candidate| matches!(bytes_per_record(candidate), Ok(size) if size < record_size))
+            .collect(),
+    };
+    let mut report = TranscodeReport {
+        file: fname.to_string(),
+        device: header.device.name.clone(),
+        timepatch: header.timepatch.clone(),
+        usage,
+        target: None,
+        output: None,
+        data_bytes: data.len(),
+        target_data_bytes: None,
+        dropped_zero_records: 0,
+        rejected: Vec::new(),
+    };
+    for candidate in candidates {
+        let mut reasons = misfits(&report.usage, header, candidate)?;
+        if reasons.is_empty() {
+            match write_transcoded(&data_with_headers, header, &options, candidate, out_path)? {
+                Ok(written) => {
+                    report.target = Some(candidate.to_string());
+                    report.output = Some(out_path.to_string());
+                    report.target_data_bytes = Some(written);
+                    report.dropped_zero_records = report.usage.zero_records;
+                    break;
+                },
+                Err(reason) => reasons.push(reason),
+            }
+        }
+        report.rejected.push(Rejection { timepatch: candidate.to_string(), reasons });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+
+    use super::*;
+    use crate::analyze_lst_u16;
+    use crate::device::Device;
+    use crate::writer::write_lst;
+
+    /// Writes a "43" file of the MCS6A with `events`, whose records are twice
+    /// as large as their content needs unless they use the tag or lost bit.
+    fn write_43(name: &str, events: &[Event]) -> (String, LstHeader) {
+        write_43_of(name, Device::mcs6a(), events)
+    }
+
+    fn write_43_of(name: &str, device: Device, events: &[Event]) -> (String, LstHeader) {
+        let header = LstHeader { start_of_data: 0, range: 0, timepatch: "43".to_string(),
+                                 channel_map: vec![1; device.num_of_channels()], device, bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        let written = write_lst(&path, &header, events).unwrap();
+        (path, written)
+    }
+
+    fn events(tag: impl Fn(u64) -> u16) -> Vec<Event> {
+        (0..200u64)
+            .map(|idx| Event { channel: (idx % 3) as usize, edge: idx % 5 == 0, time: idx * 1000, tag: tag(idx),
+                               ..Event::default() })
+            .collect()
+    }
+
+    /// Channel, edge and time of the events of a file.
+    fn parse(path: &str) -> Vec<(usize, bool, u64)> {
+        let header = LstHeader::read(path).unwrap();
+        analyze_lst_u16(path, header.start_of_data, header.range, &header.timepatch, header.channel_map.clone(),
+                        &ParseOptions::default()).unwrap().events()
+            .iter().map(|event| (event.channel, event.edge, event.time)).collect()
+    }
+
+    #[test]
+    fn unused_tag_and_lost_bit_are_dropped() {
+        let (path, header) = write_43("libps_transcode_43.lst", &events(|_| 0));
+        let out_path = format!("{}.small.lst", path);
+        let report = transcode_lst(&path, &header, &ParseOptions::default(), None, &out_path).unwrap();
+        assert_eq!(report.usage.time_bits, 18);
+        assert_eq!((report.usage.tag_bits, report.usage.lost_records), (0, 0));
+        // "0" is too narrow for the times, and "5" has a sweep counter
+        assert_eq!(report.target.as_deref(), Some("1"));
+        assert_eq!((report.data_bytes, report.target_data_bytes), (1600, Some(800)));
+        assert_eq!(LstHeader::read(&out_path).unwrap().timepatch, "1");
+        assert_eq!(parse(&out_path), parse(&path));
+        assert_eq!(parse(&out_path).len(), 200);
+    }
+
+    #[test]
+    fn used_tags_are_refused() {
+        let (path, header) = write_43("libps_transcode_43_tags.lst", &events(|idx| (idx * 300) as u16));
+        let out_path = format!("{}.small.lst", path);
+        let report = transcode_lst(&path, &header, &ParseOptions::default(), None, &out_path).unwrap();
+        assert_eq!(report.usage.tag_bits, 16);
+        assert_eq!((report.target, report.output), (None, None));
+        assert!(!Path::new(&out_path).exists());
+        let tagged: Vec<&Rejection> = report.rejected.iter().filter(|rejection| rejection.timepatch == "2a").collect();
+        assert_eq!(tagged[0].reasons, vec!["it has a sweep counter, which the file doesn't",
+                                           "the tag needs 16 bits but the field has 8"]);
+        assert!(transcode_lst(&path, &header, &ParseOptions::default(), Some("4"), &out_path).is_err());
+        assert!(transcode_lst(&path, &header, &ParseOptions::default(), None, &path).is_err());
+    }
+
+    #[test]
+    fn zero_records_are_reported_as_dropped() {
+        let (path, header) = write_43("libps_transcode_43_zeros.lst", &events(|_| 0));
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0; 5 * 8]).unwrap();
+        drop(file);
+        let out_path = format!("{}.small.lst", path);
+        let report = transcode_lst(&path, &header, &ParseOptions::default(), None, &out_path).unwrap();
+        assert_eq!((report.usage.records, report.usage.zero_records), (205, 5));
+        assert_eq!(report.target.as_deref(), Some("1"));
+        assert_eq!((report.data_bytes, report.target_data_bytes, report.dropped_zero_records), (1640, Some(800), 5));
+        assert_eq!(parse(&out_path), parse(&path));
+        assert!(report.to_json().unwrap().contains("\"dropped_zero_records\": 5"));
+    }
+
+    #[test]
+    fn mcs8a_files_are_transcoded() {
+        let (path, header) = write_43_of("libps_transcode_43_mcs8a.lst", Device::mcs8a(), &events(|_| 0));
+        let out_path = format!("{}.small.lst", path);
+        let report = transcode_lst(&path, &header, &ParseOptions::default(), None, &out_path).unwrap();
+        assert_eq!(report.device, "MCS8A");
+        // "0" leaves 11 bits for the time after the 4-bit channel field
+        assert_eq!(report.target.as_deref(), Some("1"));
+        assert_eq!((report.target_data_bytes, report.dropped_zero_records), (Some(800), 0));
+        let written = LstHeader::read(&out_path).unwrap();
+        assert_eq!((written.device.name.as_str(), written.timepatch.as_str()), ("MCS8A", "1"));
+        assert_eq!(parse(&out_path), parse(&path));
+        assert_eq!(parse(&out_path).len(), 200);
+    }
+}
@@ -0,0 +1,140 @@
+/// Unit of the times returned by the parsers.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeUnit {
+    /// Raw bins of the multiscaler
+    #[default]
+    Bins,
+    Picoseconds,
+    Seconds,
+}
+
+impl TimeUnit {
+    /// Parses the short names "bins", "ps" and "s".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bins" => Some(TimeUnit::Bins),
+            "ps" => Some(TimeUnit::Picoseconds),
+            "s" => Some(TimeUnit::Seconds),
+            _ => None,
+        }
+    }
+}
+
+/// Duration of a single time bin of the multiscaler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BinWidth {
+    pub picoseconds: f64,
+}
+
+impl BinWidth {
+    /// The nominal bin width of the MCS6A, 100 ps times 2 to the power of the
+    /// bitshift set in the header.
+    pub fn from_bitshift(bitshift: u8) -> Self {
+        BinWidth { picoseconds: 100.0 * 2f64.powi(i32::from(bitshift)) }
+    }
+
+    pub fn from_picoseconds(picoseconds: f64) -> Self {
+        BinWidth { picoseconds }
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.picoseconds * 1e-12
+    }
+
+    /// Converts a number of bins, e.g. a time, window or period, to `unit`.
+    pub fn convert(&self, bins: u64, unit: TimeUnit) -> f64 {
+        match unit {
+            TimeUnit::Bins => bins as f64,
+            TimeUnit::Picoseconds => bins as f64 * self.picoseconds,
+            TimeUnit::Seconds => bins as f64 * self.seconds(),
+        }
+    }
+
+    /// Converts a duration in `unit` to the closest number of bins.
+    pub fn to_bins(&self, value: f64, unit: TimeUnit) -> u64 {
+        let bins = match unit {
+            TimeUnit::Bins => value,
+            TimeUnit::Picoseconds => value / self.picoseconds,
+            TimeUnit::Seconds => value / self.seconds(),
+        };
+        bins.round().max(0.0) as u64
+    }
+
+    pub fn to_seconds(&self, bins: u64) -> f64 {
+        self.convert(bins, TimeUnit::Seconds)
+    }
+
+    pub fn to_picoseconds(&self, bins: u64) -> f64 {
+        self.convert(bins, TimeUnit::Picoseconds)
+    }
+
+    pub fn seconds_to_bins(&self, seconds: f64) -> u64 {
+        self.to_bins(seconds, TimeUnit::Seconds)
+    }
+
+    pub fn picoseconds_to_bins(&self, picoseconds: f64) -> u64 {
+        self.to_bins(picoseconds, TimeUnit::Picoseconds)
+    }
+
+    /// Converts all the times of a channel to `unit`.
+    pub fn convert_all(&self, times: &[u64], unit: TimeUnit) -> Vec<f64> {
+        times.iter().map(|time| self.convert(*time, unit)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::LstHeader;
+
+    #[test]
+    fn bitshift_doubles_the_bin_width() {
+        assert_eq!(BinWidth::from_bitshift(0).picoseconds, 100.0);
+        assert_eq!(BinWidth::from_bitshift(3).picoseconds, 800.0);
+        let width = BinWidth::from_bitshift(4);
+        assert_eq!(width.convert(10, TimeUnit::Bins), 10.0);
+        assert_eq!(width.to_picoseconds(10), 16_000.0);
+        assert!((width.to_seconds(10) - 16e-9).abs() < 1e-21);
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        let width = BinWidth::from_picoseconds(80.0);
+        assert_eq!(width.picoseconds_to_bins(800.0), 10);
+        assert_eq!(width.seconds_to_bins(1e-6), 12_500);
+        // durations are rounded to the closest bin, and negative ones to 0
+        assert_eq!(width.picoseconds_to_bins(839.0), 10);
+        assert_eq!(width.picoseconds_to_bins(841.0), 11);
+        assert_eq!(width.to_bins(-5.0, TimeUnit::Picoseconds), 0);
+        assert_eq!(width.to_bins(7.4, TimeUnit::Bins), 7);
+        for bins in [0, 1, 12_345, 1 << 40].iter() {
+            for unit in [TimeUnit::Bins, TimeUnit::Picoseconds, TimeUnit::Seconds].iter() {
+                assert_eq!(width.to_bins(width.convert(*bins, *unit), *unit), *bins);
+            }
+        }
+        assert_eq!(width.convert_all(&[1, 2], TimeUnit::Picoseconds), vec![80.0, 160.0]);
+    }
+
+    #[test]
+    fn unit_names() {
+        assert_eq!(TimeUnit::from_name("ps"), Some(TimeUnit::Picoseconds));
+        assert_eq!(TimeUnit::from_name("s"), Some(TimeUnit::Seconds));
+        assert_eq!(TimeUnit::from_name("bins"), Some(TimeUnit::Bins));
+        assert_eq!(TimeUnit::from_name("ns"), None);
+    }
+
+    #[test]
+    fn bin_width_from_the_header() {
+        let header = |settings: &str| {
+            LstHeader::parse(format!("[MCS6A A] 6\r\nrange=10\r\ntime_patch=5\r\n{}[DATA]\r\n", settings).as_bytes())
+        };
+        // calfact is in nanoseconds, and takes precedence over the bitshift
+        let parsed = header("bitshift=0x3\r\ncalfact=0.0785\r\n").unwrap();
+        assert_eq!((parsed.bitshift, parsed.calfact), (3, Some(0.0785)));
+        assert!((parsed.bin_width().picoseconds - 78.5).abs() < 1e-9);
+        assert_eq!(header("bitshift=2\r\n").unwrap().bin_width().picoseconds, 400.0);
+        assert_eq!(header("bitshift=2\r\ncalfact=0\r\n").unwrap().bin_width().picoseconds, 400.0);
+        assert_eq!(header("").unwrap().bin_width().picoseconds, 100.0);
+        assert!(header("calfact=fast\r\n").is_err());
+    }
+}
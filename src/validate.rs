@@ -0,0 +1,243 @@
+use failure::{Error, format_err};
+use filebuffer::FileBuffer;
+use serde_derive::Serialize;
+
+use crate::binary_parsing::*;
+use crate::header::LstHeader;
+use crate::helper_funcs::*;
+use crate::resync::Resync;
+
+/// Event counts of a single input.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChannelReport {
+    pub name: String,
+    pub rising: u64,
+    pub falling: u64,
+    /// Events with the lost flag set, in timepatches that have one
+    pub lost: u64,
+}
+
+/// Number of records with a channel code that doesn't belong to an input.
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeCount {
+    pub code: u64,
+    pub count: u64,
+}
+
+/// Integrity and quality of a list file, gathered in a single pass over its
+/// records. `passed` is false if anything suggests that the file is damaged.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub file: String,
+    pub device: String,
+    pub timepatch: String,
+    pub record_size: usize,
+    /// How overflows of the time counter were unwrapped, "disabled", "jumps"
+    /// or "range", see `validate`
+    pub time_overflow: &'static str,
+    /// Size of the data section, in bytes
+    pub data_bytes: usize,
+    /// Number of complete records in the data section
+    pub records: usize,
+    pub channels: Vec<ChannelReport>,
+    /// All-zero records, which the parsers skip
+    pub zero_records: u64,
+    pub invalid_channel_codes: Vec<CodeCount>,
+    /// Stretches of records whose absolute time is lower than the time of an
+    /// earlier record
+    pub non_monotonic_runs: u64,
+    /// Byte offset of the first record of the first such stretch
+    pub first_non_monotonic: Option<usize>,
+    /// Number of times the sweep counter skipped sweeps, and the number of
+    /// skipped sweeps
+    pub sweep_gaps: u64,
+    pub missing_sweeps: u64,
+    /// Bytes after the last complete record
+    pub trailing_bytes: usize,
+    /// Whether the file is as long as the header implies, the start of the
+    /// data plus a whole number of records of the size of its timepatch. The
+    /// multiscaler doesn't record a length or count of the records to compare
+    /// with.
+    pub size_matches_header: bool,
+    /// Offset of the first aligned record, if the data doesn't start aligned
+    pub misaligned_start: Option<usize>,
+    pub passed: bool,
+}
+
+impl ValidationReport {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Scans the data section of a list file and reports what's in it and what's
+/// wrong with it. The times are followed with the overflow and start settings
+/// of `options`, like the parsers do, except that the time counter of a
+/// timepatch without a sweep counter is unwrapped at backward jumps if
+/// `options` leave it alone, since each of its overflows would look like damage.
+pub fn validate(fname: &str, header: &LstHeader, options: &ParseOptions) -> Result<ValidationReport, Error> {
+    let data_with_headers = FileBuffer::open(fname)?;
+    let data = data_with_headers.get(header.start_of_data..)
+        .ok_or_else(|| format_err!("The data of {} should start at byte {}, but the file has {} bytes", fname,
+                                   header.start_of_data, data_with_headers.len()))?;
+    let bit_order = TimepatchBits::new(&header.timepatch, &header.device)?;
+    let time_overflow = match options.time_overflow {
+        TimeOverflow::Disabled if bit_order[2] == 0 => TimeOverflow::BackwardJumps,
+        time_overflow => time_overflow,
+    };
+    let options = ParseOptions { device: header.device.clone(), time_overflow, resume: header.resume,
+                                 ..options.clone() };
+    let device = &options.device;
+    let record_size = bytes_per_record(&header.timepatch)?;
+    let channel_mask = field_modulus(device.channel_bits) - 1;
+
+    let mut channels: Vec<ChannelReport> = device.channel_names.iter()
+        .map(|name| ChannelReport { name: name.clone(), ..ChannelReport::default() })
+        .collect();
+    let complete = data.len() - data.len() % record_size;
+    let mut zero_records = (complete / record_size) as u64;
+    let mut invalid_codes: Vec<CodeCount> = Vec::new();
+    let mut non_monotonic_runs = 0;
+    let mut first_non_monotonic = None;
+    // the latest time seen, and whether the records since it went back in time
+    let mut latest_time: Option<u64> = None;
+    let mut in_run = false;
+    let mut last_sweep: Option<u64> = None;
+    let (mut sweep_gaps, mut missing_sweeps) = (0, 0);
+    for_each_record(data, &header.timepatch, header.range, &options, |offset, line, followed| {
+        zero_records -= 1;
+        let FollowedEvent { event, time, .. } = match followed {
+            Some(followed) => followed,
+            None => {
+                let code = line & channel_mask;
+                match invalid_codes.iter_mut().find(|count| count.code == code) {
+                    Some(count) => count.count += 1,
+                    None => invalid_codes.push(CodeCount { code, count: 1 }),
+                }
+                return Ok(());
+            },
+        };
+        let channel = &mut channels[event.channel];
+        if event.edge { channel.falling += 1 } else { channel.rising += 1 };
+        if event.lost { channel.lost += 1 };
+        if bit_order[2] > 0 && options.start_clock == StartClock::Disabled {
+            // event.sweep is unwrapped by now
+            if let Some(last) = last_sweep {
+                if event.sweep > last + 1 {
+                    sweep_gaps += 1;
+                    missing_sweeps += event.sweep - last - 1;
+                }
+            }
+            last_sweep = Some(event.sweep.max(last_sweep.unwrap_or(0)));
+        }
+        match latest_time {
+            Some(latest) if time < latest => {
+                if !in_run {
+                    non_monotonic_runs += 1;
+                    in_run = true;
+                    if first_non_monotonic.is_none() { first_non_monotonic = Some(offset) };
+                }
+            },
+            _ => {
+                in_run = false;
+                latest_time = Some(time);
+            },
+        }
+        Ok(())
+    })?;
+    invalid_codes.sort_by_key(|count| count.code);
+
+    let trailing_bytes = data.len() - complete;
+    let size_matches_header = data_with_headers.len() == header.start_of_data + complete;
+    let misaligned_start = Resync::new(record_size, &bit_order, &options).start(data).map(|(start, _)| start);
+    let passed = invalid_codes.is_empty() && non_monotonic_runs == 0 && size_matches_header
+        && misaligned_start.is_none();
+    Ok(ValidationReport {
+        file: fname.to_string(),
+        device: device.name.clone(),
+        timepatch: header.timepatch.clone(),
+        record_size,
+        time_overflow: options.time_overflow.name(),
+        data_bytes: data.len(),
+        records: complete / record_size,
+        channels,
+        zero_records,
+        invalid_channel_codes: invalid_codes,
+        non_monotonic_runs,
+        first_non_monotonic,
+        sweep_gaps,
+        missing_sweeps,
+        trailing_bytes,
+        size_matches_header,
+        misaligned_start,
+        passed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+    use crate::device::Device;
+    use crate::writer::write_lst;
+
+    /// Writes a "0" file whose 12-bit time counter wraps every four events.
+    fn wrapping_file(name: &str) -> (String, LstHeader) {
+        let device = Device::mcs6a();
+        let header = LstHeader { start_of_data: 0, range: 0, timepatch: "0".to_string(), device,
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let events: Vec<Event> = (0..20u64).map(|idx| Event { time: idx * 1000, ..Event::default() }).collect();
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        let written = write_lst(&path, &header, &events).unwrap();
+        (path, written)
+    }
+
+    #[test]
+    fn time_overflows_are_unwrapped_by_default() {
+        let (path, header) = wrapping_file("libps_validate_wrapping.lst");
+        let report = validate(&path, &header, &ParseOptions::default()).unwrap();
+        assert_eq!((report.records, report.non_monotonic_runs, report.trailing_bytes), (20, 0, 0));
+        assert_eq!(report.data_bytes, 40);
+        assert_eq!(report.time_overflow, "jumps");
+        assert!(report.size_matches_header);
+        assert!(report.passed);
+        assert_eq!(report.channels[0].rising, 20);
+    }
+
+    #[test]
+    fn trailing_bytes_fail_the_file() {
+        let (path, header) = wrapping_file("libps_validate_trailing.lst");
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1]).unwrap();
+        let report = validate(&path, &header, &ParseOptions::default()).unwrap();
+        assert_eq!((report.records, report.trailing_bytes), (20, 1));
+        assert!(!report.size_matches_header);
+        assert!(!report.passed);
+    }
+
+    #[test]
+    fn the_time_overflow_of_the_caller_is_kept() {
+        let (path, header) = wrapping_file("libps_validate_range.lst");
+        let options = ParseOptions { time_overflow: TimeOverflow::Range, ..ParseOptions::default() };
+        let report = validate(&path, &LstHeader { range: 4096, ..header }, &options).unwrap();
+        assert_eq!(report.time_overflow, "range");
+        assert_eq!(report.non_monotonic_runs, 0);
+        assert!(report.passed);
+    }
+
+    #[test]
+    fn data_size_is_checked_against_the_header() {
+        let (path, header) = wrapping_file("libps_validate_short.lst");
+        let file_size = header.start_of_data + 40;
+        let shifted = LstHeader { start_of_data: file_size + 2, ..header.clone() };
+        assert!(validate(&path, &shifted, &ParseOptions::default()).is_err());
+        // a header that ends a byte later leaves the last record incomplete
+        let shifted = LstHeader { start_of_data: header.start_of_data + 1, ..header };
+        let report = validate(&path, &shifted, &ParseOptions::default()).unwrap();
+        assert_eq!((report.records, report.trailing_bytes), (19, 1));
+        assert!(!report.size_matches_header);
+    }
+}
@@ -0,0 +1,302 @@
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::binary_parsing::{CounterState, CounterUnwrapper, ParseOptions, decode_common, field_modulus};
+
+/// Part of an acquisition to parse. Both bounds are half-open, so `Sweeps {
+/// first: 3, stop: 5 }` holds sweeps 3 and 4. Sweep numbers are unwrapped, and
+/// times are absolute, in bins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Time { start: u64, stop: u64 },
+    Sweeps { first: u64, stop: u64 },
+}
+
+impl Window {
+    pub fn contains(&self, time: u64, sweep: u64) -> bool {
+        match *self {
+            Window::Time { start, stop } => time >= start && time < stop,
+            Window::Sweeps { first, stop } => sweep >= first && sweep < stop,
+        }
+    }
+
+    /// The sweeps that hold the window, or None if it can't be expressed in sweeps.
+    fn sweeps(&self, range: u64) -> Option<(u64, u64)> {
+        match *self {
+            Window::Sweeps { first, stop } => Some((first, stop)),
+            Window::Time { start, stop } if range > 0 => {
+                Some((start / range + 1, stop.saturating_sub(1) / range + 2))
+            },
+            Window::Time { .. } => None,
+        }
+    }
+}
+
+/// Number of records whose sweeps are scanned together.
+const BLOCK_RECORDS: usize = 1 << 12;
+
+/// Number of places where the pace of the sweep counter is sampled.
+const PACE_SAMPLES: usize = 256;
+
+/// Fraction of a wrap of the sweep counter that probes may be apart at the
+/// sampled pace, which leaves room for sweeps that pass faster elsewhere.
+const PROBE_SAFETY: u64 = 8;
+
+/// Fewest records between probes for which probing beats scanning.
+const MIN_STRIDE: usize = 256;
+
+/// Number of records after a probe that are tried when it hits empty or
+/// damaged records.
+const PROBE_RECORDS: usize = 64;
+
+/// The raw sweep counter of the first and last decodable record of a block,
+/// and how often it wraps in between.
+#[derive(Debug, Clone, Copy)]
+struct BlockSweeps {
+    offset: usize,
+    first: u64,
+    last: u64,
+    wraps: u64,
+}
+
+/// A record with its unwrapped sweep, and the sweep counter before it.
+#[derive(Debug, Clone, Copy)]
+struct Checkpoint {
+    offset: usize,
+    sweep: u64,
+    state: CounterState,
+}
+
+/// Finds the records of a sweep timepatch that may belong to `window`, so that
+/// only they have to be decoded. The sweep counter only grows between wraps, so
+/// the records that hold the window are found by bisecting on the unwrapped
+/// sweep of checkpoints spread over the data: probed records when the counter
+/// is slow enough for that, otherwise the start of every block of a scan (see
+/// `probe_checkpoints`). The returned byte range reaches one checkpoint beyond
+/// the window on both sides, in case records arrive slightly out of order, and
+/// it comes with the sweep counter to resume parsing from. The counters before
+/// `data` are `options.resume`. None means the whole data has to be parsed.
+pub fn window_slice(data: &[u8], record_size: usize, range: u64, bit_order: &[u8; 4],
+                    options: &ParseOptions, window: &Window) -> Option<(Range<usize>, CounterState)> {
+    if bit_order[2] == 0 { return None };
+    let (first, stop) = window.sweeps(range)?;
+    let checkpoints = probe_checkpoints(data, record_size, bit_order, options)
+        .unwrap_or_else(|| scan_checkpoints(data, record_size, bit_order, options));
+    // the first checkpoint inside the window, and the first one beyond it
+    let inside = checkpoints.partition_point(|cp| cp.sweep < first);
+    let beyond = checkpoints.partition_point(|cp| cp.sweep < stop);
+    let (start, state) = match inside.checked_sub(2) {
+        Some(idx) => (checkpoints[idx].offset, checkpoints[idx].state),
+        None => (0, options.resume),
+    };
+    let stop = checkpoints.get(beyond + 1).map_or(data.len(), |cp| cp.offset);
+    Some((start..stop.max(start), state))
+}
+
+/// The first decodable record at `record` or shortly after it, with its raw
+/// sweep counter.
+fn sweep_near(data: &[u8], record_size: usize, bit_order: &[u8; 4], options: &ParseOptions,
+              record: usize) -> Option<(usize, u64)> {
+    let records = data.len() / record_size;
+    (record..records.min(record + PROBE_RECORDS)).find_map(|idx| {
+        let line = &data[idx * record_size..(idx + 1) * record_size];
+        if line.iter().all(|byte| *byte == 0) { return None };
+        decode_common(line, bit_order, &options.device).map(|event| (idx, event.sweep))
+    })
+}
+
+/// Checkpoints at probed records, without reading the records in between. A
+/// wrap of the sweep counter can only be told apart from a step back if the
+/// probes are less than half a wrap apart, so the steepest step of the counter
+/// between neighbouring records is sampled across the data, and the probes are
+/// placed a fraction `1 / PROBE_SAFETY` of a wrap apart at that pace. Sweeps
+/// that pass much faster than anywhere sampled, like a long stretch without
+/// events, can still hide a wrap between two probes. None if the probes would
+/// be too close for probing to pay off, as with counters of 8 bits or less.
+fn probe_checkpoints(data: &[u8], record_size: usize, bit_order: &[u8; 4],
+                     options: &ParseOptions) -> Option<Vec<Checkpoint>> {
+    let modulus = field_modulus(bit_order[2]);
+    let records = data.len() / record_size;
+    let steepest = (0..PACE_SAMPLES)
+        .filter_map(|sample| {
+            let (at, sweep) = sweep_near(data, record_size, bit_order, options, sample * records / PACE_SAMPLES)?;
+            let (next, following) = sweep_near(data, record_size, bit_order, options, at + 1)?;
+            let step = (following + modulus - sweep) % modulus;
+            // a step back of a record that arrived out of order
+            if step > modulus / 2 { return Some(0) };
+            Some(step.div_ceil((next - at) as u64))
+        })
+        .max()?;
+    let stride = (modulus / (PROBE_SAFETY * steepest.max(1))).min(records as u64) as usize;
+    if stride < MIN_STRIDE { return None };
+    let mut unwrapper = CounterUnwrapper::new(modulus);
+    unwrapper.epoch = options.resume.sweep_epoch;
+    unwrapper.last = options.resume.last_sweep;
+    let checkpoints = (0..records).step_by(stride)
+        .filter_map(|record| sweep_near(data, record_size, bit_order, options, record))
+        .map(|(at, sweep)| {
+            let state = CounterState { sweep_epoch: unwrapper.epoch, last_sweep: unwrapper.last, ..options.resume };
+            let (sweep, _) = unwrapper.unwrap(sweep);
+            Checkpoint { offset: at * record_size, sweep, state }
+        })
+        .collect();
+    Some(checkpoints)
+}
+
+/// Checkpoints at the start of each block, from reading the sweep counter of
+/// every record, block by block in parallel, so that no wrap is missed.
+fn scan_checkpoints(data: &[u8], record_size: usize, bit_order: &[u8; 4],
+                    options: &ParseOptions) -> Vec<Checkpoint> {
+    let modulus = field_modulus(bit_order[2]);
+    let complete = data.len() - data.len() % record_size;
+    let blocks: Vec<Option<BlockSweeps>> = data[..complete]
+        .par_chunks(record_size * BLOCK_RECORDS)
+        .enumerate()
+        .map(|(block_idx, block)| {
+            let mut unwrapper = CounterUnwrapper::new(modulus);
+            let mut first = None;
+            for record in block.chunks(record_size).filter(|record| record.iter().any(|byte| *byte != 0)) {
+                if let Some(event) = decode_common(record, bit_order, &options.device) {
+                    first.get_or_insert(event.sweep);
+                    unwrapper.unwrap(event.sweep);
+                }
+            }
+            Some(BlockSweeps { offset: block_idx * record_size * BLOCK_RECORDS, first: first?,
+                               last: unwrapper.last?, wraps: unwrapper.epoch })
+        })
+        .collect();
+    let mut unwrapper = CounterUnwrapper::new(modulus);
+    unwrapper.epoch = options.resume.sweep_epoch;
+    unwrapper.last = options.resume.last_sweep;
+    blocks.iter().flatten()
+        .map(|block| {
+            let state = CounterState { sweep_epoch: unwrapper.epoch, last_sweep: unwrapper.last, ..options.resume };
+            let (sweep, _) = unwrapper.unwrap(block.first);
+            unwrapper.epoch += block.wraps;
+            unwrapper.last = Some(block.last);
+            Checkpoint { offset: block.offset, sweep, state }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{WriteBytesExt, LE};
+
+    use super::*;
+    use crate::binary_parsing::*;
+    use crate::device::Device;
+    use crate::helper_funcs::*;
+
+    const RANGE: u64 = 1000;
+
+    /// A "5" file with a stop1 record at each of `sweeps`, which are unwrapped
+    /// and counted from 1. The 8-bit sweep counter wraps every 256 sweeps.
+    fn data_5(sweeps: impl Iterator<Item = u64>) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (idx, sweep) in sweeps.enumerate() {
+            let time = (idx as u64 * 7) % RANGE;
+            data.write_u32::<LE>(((sweep % 256) << 24 | time << 4 | 1) as u32).unwrap();
+        }
+        data
+    }
+
+    /// A "1a" file with a stop1 record at each of `sweeps`, whose 16-bit sweep
+    /// counter wraps every 65536 sweeps.
+    fn data_1a(sweeps: impl Iterator<Item = u64>) -> Vec<u8> {
+        let time_bits = TimepatchBits::new("1a", &Device::mcs6a()).unwrap()[3];
+        let mut data = Vec::new();
+        for (idx, sweep) in sweeps.enumerate() {
+            let time = (idx as u64 * 7) % RANGE;
+            data.write_uint::<LE>((sweep % 65536) << (4 + time_bits) | time << 4 | 1, 6).unwrap();
+        }
+        data
+    }
+
+    fn times(timepatch: &str, data: &[u8], options: &ParseOptions) -> Vec<u64> {
+        let channels = create_channels_u16(timepatch, vec![1, 0, 0, 0, 0, 1], &options.channel_names(), data.len())
+            .unwrap();
+        let bit_order = TimepatchBits::new(timepatch, &options.device).unwrap();
+        let parsed = match timepatch {
+            "5" => parse_5(data, RANGE, &bit_order, channels, options),
+            _ => parse_1a(data, RANGE, &bit_order, channels, options),
+        };
+        parsed.channel(0).unwrap().time.clone()
+    }
+
+    /// Parses the slice of `data` that `window_slice` picks, and compares it
+    /// with parsing the whole data.
+    fn check_slice_of(timepatch: &str, data: &[u8], window: Window) {
+        let mut options = ParseOptions { window: Some(window), ..ParseOptions::default() };
+        let bit_order = TimepatchBits::new(timepatch, &options.device).unwrap();
+        let record_size = bytes_per_record(timepatch).unwrap();
+        let expected = times(timepatch, data, &options);
+        let (slice, state) = window_slice(data, record_size, RANGE, &bit_order, &options, &window).unwrap();
+        assert!(!expected.is_empty());
+        assert!(slice.len() < data.len() / 2, "{:?} of {} bytes", slice, data.len());
+        options.resume = state;
+        options.data_offset = slice.start;
+        assert_eq!(times(timepatch, &data[slice], &options), expected);
+    }
+
+    fn check_slice(data: &[u8], window: Window) {
+        check_slice_of("5", data, window);
+    }
+
+    fn probes(timepatch: &str, data: &[u8]) -> Option<Vec<Checkpoint>> {
+        let options = ParseOptions::default();
+        let bit_order = TimepatchBits::new(timepatch, &options.device).unwrap();
+        probe_checkpoints(data, bytes_per_record(timepatch).unwrap(), &bit_order, &options)
+    }
+
+    #[test]
+    fn window_across_a_wrap_of_dense_sweeps() {
+        let data = data_5((1..=4000).flat_map(|sweep| std::iter::repeat_n(sweep, 20)));
+        check_slice(&data, Window::Sweeps { first: 2000, stop: 2060 });
+        check_slice(&data, Window::Time { start: 2047 * RANGE + 100, stop: 2050 * RANGE });
+    }
+
+    #[test]
+    fn window_across_a_wrap_of_sparse_sweeps() {
+        // the counter wraps every 86 records or so, many times per block
+        let data = data_5((0..30_000).map(|idx| 1 + idx * 3));
+        check_slice(&data, Window::Sweeps { first: 45_000, stop: 45_300 });
+        check_slice(&data, Window::Sweeps { first: 256 * 300, stop: 256 * 300 + 3 });
+    }
+
+    #[test]
+    fn window_at_the_start_or_end() {
+        let data = data_5((1..=4000).flat_map(|sweep| std::iter::repeat_n(sweep, 20)));
+        check_slice(&data, Window::Sweeps { first: 1, stop: 3 });
+        check_slice(&data, Window::Sweeps { first: 3990, stop: 5000 });
+    }
+
+    #[test]
+    fn slow_16_bit_sweeps_are_probed() {
+        // four records per sweep, and a wrap after 262144 records
+        let data = data_1a((1..=100_000).flat_map(|sweep| std::iter::repeat_n(sweep, 4)));
+        let checkpoints = probes("1a", &data).unwrap();
+        assert!(checkpoints.len() < 100, "{} probes", checkpoints.len());
+        assert!(checkpoints.windows(2).all(|pair| pair[0].sweep <= pair[1].sweep));
+        check_slice_of("1a", &data, Window::Sweeps { first: 65_530, stop: 65_550 });
+        check_slice_of("1a", &data, Window::Sweeps { first: 99_000, stop: 99_010 });
+        check_slice_of("1a", &data, Window::Time { start: 2000 * RANGE, stop: 2002 * RANGE + 10 });
+    }
+
+    #[test]
+    fn fast_or_short_sweep_counters_are_scanned() {
+        let sparse = data_5((0..30_000).map(|idx| 1 + idx * 3));
+        assert!(probes("5", &sparse).is_none());
+        let fast = data_1a((0..30_000).map(|idx| 1 + idx * 300));
+        assert!(probes("1a", &fast).is_none());
+        check_slice_of("1a", &fast, Window::Sweeps { first: 300 * 20_000, stop: 300 * 20_100 });
+    }
+
+    #[test]
+    fn no_slice_without_sweeps() {
+        let bit_order = TimepatchBits::new("1", &Device::mcs6a()).unwrap();
+        let window = Window::Time { start: 0, stop: 10 };
+        assert!(window_slice(&[0; 64], 4, RANGE, &bit_order, &ParseOptions::default(), &window).is_none());
+    }
+}
@@ -0,0 +1,303 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use byteorder::{WriteBytesExt, LE};
+use failure::{Error, format_err};
+
+use crate::binary_parsing::{field_modulus, CounterState, Event};
+use crate::device::Device;
+use crate::header::LstHeader;
+use crate::helper_funcs::*;
+
+/// Fails if `value` doesn't fit in a field of `bits` bits.
+fn check_field(name: &str, value: u64, bits: u8) -> Result<(), Error> {
+    if bits < 64 && value >= field_modulus(bits) {
+        return Err(format_err!("{} {} doesn't fit in {} bits", name, value, bits));
+    }
+    Ok(())
+}
+
+/// Number of bits of a record of `device` with the layout `bit_order`.
+pub fn record_bits(bit_order: &[u8; 4], device: &Device) -> u32 {
+    u32::from(device.channel_bits) + 1 + bit_order.iter().map(|bits| u32::from(*bits)).sum::<u32>()
+}
+
+/// Packs an event into a record with the layout `bit_order`, with the lost bit
+/// below the tag if `lost_below_tag`. `time` and `sweep` have to be the raw
+/// counters of the record, see `record_counters`.
+pub fn encode_record(event: &Event, bit_order: &[u8; 4], lost_below_tag: bool, device: &Device)
+    -> Result<u64, Error> {
+    if event.channel >= device.num_of_channels() {
+        return Err(format_err!("{} has no input {}", device.name, event.channel + 1));
+    }
+    check_field("Time", event.time, bit_order[3])?;
+    check_field("Sweep", event.sweep, bit_order[2])?;
+    check_field("Tag", u64::from(event.tag), bit_order[1])?;
+    check_field("Lost flag", u64::from(event.lost), bit_order[0])?;
+    let (lost, tag) = ((u64::from(event.lost), bit_order[0]), (u64::from(event.tag), bit_order[1]));
+    let (high, low) = if lost_below_tag { (tag, lost) } else { (lost, tag) };
+    let mut line = high.0;
+    for &(value, bits) in [low, (event.sweep, bit_order[2]), (event.time, bit_order[3]),
+                           (u64::from(event.edge), 1)].iter() {
+        if bits > 0 { line = (line << bits) | value };
+    }
+    Ok((line << device.channel_bits) | (event.channel as u64 + 1))
+}
+
+/// Unpacks all fields of a record with the layout `bit_order`, the inverse of
+/// `encode_record`. None if the channel code isn't an input of `device`.
+pub fn decode_record(line: u64, bit_order: &[u8; 4], lost_below_tag: bool, device: &Device) -> Option<Event> {
+    let channel = device.decode_channel(line)?;
+    let mut rest = line >> device.channel_bits;
+    let mut take = |bits: u8| {
+        let value = rest % field_modulus(bits);
+        rest = if bits >= 64 { 0 } else { rest >> bits };
+        value
+    };
+    let edge = take(1) == 1;
+    let time = take(bit_order[3]);
+    let sweep = take(bit_order[2]);
+    let (tag, lost) = if lost_below_tag {
+        let lost = take(bit_order[0]);
+        (take(bit_order[1]), lost)
+    } else {
+        let tag = take(bit_order[1]);
+        (tag, take(bit_order[0]))
+    };
+    Some(Event { channel, edge, time, sweep, tag: tag as u16, lost: lost == 1 })
+}
+
+/// The raw time and sweep counters of the record of an event, whose time is
+/// absolute like the parsers return it. With a sweep counter the time is split
+/// into the sweep, which is taken from the event if it was kept, and the time
+/// within it. Without one, times beyond the time field wrap around, and can
+/// only be read back with the overflow of the time counter enabled.
+pub fn record_counters(event: &Event, range: u64, bit_order: &[u8; 4]) -> Result<(u64, u64), Error> {
+    if bit_order[2] == 0 {
+        return Ok((event.time % field_modulus(bit_order[3]), 0));
+    }
+    let sweep = match (event.sweep, range) {
+        (0, 0) => 1,
+        (0, range) => event.time / range + 1,
+        (sweep, _) => sweep,
+    };
+    let time = event.time.checked_sub(range * sweep.saturating_sub(1))
+        .ok_or_else(|| format_err!("Time {} lies before the start of sweep {}", event.time, sweep))?;
+    Ok((time, sweep % field_modulus(bit_order[2])))
+}
+
+/// Writes events to a new list file with the settings of `header`, which
+/// usually comes from the file the events were parsed from. The events have to
+/// be in file order, with absolute times, e.g. from `LstReturnU16::events`. The
+/// header is regenerated, and inputs that have events are marked as active.
+/// Returns the header of the new file.
+pub fn write_lst(path: &str, header: &LstHeader, events: &[Event]) -> Result<LstHeader, Error> {
+    write_events(path, header, events, |written| written.to_text())
+}
+
+/// Like `write_lst`, but the header of the new file is a copy of `text`, the
+/// header of the file that `header` was read from, with only the settings
+/// that `write_lst` changes patched (see `LstHeader::to_text_from`).
+pub fn write_lst_with_text(path: &str, header: &LstHeader, text: &[u8], events: &[Event])
+    -> Result<LstHeader, Error> {
+    write_events(path, header, events, |written| written.to_text_from(text))
+}
+
+fn write_events<F>(path: &str, header: &LstHeader, events: &[Event], header_text: F) -> Result<LstHeader, Error>
+    where F: FnOnce(&LstHeader) -> String {
+    let bit_order = TimepatchBits::new(&header.timepatch, &header.device)?;
+    let record_size = bytes_per_record(&header.timepatch)?;
+    let device = &header.device;
+    if record_bits(&bit_order, device) > record_size as u32 * 8 {
+        return Err(format_err!("Records of timepatch {} are too small for the channel field of {}",
+                               header.timepatch, device.name));
+    }
+    let mut channel_map = header.channel_map.clone();
+    channel_map.resize(device.num_of_channels(), 1);
+    for event in events {
+        if let Some(active) = channel_map.get_mut(event.channel) { *active = 1 };
+    }
+    let mut written = LstHeader { channel_map, resume: CounterState::default(), ..header.clone() };
+    let text = header_text(&written);
+    written.start_of_data = text.len();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(text.as_bytes())?;
+    let time_modulus = field_modulus(bit_order[3]);
+    let mut last_time = None;
+    for event in events {
+        // times that wrap around are only unwrapped if they don't jump by more
+        // than half the counter
+        if bit_order[2] == 0 && event.time >= time_modulus / 2 {
+            if let Some(last) = last_time {
+                if event.time - last > time_modulus / 2 {
+                    return Err(format_err!("Times {} and {} are too far apart to be unwrapped from the \
+                                            {}-bit time field", last, event.time, bit_order[3]));
+                }
+            }
+        }
+        last_time = Some(event.time);
+        let (time, sweep) = record_counters(event, header.range, &bit_order)?;
+        let record = Event { time, sweep, ..*event };
+        let line = encode_record(&record, &bit_order, lost_below_tag(&header.timepatch), device)
+            .map_err(|err| format_err!("Can't write timepatch {}: {}", header.timepatch, err))?;
+        file.write_uint::<LE>(line, record_size)?;
+    }
+    file.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::binary_parsing::*;
+    use crate::{analyze_lst_u16, analyze_lst_u8};
+
+    /// Events in file order on every input, with the tags counting down from
+    /// the largest one the timepatch holds.
+    fn events_for(timepatch: &str, device: &Device, range: u64) -> Vec<Event> {
+        let bit_order = TimepatchBits::new(timepatch, device).unwrap();
+        let max_tag = field_modulus(bit_order[1]) - 1;
+        (0..40u64)
+            .map(|idx| {
+                let (sweep, time) = if bit_order[2] > 0 {
+                    (idx / 4 + 1, range * (idx / 4) + (idx * 7) % range)
+                } else { (0, idx * 50) };
+                Event {
+                    channel: idx as usize % device.num_of_channels(),
+                    edge: idx % 3 == 0,
+                    time,
+                    sweep,
+                    tag: (max_tag - idx % (max_tag + 1)) as u16,
+                    lost: bit_order[0] > 0 && idx % 2 == 1,
+                }
+            })
+            .collect()
+    }
+
+    /// Time, edge, tag, lost flag and sweep of an event
+    type Fields = (u64, bool, u16, bool, u64);
+
+    /// The events of each input, ordered by time, with the fields the
+    /// timepatch has.
+    fn by_channel(events: &[Event], device: &Device, bit_order: &[u8; 4]) -> Vec<Vec<Fields>> {
+        (0..device.num_of_channels())
+            .map(|chan| {
+                let mut fields: Vec<_> = events.iter()
+                    .filter(|event| event.channel == chan)
+                    .map(|event| (event.time, event.edge, if bit_order[1] > 0 { event.tag } else { 0 },
+                                  bit_order[0] > 0 && event.lost, if bit_order[2] > 0 { event.sweep } else { 0 }))
+                    .collect();
+                fields.sort_by_key(|field| field.0);
+                fields
+            })
+            .collect()
+    }
+
+    fn round_trip(timepatch: &str, device: Device) {
+        let header = LstHeader { start_of_data: 0, range: 1000, timepatch: timepatch.to_string(),
+                                 device: device.clone(), channel_map: vec![1; device.num_of_channels()],
+                                 bitshift: 2, calfact: None, resume: CounterState::default() };
+        let bit_order = TimepatchBits::new(timepatch, &device).unwrap();
+        assert_eq!(record_bits(&bit_order, &device), bytes_per_record(timepatch).unwrap() as u32 * 8);
+        let path = env::temp_dir().join(format!("libps_writer_{}_{}.lst", device.name, timepatch));
+        let path = path.to_str().unwrap();
+        let events = events_for(timepatch, &device, header.range);
+        let written = write_lst(path, &header, &events).unwrap();
+
+        let read = LstHeader::read(path).unwrap();
+        assert_eq!(read.start_of_data, written.start_of_data);
+        assert_eq!((read.range, &read.timepatch, &read.device, &read.channel_map, read.bitshift),
+                   (header.range, &header.timepatch, &header.device, &header.channel_map, header.bitshift));
+        let options = ParseOptions { device: device.clone(), sweep_output: true, ..ParseOptions::default() };
+        let parsed = match timepatch {
+            "2a" | "22" | "3" => analyze_lst_u8(path, read.start_of_data, read.range, timepatch,
+                                                read.channel_map.clone(), &options).unwrap().events(),
+            _ => analyze_lst_u16(path, read.start_of_data, read.range, timepatch,
+                                 read.channel_map.clone(), &options).unwrap().events(),
+        };
+        assert_eq!(by_channel(&parsed, &device, &bit_order), by_channel(&events, &device, &bit_order),
+                   "timepatch {}", timepatch);
+    }
+
+    #[test]
+    fn round_trip_mcs6a() {
+        for timepatch in TIMEPATCHES.iter() {
+            round_trip(timepatch, Device::mcs6a());
+        }
+    }
+
+    #[test]
+    fn round_trip_mcs8a() {
+        for timepatch in TIMEPATCHES.iter() {
+            round_trip(timepatch, Device::mcs8a());
+        }
+    }
+
+    #[test]
+    fn wrapped_times_need_the_overflow() {
+        let device = Device::mcs6a();
+        let header = LstHeader { start_of_data: 0, range: 0, timepatch: "0".to_string(), device: device.clone(),
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let events: Vec<Event> = (0..20u64).map(|idx| Event { time: idx * 1000, ..Event::default() }).collect();
+        let path = env::temp_dir().join("libps_writer_wrapped.lst");
+        let path = path.to_str().unwrap();
+        let written = write_lst(path, &header, &events).unwrap();
+        let options = ParseOptions { time_overflow: TimeOverflow::BackwardJumps, ..ParseOptions::default() };
+        let parsed = analyze_lst_u16(path, written.start_of_data, 0, "0", written.channel_map, &options).unwrap();
+        let times: Vec<u64> = events.iter().map(|event| event.time).collect();
+        assert_eq!(parsed.channel(0).unwrap().time, times);
+    }
+
+    #[test]
+    fn fields_that_dont_fit_fail() {
+        let bit_order = TimepatchBits::new("0", &Device::mcs6a()).unwrap();
+        let event = Event { time: 1 << 12, ..Event::default() };
+        assert!(encode_record(&event, &bit_order, false, &Device::mcs6a()).is_err());
+        let event = Event { tag: 1, ..Event::default() };
+        assert!(encode_record(&event, &bit_order, false, &Device::mcs6a()).is_err());
+        let event = Event { channel: 6, ..Event::default() };
+        assert!(encode_record(&event, &bit_order, false, &Device::mcs6a()).is_err());
+    }
+
+    #[test]
+    fn unknown_timepatches_fail() {
+        assert!(bytes_per_record("7").is_err());
+        assert!(TimepatchBits::new("7", &Device::mcs6a()).is_err());
+        let header = LstHeader { start_of_data: 0, range: 1000, timepatch: "5".to_string(), device: Device::mcs6a(),
+                                 channel_map: vec![1; 6], bitshift: 0, calfact: None,
+                                 resume: CounterState::default() };
+        let path = env::temp_dir().join("libps_writer_unknown_timepatch.lst");
+        let path = path.to_str().unwrap();
+        let written = write_lst(path, &header, &events_for("5", &header.device, header.range)).unwrap();
+        let options = ParseOptions::default();
+        let err = analyze_lst_u16(path, written.start_of_data, 1000, "7", written.channel_map.clone(), &options)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Unknown timepatch 7");
+        assert!(analyze_lst_u8(path, written.start_of_data, 1000, "5", written.channel_map, &options).is_err());
+    }
+
+    #[test]
+    fn template_header_is_kept() {
+        let template = "[MCS6A A] 6\r\nsweepmode=1234\r\nrange=1000\r\ntime_patch=1a\r\n[CHN1]\r\nactive=0\r\n\
+                        holdafter=5\r\n[DATA]\r\n";
+        let header = LstHeader { timepatch: "5".to_string(), ..LstHeader::parse(template.as_bytes()).unwrap() };
+        let events = events_for("5", &header.device, header.range);
+        let path = env::temp_dir().join("libps_writer_template.lst");
+        let path = path.to_str().unwrap();
+        let written = write_lst_with_text(path, &header, template.as_bytes(), &events).unwrap();
+        let text = std::fs::read(path).unwrap();
+        let text = String::from_utf8_lossy(&text[..written.start_of_data]);
+        assert!(text.starts_with("[MCS6A A] 6\r\nsweepmode=1234\r\n") && text.contains("holdafter=5\r\n"), "{}", text);
+        // the first input has events, so it's marked as active
+        assert!(text.contains("[CHN1]\r\nactive=1\r\n") && text.contains("time_patch=5\r\n"), "{}", text);
+        let read = LstHeader::read(path).unwrap();
+        assert_eq!((read.start_of_data, read.timepatch.as_str()), (written.start_of_data, "5"));
+        let parsed = analyze_lst_u16(path, read.start_of_data, read.range, &read.timepatch, read.channel_map,
+                                     &ParseOptions::default()).unwrap();
+        assert_eq!(parsed.channel(0).unwrap().time.len(), events.iter().filter(|event| event.channel == 0).count());
+    }
+}